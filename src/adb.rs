@@ -4,6 +4,16 @@ use std::{fs, io::{self, Error, ErrorKind}, process::{Command, Stdio}, thread::s
 use std::fmt::Debug;
 
 
+/// How many files are given to each adb push.
+const PUSH_CHUNK_SIZE: usize = 100;
+
+
+/// Quotes path for the device's shell, which splits arguments on spaces.
+fn shell_quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', "'\\''"))
+}
+
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum DeviceStatus {
     Online,
@@ -74,16 +84,12 @@ impl AndroidDevice {
 
     fn new(identifier: String, model: String, transport_id: u32) -> AndroidDevice {
 
-        return AndroidDevice {identifier, model, transport_id} ;
+        AndroidDevice {identifier, model, transport_id}
     }
 
 
     pub fn is_decoy(&self) -> bool{
-        if self.transport_id == 99 {
-            true
-        } else {
-            false
-        }
+        self.transport_id == 99
 
     }
 
@@ -96,7 +102,6 @@ impl DeviceManager {
     pub fn new(command: &str) -> io::Result<DeviceManager> {
 
         let adb_inst = Command::new(command).arg("--version").stdout(Stdio::piped()).spawn()?;
-        let ver_no: Vec<i32>;
         
         let mut output = Self::dump_stdout(adb_inst)?;
         
@@ -110,7 +115,7 @@ impl DeviceManager {
         
         
 
-        ver_no = output.split(".").map(|num| num.parse::<i32>().unwrap()).collect();
+        let ver_no: Vec<i32> = output.split(".").map(|num| num.parse::<i32>().unwrap()).collect();
 
 
 
@@ -170,18 +175,19 @@ impl DeviceManager {
     }
 
 
-    pub fn push_dir(&mut self, source_dir: &str, dest_dir: &str, target_device: AndroidDevice) -> io::Result<String> {
+    /// Pushes files into dir_name under dest_dir on the device, creating it if needed. Files already on the device
+    /// that are up to date are skipped. Returns the last line adb printed.
+    pub fn push_files(&mut self, files: &[String], dest_dir: &str, dir_name: &str, target_device: AndroidDevice) -> io::Result<String> {
     
 
         
 
-        if !Self::path_exists(source_dir) {
-            return Err(Error::new(ErrorKind::NotFound, format!("Could not find \"{}\"", source_dir)));
+        if let Some(file) = files.iter().find(|f| !Self::path_exists(f)) {
+            return Err(Error::new(ErrorKind::NotFound, format!("Could not find \"{}\"", file)));
         }
 
  
        
-        let transport_id;
 
         
         let status = self.get_device_status(target_device.clone())?;
@@ -196,7 +202,7 @@ impl DeviceManager {
         }
 
 
-        transport_id = self.get_transport_id(target_device.clone());
+        let transport_id = self.get_transport_id(target_device.clone());
 
 
 
@@ -212,22 +218,33 @@ impl DeviceManager {
         if buffer.ends_with("No such file or directory") || dest_dir.is_empty(){
             return Err(Error::new(ErrorKind::NotFound, format!("Could not find \"{}\"", dest_dir)));
         } 
-          
 
-        let adb_inst = Command::new(&self.adb_command).args(["-t", transport_id.to_string().as_str(), "push", "--sync", source_dir, dest_dir]).stdout(Stdio::piped()).spawn()?;
-        let mut buffer = Self::dump_stdout(adb_inst)?;
 
-        buffer = buffer.trim().to_string();
+        //adb only pushes several files at once into a directory that exists.
+        let target_dir = format!("{}/{}", dest_dir, dir_name);
+
+        let adb_inst = Command::new(&self.adb_command).args(["-t", transport_id.to_string().as_str(), "shell", "mkdir", "-p", &shell_quote(&target_dir)]).stdout(Stdio::piped()).spawn()?;
+        Self::dump_stdout(adb_inst)?;
+
+
+        let mut last = String::new();
+
+        //Keeps the command line well under the limits of every platform.
+        for chunk in files.chunks(PUSH_CHUNK_SIZE) {
+            let adb_inst = Command::new(&self.adb_command).args(["-t", transport_id.to_string().as_str(), "push", "--sync"]).args(chunk).arg(&target_dir).stdout(Stdio::piped()).spawn()?;
+            let mut buffer = Self::dump_stdout(adb_inst)?;
+
+            buffer = buffer.trim().to_string();
         
         
-        let last;
-        if buffer.contains("\r\n") {
+            if buffer.contains("\r\n") {
             
-            last = buffer.split("\r\n").last().expect("last line of buffer should not be empty").to_owned(); 
+                last = buffer.split("\r\n").last().expect("last line of buffer should not be empty").to_owned(); 
 
             
-        } else {
-            last = buffer;
+            } else {
+                last = buffer;
+            }
         }
 
 
@@ -301,7 +318,7 @@ impl DeviceManager {
             }
 
         }
-        return 99;
+        99
     }
 
     pub fn get_device_status(&mut self, input_device: AndroidDevice) -> io::Result<DeviceStatus> {
@@ -309,7 +326,7 @@ impl DeviceManager {
         self.refresh_devices()?;
 
         if input_device.is_decoy() {
-            return Err(Error::new(ErrorKind::NotFound, format!("Device does not exist!")));
+            return Err(Error::new(ErrorKind::NotFound, "Device does not exist!"));
         }
 
         if !self.devices.contains(&input_device) {
@@ -319,7 +336,7 @@ impl DeviceManager {
         
         
 
-        let transport_id = Self::get_transport_id(&self, input_device.clone());
+        let transport_id = Self::get_transport_id(self, input_device.clone());
 
         let adb_inst = Command::new(&self.adb_command).args(["-t", transport_id.to_string().as_str(), "get-state"]).stdout(Stdio::piped()).spawn()?;
 
@@ -340,13 +357,7 @@ impl DeviceManager {
   
 
     fn path_exists(path: &str) -> bool {
-        let metadata = fs::metadata(path);
-        
-        if metadata.is_ok() {
-            true 
-        } else {
-            false
-        }
+        fs::metadata(path).is_ok()
 
     }
 
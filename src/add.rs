@@ -0,0 +1,95 @@
+use std::{env::set_current_dir, fs::File, io::Error};
+
+use crate::filters::{compile_excludes, exclude_reason};
+use crate::history::record_playlist;
+use crate::{download, fetch_songs, run_summary, parse_manifest, pl_update_fatal_error, rename_changed_files, save_downloads, save_manifest, Args, Filters, Song};
+
+
+pub(crate) fn pl_add(options: Args, playlist_name: Option<String>, urls: Vec<String>) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
+        }
+    };
+
+    let mut playlist = parse_manifest(manifest)?;
+    let old_playlist = playlist.clone();
+    record_playlist(&playlist);
+
+
+    //The playlist's filters are for its sources, songs added by hand are always wanted.
+    let mut added_songs: Vec<Song> = Vec::new();
+    let mut manifest_changed = false;
+    let exclude_titles = compile_excludes(&playlist.excludes)?;
+
+    for url in &urls {
+        pl_update_println!("Fetching \"{}\"", url);
+
+        for mut song in fetch_songs(url, &Filters::default(), &options)? {
+            if playlist.excludes.ids.contains(&song.id) {
+                pl_update_warn!("\"{}\" [{}] is skipped, undo this with the skip command to add it.", song.title, song.id);
+                continue;
+            }
+
+            if let Some(reason) = exclude_reason(&playlist.excludes, &exclude_titles, &song) {
+                pl_update_warn!("\"{}\" [{}] is excluded by the playlist's rules ({}), it is added anyway as it was asked for by hand.", song.title, song.id, reason);
+            }
+
+            match playlist.songs.iter_mut().find(|f| **f == song) {
+                Some(existing) => {
+                    if !existing.pinned {
+                        pl_update_println!("\"{}\" is already in the playlist, it will now be kept when it leaves its source.", existing.title);
+                    }
+                    manifest_changed |= !existing.pinned;
+                    existing.pinned = true;
+                },
+                None => {
+                    song.position = playlist.songs.len() + 1;
+                    song.pinned = true;
+                    //Any source would be wrong, pinned songs are kept whatever their source does.
+                    song.source = None;
+                    playlist.songs.push(song.clone());
+                    added_songs.push(song);
+                }
+            }
+        }
+    }
+
+
+    if !added_songs.is_empty() {
+        //The track number width may have grown.
+        rename_changed_files(&old_playlist, &playlist)?;
+    }
+
+    //Saved before downloading, so the songs added by hand are kept whatever the download does.
+    if manifest_changed || !added_songs.is_empty() {
+        save_manifest(&playlist)?;
+    }
+
+    if !added_songs.is_empty() {
+        pl_update_println!("Downloading {} songs...", added_songs.len());
+        let failures = download(&added_songs, &playlist.audio_format, &options)?;
+        save_downloads(&mut playlist, &added_songs, &failures, &options)?;
+    }
+
+    run_summary().added = added_songs.iter().map(Into::into).collect();
+
+    pl_update_println!("Added {} songs to \"{}\".", added_songs.len(), playlist.title);
+
+
+    Ok(())
+}
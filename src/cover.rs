@@ -0,0 +1,292 @@
+use std::{env::set_current_dir, fs::{self, File}, io::{Error, ErrorKind}, path::Path, process::Command};
+
+use crate::{find_ffmpeg, interrupted, parse_manifest, pl_update_fatal_error, save_manifest, unique_songs, Args, CoverRules, Manifest, Song};
+use crate::history::record_playlist;
+use crate::tags::read_tags;
+
+
+/// The name players look for playlist art under.
+pub(crate) const FOLDER_COVER: &str = "folder.jpg";
+
+
+/// The ffmpeg video filter that applies the crop and size rules, if there is anything to do.
+fn art_filter(rules: &CoverRules) -> Option<String> {
+    let mut filters = Vec::new();
+
+    if rules.square {
+        filters.push("crop='min(iw,ih)':'min(iw,ih)'".to_string()); //Centered
+    }
+
+    if let Some(size) = rules.size {
+        filters.push(format!("scale='min(iw,{size})':'min(ih,{size})':force_original_aspect_ratio=decrease"));
+    }
+
+    if filters.is_empty() {
+        None
+    } else {
+        Some(filters.join(","))
+    }
+}
+
+
+/// Whether art of the given width and height already meets the crop and size rules, so filtering it would not change it.
+fn art_fits(rules: &CoverRules, (width, height): (u32, u32)) -> bool {
+    (!rules.square || width == height) && rules.size.is_none_or(|size| width <= size && height <= size)
+}
+
+
+/// Replaces the art of filename with image, or with its own art passed through filter if no image is given.
+fn embed_cover(ffmpeg_command: &String, filename: &str, image: Option<&str>, filter: Option<&str>) -> Result<(), Error> {
+    let file_ext = filename.rsplit_once('.').map_or("", |f| f.1);
+    let tmp_filename = format!("{}.cover.{}", filename, file_ext);
+
+    let mut ffmpeg = Command::new(ffmpeg_command);
+    ffmpeg.args(["-y", "-loglevel", "error", "-i", filename]);
+
+    match image {
+        Some(image) => ffmpeg.args(["-i", image, "-map", "0:a", "-map", "1:v:0"]),
+        None => ffmpeg.args(["-map", "0:a", "-map", "0:v:0"]),
+    };
+
+    ffmpeg.args(["-c:a", "copy"]);
+
+    match filter {
+        Some(filter) => ffmpeg.args(["-vf", filter, "-c:v", "mjpeg"]),
+        None => ffmpeg.args(["-c:v", "copy"]),
+    };
+
+    ffmpeg.args(["-disposition:v:0", "attached_pic"]);
+
+    if file_ext == "mp3" {
+        ffmpeg.args(["-id3v2_version", "3", "-metadata:s:v", "title=Album cover", "-metadata:s:v", "comment=Cover (front)"]);
+    }
+
+    let ffmpeg_output = ffmpeg
+        .arg(&tmp_filename)
+        .output()?;
+
+    if !ffmpeg_output.status.success() {
+        let _ = fs::remove_file(&tmp_filename);
+        pl_update_fatal_error!(ErrorKind::Other, "FFMPEG could not embed cover art in \"{}\": {}", filename, String::from_utf8_lossy(&ffmpeg_output.stderr).trim());
+    }
+
+    fs::rename(&tmp_filename, filename)?;
+
+    Ok(())
+}
+
+
+/// Writes folder.jpg from the playlist's image, or from the art of the first song that has any.
+fn write_folder_cover(ffmpeg_command: &String, playlist: &Manifest, filter: Option<&str>) -> Result<bool, Error> {
+    let source = match &playlist.cover_rules.image {
+        Some(image) => Some(image.clone()),
+        None => unique_songs(&playlist.songs).iter()
+            .map(|f| playlist.song_filename(f))
+            .find(|filename| read_tags(filename).ok().flatten().is_some_and(|f| f.has_cover)),
+    };
+
+    let Some(source) = source else {
+        pl_update_warn!("No song in the playlist has cover art, {} was not written.", FOLDER_COVER);
+        return Ok(false);
+    };
+
+    let mut ffmpeg = Command::new(ffmpeg_command);
+    ffmpeg.args(["-y", "-loglevel", "error", "-i", &source, "-map", "0:v:0", "-frames:v", "1", "-update", "1"]);
+
+    if let Some(filter) = filter {
+        ffmpeg.args(["-vf", filter]);
+    }
+
+    let ffmpeg_output = ffmpeg
+        .arg(FOLDER_COVER)
+        .output()?;
+
+    if !ffmpeg_output.status.success() {
+        pl_update_fatal_error!(ErrorKind::Other, "FFMPEG could not write {} from \"{}\": {}", FOLDER_COVER, source, String::from_utf8_lossy(&ffmpeg_output.stderr).trim());
+    }
+
+    Ok(true)
+}
+
+
+/// Re-embeds the art of the given songs using the playlist's cover rules, and writes folder.jpg if it is missing.
+/// Returns the number of files that were changed.
+pub(crate) fn apply_cover_rules(playlist: &Manifest, songs: &[Song], options: &Args) -> Result<usize, Error> {
+    let rules = &playlist.cover_rules;
+
+    if rules.is_empty() {
+        return Ok(0);
+    }
+
+    let ffmpeg_command = options.ffmpeg_location.clone().unwrap_or("ffmpeg".to_string());
+    find_ffmpeg(&ffmpeg_command)?;
+
+    let filter = art_filter(rules);
+    let mut changed = 0;
+
+    //yt-dlp embeds art in ogg files itself, ffmpeg has no way to.
+    if matches!(playlist.file_ext(), "ogg" | "opus") && (filter.is_some() || rules.image.is_some()) {
+        pl_update_warn!("FFMPEG cannot embed cover art in {} files, only {} will be written.", playlist.file_ext(), FOLDER_COVER);
+    } else {
+        for song in unique_songs(songs) {
+            if interrupted() {
+                return Ok(changed);
+            }
+
+            let filename = playlist.song_filename(&song);
+
+            if fs::metadata(&filename).is_err() {
+                continue;
+            }
+
+            let tags = read_tags(&filename).ok().flatten();
+            let has_cover = tags.as_ref().is_some_and(|f| f.has_cover);
+
+            let image = match &rules.image {
+                Some(image) if !rules.fallback || !has_cover => Some(image.as_str()),
+                _ => None,
+            };
+
+            //Re-encoding art that already fits the rules would only lose quality on every run.
+            let fits = tags.and_then(|f| f.cover_size).is_some_and(|f| art_fits(rules, f));
+
+            if image.is_none() && (!has_cover || filter.is_none() || fits) {
+                continue;
+            }
+
+            pl_update_vprintln!("Embedding cover art in \"{}\"", filename);
+
+            embed_cover(&ffmpeg_command, &filename, image, filter.as_deref())?;
+            changed += 1;
+        }
+    }
+
+    if rules.folder && fs::metadata(FOLDER_COVER).is_err() {
+        write_folder_cover(&ffmpeg_command, playlist, filter.as_deref())?;
+    }
+
+    Ok(changed)
+}
+
+
+pub(crate) fn pl_cover(options: Args, playlist_name: Option<String>, square: Option<bool>, size: Option<u32>, image: Option<String>, fallback: bool, folder: Option<bool>) -> Result<(), Error> {
+
+
+    //The image path is relative to where the command was run, not the playlist directory.
+    let image = match image {
+        Some(image) if image == "none" => Some(None),
+        Some(image) => match fs::canonicalize(&image) {
+            Ok(val) => Some(Some(val)),
+            Err(e) => {
+                pl_update_fatal_error!(e.kind(), "Could not find cover image \"{}\": {}", image, e);
+            }
+        },
+        None => None,
+    };
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
+        }
+    };
+
+    let mut playlist = parse_manifest(manifest)?;
+    record_playlist(&playlist);
+    let old_rules = playlist.cover_rules.header_fields();
+
+
+    if let Some(square) = square {
+        playlist.cover_rules.square = square;
+    }
+
+    if let Some(size) = size {
+        playlist.cover_rules.size = if size > 0 { Some(size) } else { None };
+    }
+
+    if let Some(image) = image {
+        let old_image = playlist.cover_rules.image.take();
+
+        if let Some(path) = image {
+            let file_ext = path.extension().and_then(|f| f.to_str()).unwrap_or("jpg").to_lowercase();
+            let image_filename = format!("cover.{}", file_ext);
+
+            if path != Path::new(&image_filename).canonicalize().unwrap_or_default() {
+                fs::copy(&path, &image_filename)?;
+            }
+
+            playlist.cover_rules.image = Some(image_filename);
+        } else {
+            playlist.cover_rules.fallback = false;
+        }
+
+        if let Some(old_image) = old_image.filter(|f| playlist.cover_rules.image.as_ref() != Some(f)) {
+            let _ = fs::remove_file(old_image);
+        }
+    }
+
+    if fallback {
+        if playlist.cover_rules.image.is_none() {
+            pl_update_fatal_error!(ErrorKind::InvalidInput, "--fallback needs an image, give one with --image.");
+        }
+        playlist.cover_rules.fallback = true;
+    }
+
+    if let Some(folder) = folder {
+        playlist.cover_rules.folder = folder;
+    }
+
+
+    if playlist.cover_rules.header_fields() != old_rules {
+        save_manifest(&playlist)?;
+        pl_update_println!("Saved cover rules for \"{}\".", playlist.title);
+    }
+
+
+    //folder.jpg is always rewritten here, as the art it was made from may have changed.
+    if (playlist.cover_rules.folder || folder == Some(false)) && fs::metadata(FOLDER_COVER).is_ok() {
+        fs::remove_file(FOLDER_COVER)?;
+    }
+
+    if playlist.cover_rules.is_empty() {
+        pl_update_println!("Playlist \"{}\" has no cover rules.", playlist.title);
+        return Ok(());
+    }
+
+    pl_update_println!("Embedding cover art...");
+    let changed = apply_cover_rules(&playlist, &playlist.songs, &options)?;
+    pl_update_println!("Changed the cover art of {} files.", changed);
+
+
+    Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn art_fits_crop_and_size_rules() {
+        let rules = CoverRules { square: true, size: Some(500), ..Default::default() };
+
+        assert!(art_fits(&rules, (500, 500)));
+        assert!(art_fits(&rules, (300, 300)));
+        assert!(!art_fits(&rules, (1280, 720)));
+        assert!(!art_fits(&rules, (720, 720)));
+        assert!(art_fits(&CoverRules { size: Some(1280), ..Default::default() }, (1280, 720)));
+    }
+}
@@ -0,0 +1,437 @@
+use regex::Regex;
+use std::{env::set_current_dir, fs::File, io::{Error, ErrorKind}};
+
+use crate::{parse_manifest, pl_update_fatal_error, save_manifest, unique_songs, Args, Excludes, Filters, Manifest, Song};
+use crate::history::record_playlist;
+
+
+/// Checks the filters can be passed to yt-dlp, so a bad filter is caught before it is saved.
+pub(crate) fn check_filters(filters: &Filters) -> Result<(), Error> {
+    if let Some(date_after) = &filters.date_after {
+        let date_format = Regex::new(r"^(\d{8}|(now|today|yesterday)([+-]\d+(day|week|month|year)s?)?)$").unwrap();
+
+        if !date_format.is_match(date_after) {
+            pl_update_fatal_error!(ErrorKind::InvalidInput, "\"{}\" is not a date yt-dlp understands, use YYYYMMDD or a relative date like \"now-1month\".", date_after);
+        }
+    }
+
+    if let (Some(min_duration), Some(max_duration)) = (filters.min_duration, filters.max_duration) {
+        if min_duration > max_duration {
+            pl_update_fatal_error!(ErrorKind::InvalidInput, "The minimum duration ({}s) is longer than the maximum duration ({}s).", min_duration, max_duration);
+        }
+    }
+
+    if let Some(title_match) = &filters.title_match {
+        if let Err(e) = Regex::new(title_match) {
+            pl_update_fatal_error!(ErrorKind::InvalidInput, "Title filter \"{}\" is not a valid regex: {}", title_match, e);
+        }
+    }
+
+    if filters.max_items == Some(0) {
+        pl_update_fatal_error!(ErrorKind::InvalidInput, "The maximum number of items must be at least 1.");
+    }
+
+    Ok(())
+}
+
+
+/// A readable description of each filter that is set.
+pub(crate) fn describe_filters(filters: &Filters) -> Vec<String> {
+    let mut descriptions = Vec::new();
+
+    if let Some(date_after) = &filters.date_after {
+        descriptions.push(format!("uploaded on or after {}", date_after));
+    }
+
+    if let Some(min_duration) = filters.min_duration {
+        descriptions.push(format!("at least {}s long", min_duration));
+    }
+
+    if let Some(max_duration) = filters.max_duration {
+        descriptions.push(format!("at most {}s long", max_duration));
+    }
+
+    if let Some(title_match) = &filters.title_match {
+        descriptions.push(format!("titles matching \"{}\"", title_match));
+    }
+
+    if let Some(max_items) = filters.max_items {
+        descriptions.push(format!("first {} songs of each source", max_items));
+    }
+
+    if let Some(match_filter) = &filters.match_filter {
+        descriptions.push(format!("passes \"{}\"", match_filter));
+    }
+
+    descriptions
+}
+
+
+pub(crate) fn compile_excludes(excludes: &Excludes) -> Result<Vec<Regex>, Error> {
+    let mut titles = Vec::new();
+
+    for pattern in &excludes.titles {
+        match Regex::new(pattern) {
+            Ok(val) => titles.push(val),
+            Err(e) => {
+                pl_update_fatal_error!(ErrorKind::InvalidInput, "Exclude rule \"{}\" is not a valid regex: {}", pattern, e);
+            }
+        }
+    }
+
+    Ok(titles)
+}
+
+
+/// The reason song is excluded by the playlist's rules, if it is.
+pub(crate) fn exclude_reason(excludes: &Excludes, titles: &[Regex], song: &Song) -> Option<String> {
+    if excludes.ids.contains(&song.id) {
+        return Some("skipped".to_string());
+    }
+
+    if let Some(title) = titles.iter().find(|f| f.is_match(&song.title)) {
+        return Some(format!("title matches \"{}\"", title));
+    }
+
+    if let (Some(longer_than), Some(duration)) = (excludes.longer_than, song.duration) {
+        if duration > longer_than * 60 {
+            return Some(format!("longer than {} minutes", longer_than));
+        }
+    }
+
+    if let Some(live_status) = song.live_status.as_ref().filter(|f| excludes.live && *f != "not_live") {
+        return Some(format!("live status is {}", live_status));
+    }
+
+    None
+}
+
+
+/// Removes the songs the playlist's exclude rules match, renumbering the songs that are left. Songs added by hand
+/// are only removed when they are skipped, as they were wanted whatever the rules say.
+/// Returns each removed song with the reason it was excluded.
+pub(crate) fn apply_excludes(playlist: &mut Manifest) -> Result<Vec<(Song, String)>, Error> {
+    if playlist.excludes.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let titles = compile_excludes(&playlist.excludes)?;
+
+    let excluded: Vec<(Song, String)> = unique_songs(&playlist.songs).into_iter()
+        .filter(|song| !song.pinned || playlist.excludes.ids.contains(&song.id))
+        .filter_map(|song| exclude_reason(&playlist.excludes, &titles, &song).map(|reason| (song, reason)))
+        .collect();
+
+    if excluded.is_empty() {
+        return Ok(excluded);
+    }
+
+    playlist.songs.retain(|song| !excluded.iter().any(|(f, _)| f == song));
+
+    for (position, song) in playlist.songs.iter_mut().enumerate() {
+        song.position = position + 1;
+    }
+
+    Ok(excluded)
+}
+
+
+/// A readable description of each exclude rule other than skipped songs.
+pub(crate) fn describe_excludes(excludes: &Excludes) -> Vec<String> {
+    let mut descriptions = Vec::new();
+
+    for title in &excludes.titles {
+        descriptions.push(format!("titles matching \"{}\"", title));
+    }
+
+    if let Some(longer_than) = excludes.longer_than {
+        descriptions.push(format!("longer than {} minutes", longer_than));
+    }
+
+    if excludes.live {
+        descriptions.push("live streams".to_string());
+    }
+
+    descriptions
+}
+
+
+pub(crate) fn pl_filter(_options: Args, playlist_name: Option<String>, filters: Filters, clear: bool) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
+        }
+    };
+
+    let mut playlist = parse_manifest(manifest)?;
+    let old_filters = playlist.filters.clone();
+
+
+    if clear {
+        playlist.filters = Filters::default();
+    }
+
+    playlist.filters.merge(filters);
+
+
+    if playlist.filters != old_filters {
+        check_filters(&playlist.filters)?;
+
+        record_playlist(&playlist);
+        save_manifest(&playlist)?;
+        pl_update_println!("Saved filters for \"{}\", they will be applied on the next update.", playlist.title);
+    }
+
+
+    //The filters are the output of this command, so they are printed even when quiet.
+    if playlist.filters.is_empty() {
+        println!("Playlist \"{}\" has no filters.", playlist.title);
+    }
+
+    for description in describe_filters(&playlist.filters) {
+        println!("Filter: {}", description);
+    }
+
+
+    Ok(())
+}
+
+
+pub(crate) fn pl_exclude(_options: Args, playlist_name: Option<String>, titles: Vec<String>, longer_than: Option<u64>, live: Option<bool>, clear: bool) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
+        }
+    };
+
+    let mut playlist = parse_manifest(manifest)?;
+    let old_excludes = playlist.excludes.clone();
+
+
+    if clear {
+        playlist.excludes = Excludes::default();
+    }
+
+    for title in titles {
+        if !playlist.excludes.titles.contains(&title) {
+            playlist.excludes.titles.push(title);
+        }
+    }
+
+    if let Some(longer_than) = longer_than {
+        playlist.excludes.longer_than = if longer_than > 0 { Some(longer_than) } else { None };
+    }
+
+    if let Some(live) = live {
+        playlist.excludes.live = live;
+    }
+
+
+    if playlist.excludes != old_excludes {
+        compile_excludes(&playlist.excludes)?; //Check the new rules before they are saved
+
+        record_playlist(&playlist);
+        save_manifest(&playlist)?;
+        pl_update_println!("Saved exclude rules for \"{}\", they will be applied on the next update.", playlist.title);
+    }
+
+
+    //The rules are the output of this command, so they are printed even when quiet.
+    if playlist.excludes.is_empty() {
+        println!("Playlist \"{}\" has no exclude rules.", playlist.title);
+    }
+
+    for description in describe_excludes(&playlist.excludes) {
+        println!("Exclude: {}", description);
+    }
+
+    for id in &playlist.excludes.ids {
+        println!("Skip: {}", id);
+    }
+
+
+    Ok(())
+}
+
+
+pub(crate) fn pl_skip(_options: Args, playlist_name: Option<String>, ids: Vec<String>, undo: bool) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
+        }
+    };
+
+    let mut playlist = parse_manifest(manifest)?;
+    let old_ids = playlist.excludes.ids.clone();
+
+
+    for id in ids {
+        let title = playlist.songs.iter().find(|f| f.id == id).map_or(String::new(), |f| format!(" \"{}\"", f.title));
+
+        if undo {
+            if !playlist.excludes.ids.contains(&id) {
+                pl_update_warn!("[{}] is not skipped.", id);
+                continue;
+            }
+
+            playlist.excludes.ids.retain(|f| *f != id);
+            pl_update_println!("Stopped skipping [{}], it will be downloaded on the next update if it is still in the playlist.", id);
+        } else {
+            if playlist.excludes.ids.contains(&id) {
+                pl_update_warn!("[{}] is already skipped.", id);
+                continue;
+            }
+
+            if title.is_empty() {
+                pl_update_warn!("[{}] is not in the manifest, it will still be skipped if it is added to the playlist.", id);
+            }
+
+            playlist.excludes.ids.push(id.clone());
+            pl_update_println!("Skipping{} [{}], it will be removed on the next update.", title, id);
+        }
+    }
+
+
+    if playlist.excludes.ids != old_ids {
+        record_playlist(&playlist);
+        save_manifest(&playlist)?;
+    }
+
+
+    Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(title: &str, duration: Option<u64>, live_status: Option<&str>) -> Song {
+        let mut song = Song::new(title.to_string(), "dQw4w9WgXcQ".to_string(), None, 1, None);
+        song.duration = duration;
+        song.live_status = live_status.map(|f| f.to_string());
+        song
+    }
+
+
+    #[test]
+    fn no_rules_exclude_nothing() {
+        assert_eq!(exclude_reason(&Excludes::default(), &[], &song("Song", Some(10_000), Some("was_live"))), None);
+    }
+
+    #[test]
+    fn excludes_skipped_ids_first() {
+        let excludes = Excludes { ids: vec!["dQw4w9WgXcQ".to_string()], titles: vec!["(?i)live".to_string()], ..Default::default() };
+        let titles = compile_excludes(&excludes).unwrap();
+
+        assert_eq!(exclude_reason(&excludes, &titles, &song("Song (Live)", None, None)), Some("skipped".to_string()));
+    }
+
+    #[test]
+    fn excludes_matching_titles() {
+        let excludes = Excludes { titles: vec!["(?i)\\blive\\b".to_string()], ..Default::default() };
+        let titles = compile_excludes(&excludes).unwrap();
+
+        assert_eq!(exclude_reason(&excludes, &titles, &song("Song (LIVE)", None, None)), Some("title matches \"(?i)\\blive\\b\"".to_string()));
+        assert_eq!(exclude_reason(&excludes, &titles, &song("Oliver", None, None)), None);
+    }
+
+    #[test]
+    fn excludes_long_songs() {
+        let excludes = Excludes { longer_than: Some(10), ..Default::default() };
+
+        assert_eq!(exclude_reason(&excludes, &[], &song("Mix", Some(601), None)), Some("longer than 10 minutes".to_string()));
+        assert_eq!(exclude_reason(&excludes, &[], &song("Song", Some(600), None)), None);
+        assert_eq!(exclude_reason(&excludes, &[], &song("Unknown length", None, None)), None);
+    }
+
+    #[test]
+    fn excludes_live_streams() {
+        let excludes = Excludes { live: true, ..Default::default() };
+
+        assert_eq!(exclude_reason(&excludes, &[], &song("Stream", None, Some("was_live"))), Some("live status is was_live".to_string()));
+        assert_eq!(exclude_reason(&excludes, &[], &song("Song", None, Some("not_live"))), None);
+        assert_eq!(exclude_reason(&excludes, &[], &song("Song", None, None)), None);
+    }
+
+    #[test]
+    fn rejects_invalid_title_regex() {
+        let excludes = Excludes { titles: vec!["(".to_string()], ..Default::default() };
+
+        assert_eq!(compile_excludes(&excludes).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn renumbers_songs_left_after_excludes() {
+        let mut playlist = Manifest::new("Mix".to_string(), String::new());
+        playlist.excludes.titles.push("(?i)live".to_string());
+        playlist.songs = ["Song", "Song (Live)", "Other"].iter().enumerate()
+            .map(|(i, title)| Song::new(title.to_string(), i.to_string(), None, i + 1, None)).collect();
+
+        let excluded = apply_excludes(&mut playlist).unwrap();
+
+        assert_eq!(excluded.iter().map(|(f, _)| f.id.as_str()).collect::<Vec<_>>(), ["1"]);
+        assert_eq!(playlist.songs.iter().map(|f| (f.id.as_str(), f.position)).collect::<Vec<_>>(), [("0", 1), ("2", 2)]);
+    }
+
+    #[test]
+    fn keeps_pinned_songs_unless_skipped() {
+        let mut playlist = Manifest::new("Mix".to_string(), String::new());
+        playlist.excludes.titles.push("(?i)live".to_string());
+
+        let mut pinned = Song::new("Pinned (Live)".to_string(), "a".to_string(), None, 1, None);
+        pinned.pinned = true;
+        playlist.songs = vec![pinned, Song::new("Other (Live)".to_string(), "b".to_string(), None, 2, None), Song::new("Song".to_string(), "c".to_string(), None, 3, None)];
+
+        let excluded = apply_excludes(&mut playlist).unwrap();
+        assert_eq!(excluded.iter().map(|(f, _)| f.id.as_str()).collect::<Vec<_>>(), ["b"]);
+        assert_eq!(playlist.songs.iter().map(|f| (f.id.as_str(), f.position)).collect::<Vec<_>>(), [("a", 1), ("c", 2)]);
+
+        playlist.excludes.ids.push("a".to_string());
+        let excluded = apply_excludes(&mut playlist).unwrap();
+        assert_eq!(excluded.iter().map(|(f, r)| (f.id.as_str(), r.as_str())).collect::<Vec<_>>(), [("a", "skipped")]);
+    }
+}
@@ -0,0 +1,229 @@
+use chrono::DateTime;
+use std::{env::{current_dir, set_current_dir}, fs::{self, File, OpenOptions}, io::{BufRead, BufReader, Error, ErrorKind, Write}, path::Path};
+
+use crate::snapshots::snapshot_path;
+use crate::summary::RunSummary;
+use crate::{moved_songs, parse_manifest, pl_update_fatal_error, run_summary, unique_songs, Args, Manifest, METADATA_DIR};
+
+
+/// One line of JSON per run of a command that worked on the playlist, oldest first. Lines are only ever appended.
+/// Kept in the metadata directory.
+const HISTORY_FILE: &str = "history";
+
+
+
+/// Records that this run works on the playlist in the current directory, so the run is added to its history.
+/// Commands that only list or check the playlist call this once they change the manifest, so their history is only of changes.
+pub(crate) fn record_playlist(playlist: &Manifest) {
+    let mut summary = run_summary();
+    summary.playlist = Some(playlist.title.clone());
+    summary.playlist_dir = current_dir().ok();
+}
+
+
+/// Adds summary to the end of the history of the playlist it worked on, if it worked on one.
+pub(crate) fn append_history(summary: &RunSummary) -> Result<(), Error> {
+    let Some(playlist_dir) = &summary.playlist_dir else {
+        return Ok(());
+    };
+
+    let mut line = serde_json::to_string(summary)?;
+    line.push('\n');
+
+    let metadata_dir = playlist_dir.join(METADATA_DIR);
+    fs::create_dir_all(&metadata_dir)?;
+
+    //A single write, so a run that is killed leaves a whole line or none.
+    OpenOptions::new().create(true).append(true).open(metadata_dir.join(HISTORY_FILE))?.write_all(line.as_bytes())
+}
+
+
+pub(crate) fn read_history() -> Result<Vec<RunSummary>, Error> {
+    let history = match File::open(Path::new(METADATA_DIR).join(HISTORY_FILE)) {
+        Ok(val) => val,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            pl_update_fatal_error!(e.kind(), "Could not open playlist history: {}", e);
+        }
+    };
+
+    let mut runs = Vec::new();
+
+    for (number, line) in BufReader::new(history).lines().enumerate() {
+        let line = line?;
+
+        match serde_json::from_str(&line) {
+            Ok(run) => runs.push(run),
+            Err(e) => {
+                pl_update_warn!("Line {} of the playlist history could not be read, it is shown as an empty run: {}", number + 1, e);
+                runs.push(RunSummary::default());
+            }
+        }
+    }
+
+    Ok(runs)
+}
+
+
+/// The manifest as it was after run, which is the snapshot taken by the next run that changed the manifest.
+/// A run that nothing has changed the manifest since is the current manifest.
+fn manifest_after(runs: &[RunSummary], run: usize) -> Option<&str> {
+    runs.iter().skip(run).find_map(|f| f.snapshot.as_deref())
+}
+
+
+/// Opens the manifest a diff argument names: a run number from history, "current", or the filename of a manifest.
+fn open_manifest(runs: &[RunSummary], name: &str) -> Result<(String, Manifest), Error> {
+    let (description, filename, path) = match name.parse::<usize>() {
+        Ok(run) if run == 0 || run > runs.len() => {
+            pl_update_fatal_error!(ErrorKind::InvalidInput, "There is no run {}, the history has {} runs.", run, runs.len());
+        },
+        Ok(run) => match manifest_after(runs, run) {
+            Some(snapshot) => (format!("after run {}", run), snapshot.to_string(), snapshot_path(snapshot)),
+            None => (format!("after run {}", run), "playlist.manifest".to_string(), "playlist.manifest".into()),
+        },
+        Err(_) if name == "current" => ("current".to_string(), "playlist.manifest".to_string(), "playlist.manifest".into()),
+        Err(_) if Path::new(name).exists() => (name.to_string(), name.to_string(), name.into()),
+        Err(_) => (name.to_string(), name.to_string(), snapshot_path(name)),
+    };
+
+    let manifest = match File::open(&path) {
+        Ok(val) => val,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            pl_update_fatal_error!(ErrorKind::NotFound, "Manifest \"{}\" ({}) is not kept any more, it may have been deleted by --keep-snapshots or --snapshot-max-age.", filename, description);
+        },
+        Err(e) => {
+            pl_update_fatal_error!(e.kind(), "Could not open manifest \"{}\" ({}): {}", filename, description, e);
+        }
+    };
+
+    Ok((format!("{} ({})", description, filename), parse_manifest(manifest)?))
+}
+
+
+pub(crate) fn pl_history(_options: Args, playlist_name: Option<String>, limit: Option<usize>, show_songs: bool) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let runs = read_history()?;
+
+    //The history is the output of this command, so it is printed even when quiet.
+    if runs.is_empty() {
+        println!("The playlist has no history yet.");
+    }
+
+    let first = runs.len().saturating_sub(limit.unwrap_or(runs.len()));
+
+    for (number, run) in runs.iter().enumerate().skip(first) {
+        let started = DateTime::parse_from_rfc3339(&run.started).map_or(run.started.clone(), |f| f.format("%Y-%m-%d %H:%M:%S").to_string());
+        let ytdl = run.tools.get("yt-dlp").map_or(String::new(), |f| format!(", yt-dlp {}", f));
+
+        println!("Run {}: {} {}, exit code {} after {:.1}s{}", number + 1, started, run.command, run.exit_code, run.duration_seconds, ytdl);
+        println!("    {} added, {} removed, {} renamed, {} moved, {} failed", run.added.len(), run.removed.len(), run.renamed.len(), run.moved.len(), run.failed.len());
+
+        if let Some(error) = &run.error {
+            println!("    Error: {}", error);
+        }
+
+        for source in &run.failed_sources {
+            println!("    Could not list: {}", source);
+        }
+
+        if show_songs {
+            for song in &run.added {
+                println!("    + \"{}\" [{}]", song.title, song.id);
+            }
+
+            for song in &run.removed {
+                println!("    - \"{}\" [{}]", song.title, song.id);
+            }
+
+            for song in &run.renamed {
+                println!("    ~ \"{}\" [{}]", song.title, song.id);
+            }
+
+            for song in &run.failed {
+                println!("    ! \"{}\" [{}]: {}", song.title, song.id, song.reason);
+            }
+        }
+    }
+
+
+    Ok(())
+}
+
+
+pub(crate) fn pl_diff(_options: Args, playlist_name: Option<String>, from: String, to: String) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let runs = read_history()?;
+    let (from_description, old_playlist) = open_manifest(&runs, &from)?;
+    let (to_description, new_playlist) = open_manifest(&runs, &to)?;
+
+    let old_songs = unique_songs(&old_playlist.songs);
+    let new_songs = unique_songs(&new_playlist.songs);
+
+
+    //The differences are the output of this command, so they are printed even when quiet.
+    println!("Comparing {} with {}", from_description, to_description);
+
+    if old_playlist.title != new_playlist.title {
+        println!("Title: \"{}\" -> \"{}\"", old_playlist.title, new_playlist.title);
+    }
+
+    for source in old_playlist.sources.iter().filter(|f| !new_playlist.sources.contains(f)) {
+        println!("Source removed: {}", source);
+    }
+
+    for source in new_playlist.sources.iter().filter(|f| !old_playlist.sources.contains(f)) {
+        println!("Source added: {}", source);
+    }
+
+    let added: Vec<_> = new_songs.iter().filter(|f| !old_songs.contains(f)).collect();
+    let removed: Vec<_> = old_songs.iter().filter(|f| !new_songs.contains(f)).collect();
+    let renamed: Vec<_> = old_songs.iter().filter_map(|old_song| new_songs.iter().find(|f| old_song.is_renamed(f)).map(|new_song| (old_song, new_song))).collect();
+    let moved = moved_songs(&old_songs, &new_songs);
+
+    for song in &added {
+        println!("Added: \"{}\" [{}] at position {}", song.title, song.id, song.position);
+    }
+
+    for song in &removed {
+        println!("Removed: \"{}\" [{}]", song.title, song.id);
+    }
+
+    for (old_song, new_song) in &renamed {
+        println!("Renamed: \"{}\" -> \"{}\" [{}]", old_song.title, new_song.title, new_song.id);
+    }
+
+    for song in &moved {
+        let old_position = old_songs.iter().find(|f| *f == song).map_or(0, |f| f.position);
+        println!("Moved: \"{}\" [{}] from position {} to {}", song.title, song.id, old_position, song.position);
+    }
+
+    println!("Summary: {} added, {} removed, {} renamed, {} moved.", added.len(), removed.len(), renamed.len(), moved.len());
+
+
+    Ok(())
+}
@@ -1,10 +1,16 @@
 
+use crate::fetch_songs;
+use crate::history::record_playlist;
 use crate::update_manifest;
 use crate::Args;
+use crate::Filters;
+use crate::LoudnessMode;
+use crate::Manifest;
+use crate::SEP_CHAR;
 
 use core::str;
 use std::env::set_current_dir;
-use std::fs::create_dir;
+use std::fs::create_dir_all;
 use std::fs::read_dir;
 use std::fs::File;
 use std::fs::OpenOptions;
@@ -12,53 +18,48 @@ use std::io::stdout;
 use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Write;
+use std::path::Path;
 use std::process::Command;
 
-use colored::Colorize;
 
 use crate::download;
+use crate::filters::check_filters;
 use crate::find_yt_dl;
+use crate::log::{console_enabled, Level};
 
 use crate::parse_manifest;
+use crate::save_downloads;
+use crate::report_duplicates;
+use crate::run_summary;
+use crate::sanitize_dir_name;
+use crate::song_urls;
+use crate::unique_songs;
+use crate::unused_dir_name;
 
 use crate::pl_update_fatal_error;
 
 
+/// Where init puts the playlist, and what it is called.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub(crate) struct Destination {
+    /// The name of the playlist. Defaults to the title of the first playlist, or of the first video of a manual playlist.
+    #[arg(long)]
+    name: Option<String>,
 
-pub(crate) fn pl_init(options: Args, playlist_url: String) -> Result<(), Error> {
-    macro_rules! pl_update_vprintln {
-        ($($x:expr),*) => {
-            if options.verbose {
-                println!("{} [pl-update] {}", "DEBUG:".blue(),
-                format! (
-                    $(
-                        $x,
-                    )*
-                )
+    /// The directory to create the playlist in. Defaults to the playlist's name, made safe to use as a directory name.
+    #[arg(long)]
+    dir: Option<String>,
+}
 
-                )
-            }
-        };
-    }
 
-    macro_rules! pl_update_println {
-        ($($x:expr),*) => {
-            if !options.quiet {
-                println!("[pl-update] {}",
-                format! (
-                        $(
-                            $x,
-                        )*
-                    )
-                )
-            }
-        };
-    }
+pub(crate) fn pl_init(options: Args, playlist_urls: Vec<String>, destination: Destination, track_numbers: bool, audio_format: String, loudness_mode: LoudnessMode, filters: Filters) -> Result<(), Error> {
 
 
+    check_filters(&filters)?;
+
     let mut output_args = Vec::new();
 
-    if options.verbose {
+    if console_enabled(Level::Debug) {
         output_args.push("--verbose".to_owned());
         output_args.push("--quiet".to_owned());
     }
@@ -72,15 +73,15 @@ pub(crate) fn pl_init(options: Args, playlist_url: String) -> Result<(), Error>
     output_args.push("--simulate".to_owned());
     output_args.push("--flat-playlist".to_owned());
     output_args.push("--lazy-playlist".to_owned());
-    output_args.push(playlist_url.clone());
+    output_args.push(playlist_urls[0].clone());
     
     output_args.push("--print".to_owned());
-    output_args.push("%(playlist)s".to_owned());
+    output_args.push(format!("%(playlist_id)s{SEP_CHAR}%(playlist,channel,uploader)s{SEP_CHAR}%(title)s")); //Channels and searches may not have a playlist title
     output_args.push("--playlist-items=1".to_owned());
     
     
     let command_name = options.yt_dl_location.clone();
-    find_yt_dl(options.verbose, &command_name)?;
+    find_yt_dl(&command_name)?;
 
     pl_update_vprintln!("Running {} with arguments {:?}", command_name, output_args);
 
@@ -89,32 +90,63 @@ pub(crate) fn pl_init(options: Args, playlist_url: String) -> Result<(), Error>
             .output()?;
 
 
-
-    
-    let playlist_name = str::from_utf8(&ytdl_output.stdout).expect("output should be valid utf-8").trim();
+    let ytdl_stdout = str::from_utf8(&ytdl_output.stdout).expect("output should be valid utf-8").trim();
     
     stdout().write_all(&ytdl_output.stderr)?;
 
-    
-    if playlist_name == "NA" {
-        pl_update_fatal_error!(ErrorKind::InvalidInput, "URL provided was not a playlist, or playlist name was NA (playlist name cannot be NA)");
-    } else if playlist_name.contains('\n') {
-        panic!();
+    let fields: Vec<&str> = ytdl_stdout.split(SEP_CHAR).collect();
+
+    if fields.len() != 3 || ytdl_stdout.contains('\n') {
+        pl_update_fatal_error!(ErrorKind::InvalidData, "Could not read the title of \"{}\", yt-dlp printed: {}", playlist_urls[0], ytdl_stdout);
     }
 
+    //A url that is not part of a playlist is a single video, which starts a manual playlist.
+    let manual = fields[0] == "NA";
+
+    let playlist_name = match &destination.name {
+        Some(name) => name.as_str(),
+        None if manual => fields[2],
+        None => fields[1],
+    };
+
+    //The manifest is line based, so the title is kept on one line.
+    let playlist_name = playlist_name.replace(['\r', '\n'], " ");
+    let playlist_name = playlist_name.trim();
+
+    if playlist_name == "NA" || playlist_name.is_empty() {
+        pl_update_fatal_error!(ErrorKind::InvalidInput, "The playlist has no name (playlist name cannot be NA), give one with --name");
+    } else if manual {
+        pl_update_println!("\"{}\" is a single video, creating a manual playlist.", playlist_urls[0]);
+    }
+
+
+    //A directory that was asked for is used as is, one made from the title is moved aside from any that is in use.
+    let dir_name = match destination.dir {
+        Some(dir) => dir,
+        None => {
+            let dir_name = sanitize_dir_name(playlist_name);
+            let unused = unused_dir_name(Path::new("."), &dir_name);
 
-    match read_dir(playlist_name) {
+            if unused != dir_name {
+                pl_update_println!("Directory \"{}\" already exists and is not empty, using \"{}\"", dir_name, unused);
+            }
+
+            unused
+        }
+    };
+
+    match read_dir(&dir_name) {
         Ok(directory) => {
             if directory.count() > 0 {
-                pl_update_fatal_error!(ErrorKind::AlreadyExists, "Directory \"{}\" already exists, and is not empty.", playlist_name);
+                pl_update_fatal_error!(ErrorKind::AlreadyExists, "Directory \"{}\" already exists, and is not empty.", dir_name);
             } else {
-                pl_update_vprintln!("Using existing directory \"{}\"", playlist_name);
+                pl_update_vprintln!("Using existing directory \"{}\"", dir_name);
             }
         },
         Err(e) => {
             if e.kind() == ErrorKind::NotFound {
-                create_dir(playlist_name)?;
-                pl_update_vprintln!("Created directory \"{}\"", playlist_name);
+                create_dir_all(&dir_name)?;
+                pl_update_vprintln!("Created directory \"{}\"", dir_name);
             } else {
                 pl_update_fatal_error!(e);
             }
@@ -122,29 +154,59 @@ pub(crate) fn pl_init(options: Args, playlist_url: String) -> Result<(), Error>
     }
 
 
-    set_current_dir(playlist_name)?;
+    set_current_dir(&dir_name)?;
 
-    let manifest = OpenOptions::new().read(true).write(true).create(true).open("playlist.manifest")?;
-
-    
-    
+    let manifest = OpenOptions::new().read(true).write(true).create(true).truncate(true).open("playlist.manifest")?;
 
 
     pl_update_println!("Fetching contents of playlist \"{playlist_name}\"");
 
-    update_manifest(manifest, playlist_name.to_string(), &playlist_url, &options)?;
+    let mut playlist = Manifest::new(playlist_name.to_string(), playlist_urls[0].clone());
+    playlist.remote_title = Some(fields[1].to_string()).filter(|f| !manual && f != "NA");
+    playlist.track_numbers = track_numbers;
+    playlist.audio_format = audio_format;
+    playlist.loudness_mode = loudness_mode;
+    playlist.filters = filters;
+
+    if manual {
+        //Manual playlists have no sources, each song is pinned so update keeps it.
+        playlist.sources = Vec::new();
+
+        for url in &playlist_urls {
+            for mut song in fetch_songs(url, &playlist.filters, &options)? {
+                if !playlist.songs.contains(&song) {
+                    song.position = playlist.songs.len() + 1;
+                    song.pinned = true;
+                    playlist.songs.push(song);
+                }
+            }
+        }
+
+        playlist.write(manifest)?;
+    } else {
+        playlist.sources = playlist_urls;
+        update_manifest(manifest, &playlist, &options)?;
+    }
     pl_update_println!("Manifest created.");
     
     
     pl_update_println!("Parsing urls from manifest...");
-    let (songs, _, _) = parse_manifest(File::open("playlist.manifest")?).unwrap();
-    let song_urls: Vec<String> = songs.iter().map(|f| f.url.clone().expect("song should have url")).collect();
+    let mut playlist = parse_manifest(File::open("playlist.manifest")?).unwrap();
+    let songs = unique_songs(&playlist.songs);
+
+    report_duplicates(&playlist.songs);
+
+    record_playlist(&playlist);
+    run_summary().added = songs.iter().map(Into::into).collect();
+
+    let song_urls = song_urls(&songs);
 
     pl_update_println!("Successfully parsed {} urls from manifest.", song_urls.len());
     pl_update_vprintln!("Urls: {:?}", song_urls);
 
     pl_update_println!("Downloading...");
-    download(song_urls, &options)?;
+    let failures = download(&songs, &playlist.audio_format, &options)?;
+    save_downloads(&mut playlist, &songs, &failures, &options)?;
 
 
     Ok(())
@@ -0,0 +1,159 @@
+use chrono::{Local, SecondsFormat};
+use clap::ValueEnum;
+use colored::Colorize;
+use serde::Serialize;
+use std::{fs::{File, OpenOptions}, io::{Error, Write}, path::Path, sync::{Mutex, OnceLock}};
+
+
+/// How much is logged, each level includes the ones before it.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub(crate) enum LogFormat {
+    /// One timestamped line per message
+    #[default]
+    Text,
+    /// One JSON object per line, with time, level and message fields
+    Json,
+}
+
+
+struct Logger {
+    console: Level,
+    format: LogFormat,
+    file: Option<Mutex<File>>,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    level: Level,
+    message: &'a str,
+}
+
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// The log file records at least this much, whatever is shown on the console.
+const FILE_LEVEL: Level = Level::Debug;
+
+
+/// Sets where messages go for the rest of the run. Until it is called, messages are printed at the info level.
+pub(crate) fn init_logging(console: Level, format: LogFormat, file: Option<&Path>) -> Result<(), Error> {
+    let file = match file {
+        Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        None => None,
+    };
+
+    let _ = LOGGER.set(Logger { console, format, file });
+
+    Ok(())
+}
+
+
+fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| Logger { console: Level::Info, format: LogFormat::Text, file: None })
+}
+
+
+/// Whether messages at level are shown on the console.
+pub(crate) fn console_enabled(level: Level) -> bool {
+    level <= logger().console
+}
+
+
+/// Whether messages at level go anywhere, so expensive messages can be skipped.
+pub(crate) fn log_enabled(level: Level) -> bool {
+    let logger = logger();
+    level <= logger.console || (logger.file.is_some() && level <= logger.console.max(FILE_LEVEL))
+}
+
+
+pub(crate) fn log(level: Level, message: &str) {
+    if console_enabled(level) {
+        match level {
+            Level::Error => eprintln!("{} [pl-update] {}", "ERROR:".red().bold(), message),
+            Level::Warn => eprintln!("{} [pl-update] {}", "WARNING:".yellow(), message),
+            Level::Info => println!("[pl-update] {}", message),
+            Level::Debug => println!("{} [pl-update] {}", "DEBUG:".blue(), message),
+            Level::Trace => println!("{} [pl-update] {}", "TRACE:".dimmed(), message),
+        }
+    }
+
+    log_to_file(level, message);
+}
+
+
+/// Records message in the log file only, for output that is already printed some other way.
+pub(crate) fn log_to_file(level: Level, message: &str) {
+    let logger = logger();
+
+    let Some(file) = &logger.file else {
+        return;
+    };
+
+    if level > logger.console.max(FILE_LEVEL) {
+        return;
+    }
+
+    let time = Local::now().to_rfc3339_opts(SecondsFormat::Millis, false);
+
+    let mut line = match logger.format {
+        LogFormat::Text => format!("{} {:<5} {}", time, format!("{:?}", level).to_uppercase(), message),
+        LogFormat::Json => serde_json::to_string(&Record { time, level, message }).unwrap_or_default(),
+    };
+    line.push('\n');
+
+    //A log that cannot be written should not stop the run, it is only lost.
+    let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = file.write_all(line.as_bytes());
+}
+
+
+#[macro_export]
+macro_rules! pl_update_error {
+    ($($x:tt)*) => {
+        $crate::log::log($crate::log::Level::Error, &format!($($x)*))
+    };
+}
+
+#[macro_export]
+macro_rules! pl_update_warn {
+    ($($x:tt)*) => {
+        $crate::log::log($crate::log::Level::Warn, &format!($($x)*))
+    };
+}
+
+/// Progress messages, hidden by --quiet.
+#[macro_export]
+macro_rules! pl_update_println {
+    ($($x:tt)*) => {
+        $crate::log::log($crate::log::Level::Info, &format!($($x)*))
+    };
+}
+
+/// Debugging messages, shown by --verbose.
+#[macro_export]
+macro_rules! pl_update_vprintln {
+    ($($x:tt)*) => {
+        $crate::log::log($crate::log::Level::Debug, &format!($($x)*))
+    };
+}
+
+/// Messages too noisy for --verbose, shown by --log-level trace.
+#[macro_export]
+macro_rules! pl_update_trace {
+    ($($x:tt)*) => {
+        if $crate::log::log_enabled($crate::log::Level::Trace) {
+            $crate::log::log($crate::log::Level::Trace, &format!($($x)*))
+        }
+    };
+}
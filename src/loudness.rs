@@ -0,0 +1,394 @@
+use std::{env::set_current_dir, fs::{self, File}, io::{Error, ErrorKind}, process::Command, thread};
+
+use crate::{find_ffmpeg, interrupted, parse_manifest, pl_update_fatal_error, rewrite_tags, save_manifest, unique_songs, Args, LoudnessMode, Manifest, Song};
+use crate::history::record_playlist;
+
+
+/// The loudness ReplayGain 2.0 gains are relative to.
+const REPLAYGAIN_REFERENCE: f64 = -18.0;
+
+/// The highest true peak normalizing is allowed to raise a song to.
+const MAX_TRUE_PEAK: f64 = -1.0;
+
+/// Songs within this many dB of the target are not encoded again.
+const GAIN_TOLERANCE: f64 = 0.5;
+
+/// The loudness the EBU R128 filter reports for silence. Silent songs are not given a gain.
+const SILENCE: f64 = -70.0;
+
+const REPLAYGAIN_TAGS: [&str; 4] = ["REPLAYGAIN_TRACK_GAIN", "REPLAYGAIN_TRACK_PEAK", "REPLAYGAIN_ALBUM_GAIN", "REPLAYGAIN_ALBUM_PEAK"];
+
+
+/// Measures the integrated loudness (LUFS) and true peak (dBTP) of a file with ffmpeg's EBU R128 filter.
+fn analyse(ffmpeg_command: &String, filename: &str) -> Result<(f64, f64), Error> {
+    let ffmpeg_output = Command::new(ffmpeg_command)
+        .args(["-hide_banner", "-nostats", "-i", filename, "-map", "0:a:0", "-af", "ebur128=peak=true", "-f", "null", "-"])
+        .output()?;
+
+    let stderr = String::from_utf8_lossy(&ffmpeg_output.stderr);
+
+    if !ffmpeg_output.status.success() {
+        pl_update_fatal_error!(ErrorKind::Other, "FFMPEG could not analyse \"{}\": {}", filename, stderr.trim());
+    }
+
+    //The summary is printed last, after the log of every frame.
+    let summary_value = |label: &str| stderr.lines().rev()
+        .find_map(|f| f.trim().strip_prefix(label))
+        .and_then(|f| f.split_whitespace().next())
+        .and_then(|f| f.parse::<f64>().ok());
+
+    match (summary_value("I:"), summary_value("Peak:")) {
+        (Some(loudness), Some(peak)) => Ok((loudness, peak)),
+        _ => {
+            pl_update_fatal_error!(ErrorKind::InvalidData, "Could not find the loudness of \"{}\" in the output of FFMPEG.", filename);
+        }
+    }
+}
+
+
+/// Analyses each file, spread over the given number of threads. The results are in the same order as filenames.
+fn analyse_files(ffmpeg_command: &String, filenames: &[String], threads: usize) -> Vec<Result<(f64, f64), Error>> {
+    let files_per_thread = filenames.len().div_ceil(threads.max(1)).max(1);
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = filenames.chunks(files_per_thread).map(|chunk| scope.spawn(move ||
+            chunk.iter().map(|filename| if interrupted() {
+                Err(Error::new(ErrorKind::Interrupted, format!("Stopped before analysing \"{}\".", filename)))
+            } else {
+                analyse(ffmpeg_command, filename)
+            }).collect::<Vec<_>>()
+        )).collect();
+
+        handles.into_iter().flat_map(|f| f.join().unwrap()).collect()
+    })
+}
+
+
+/// The loudness and true peak of the playlist as a whole. Each song counts equally,
+/// as the playlist is as likely to be played on shuffle as in order.
+fn playlist_loudness(songs: &[Song]) -> Option<(f64, f64)> {
+    let measured: Vec<(f64, f64)> = songs.iter().filter_map(|f| f.loudness.zip(f.peak)).filter(|(loudness, _)| *loudness > SILENCE).collect();
+
+    if measured.is_empty() {
+        return None;
+    }
+
+    let power = measured.iter().map(|(loudness, _)| 10f64.powf(loudness / 10.0)).sum::<f64>() / measured.len() as f64;
+    let peak = measured.iter().map(|(_, peak)| *peak).fold(f64::NEG_INFINITY, f64::max);
+
+    Some((10.0 * power.log10(), peak))
+}
+
+
+fn replaygain_tags(song_loudness: (f64, f64), album_loudness: (f64, f64)) -> Vec<(&'static str, String)> {
+    let gain = |loudness: f64| format!("{:.2} dB", REPLAYGAIN_REFERENCE - loudness);
+    let peak = |peak: f64| format!("{:.6}", 10f64.powf(peak / 20.0)); //ReplayGain peaks are linear
+
+    vec![
+        (REPLAYGAIN_TAGS[0], gain(song_loudness.0)),
+        (REPLAYGAIN_TAGS[1], peak(song_loudness.1)),
+        (REPLAYGAIN_TAGS[2], gain(album_loudness.0)),
+        (REPLAYGAIN_TAGS[3], peak(album_loudness.1)),
+    ]
+}
+
+
+/// The codec and bitrate in kb/s of the first audio stream, from the stream information ffmpeg prints about its input.
+/// The bitrate of the whole file is used if the stream's is not known.
+fn parse_audio_encoding(ffmpeg_stderr: &str) -> (Option<String>, Option<u32>) {
+    let kbps = |text: &str| text.split(", ").find_map(|f| f.split_once(" kb/s")?.0.rsplit(' ').next()?.parse::<u32>().ok());
+
+    let stream = ffmpeg_stderr.lines().map(str::trim).find(|f| f.starts_with("Stream #") && f.contains("Audio: "));
+    let codec = stream.and_then(|f| f.split("Audio: ").nth(1)?.split([' ', ',']).next().map(str::to_string)).filter(|f| !f.is_empty());
+
+    let bitrate = stream.and_then(kbps)
+        .or_else(|| ffmpeg_stderr.lines().map(str::trim).find(|f| f.starts_with("Duration:")).and_then(kbps));
+
+    (codec, bitrate)
+}
+
+/// The ffmpeg arguments that encode audio like filename already is, so normalizing does not change its quality.
+fn encoder_args(ffmpeg_command: &String, filename: &str) -> Result<Vec<String>, Error> {
+    //ffmpeg fails as no output is given, after printing what it found in the input.
+    let ffmpeg_output = Command::new(ffmpeg_command).args(["-hide_banner", "-i", filename]).output()?;
+    let (codec, bitrate) = parse_audio_encoding(&String::from_utf8_lossy(&ffmpeg_output.stderr));
+
+    let Some(codec) = codec else {
+        pl_update_warn!("Could not find the codec of \"{}\", it is normalized with FFMPEG's default encoder for its format.", filename);
+        return Ok(Vec::new());
+    };
+
+    let lossless = ["flac", "alac", "wavpack"].contains(&codec.as_str()) || codec.starts_with("pcm_");
+
+    //Decoders and encoders share names, except for these.
+    let encoder = match codec.as_str() {
+        "mp3" => "libmp3lame",
+        "vorbis" => "libvorbis",
+        "opus" => "libopus",
+        other => other,
+    };
+
+    let mut args = vec!["-c:a".to_string(), encoder.to_string()];
+
+    match bitrate {
+        Some(bitrate) if !lossless => args.extend(["-b:a".to_string(), format!("{}k", bitrate)]),
+        None if !lossless => pl_update_warn!("Could not find the bitrate of \"{}\", it is normalized at FFMPEG's default bitrate.", filename),
+        _ => {},
+    }
+
+    Ok(args)
+}
+
+
+/// Re-encodes filename with its volume changed by gain dB, in the codec and at the bitrate it has. ReplayGain tags are removed, as they no longer apply.
+fn normalize(ffmpeg_command: &String, filename: &str, gain: f64) -> Result<(), Error> {
+    let file_ext = filename.rsplit_once('.').map_or("", |f| f.1);
+    let tmp_filename = format!("{}.normalize.{}", filename, file_ext);
+    let encoder_args = encoder_args(ffmpeg_command, filename)?;
+
+    let mut ffmpeg = Command::new(ffmpeg_command);
+    ffmpeg.args(["-y", "-loglevel", "error", "-i", filename, "-map", "0", "-c:v", "copy", "-af", &format!("volume={:.2}dB", gain)]);
+    ffmpeg.args(&encoder_args);
+
+    if file_ext == "mp3" {
+        ffmpeg.args(["-id3v2_version", "3"]);
+    }
+
+    for key in REPLAYGAIN_TAGS {
+        ffmpeg.arg("-metadata").arg(format!("{}=", key));
+    }
+
+    let ffmpeg_output = ffmpeg
+        .arg(&tmp_filename)
+        .output()?;
+
+    if !ffmpeg_output.status.success() {
+        let _ = fs::remove_file(&tmp_filename);
+        pl_update_fatal_error!(ErrorKind::Other, "FFMPEG could not normalize \"{}\": {}", filename, String::from_utf8_lossy(&ffmpeg_output.stderr).trim());
+    }
+
+    fs::rename(&tmp_filename, filename)?;
+
+    Ok(())
+}
+
+
+/// Analyses the songs in the playlist directory that have no stored loudness, then applies the playlist's loudness mode.
+/// The measurements are stored in playlist, so the caller should save its manifest afterwards.
+/// Returns the number of songs analysed and the number of files that were changed.
+pub(crate) fn apply_loudness(playlist: &mut Manifest, options: &Args) -> Result<(usize, usize), Error> {
+    if playlist.loudness_mode == LoudnessMode::Off {
+        return Ok((0, 0));
+    }
+
+    let ffmpeg_command = options.ffmpeg_location.clone().unwrap_or("ffmpeg".to_string());
+    find_ffmpeg(&ffmpeg_command)?;
+
+
+    let (pending_ids, pending_filenames): (Vec<String>, Vec<String>) = unique_songs(&playlist.songs).iter()
+        .filter(|f| f.loudness.is_none() || f.peak.is_none())
+        .map(|f| (f.id.clone(), playlist.song_filename(f)))
+        .filter(|(_, filename)| fs::metadata(filename).is_ok())
+        .unzip();
+
+    let results = analyse_files(&ffmpeg_command, &pending_filenames, options.threads);
+    let mut analysed = Vec::new();
+
+    for ((id, filename), result) in pending_ids.into_iter().zip(&pending_filenames).zip(results) {
+        match result {
+            Ok((loudness, peak)) => {
+                pl_update_vprintln!("\"{}\" is {:.2} LUFS with a peak of {:.2} dBTP", filename, loudness, peak);
+
+                for song in playlist.songs.iter_mut().filter(|f| f.id == id) {
+                    song.loudness = Some(loudness);
+                    song.peak = Some(peak);
+                }
+                analysed.push(id);
+            },
+            Err(_) if interrupted() => {},
+            Err(e) => pl_update_warn!("{}", e),
+        }
+    }
+
+
+    let mut changed = 0;
+
+    match playlist.loudness_mode {
+        LoudnessMode::Off => {},
+        LoudnessMode::Replaygain => {
+            if matches!(playlist.file_ext(), "m4a" | "mp4") {
+                pl_update_warn!("FFMPEG cannot write ReplayGain tags to {} files, use the normalize mode instead.", playlist.file_ext());
+                return Ok((analysed.len(), 0));
+            }
+
+            let album_loudness = playlist_loudness(&unique_songs(&playlist.songs));
+
+            //Every song carries the album gain, so all of them are rewritten when it changes.
+            let album_changed = album_loudness.map(|(l, p)| format!("{:.2} {:.2}", l, p)) != playlist.album_loudness.map(|(l, p)| format!("{:.2} {:.2}", l, p));
+
+            if let Some(album_loudness) = album_loudness {
+                for song in unique_songs(&playlist.songs) {
+                    //The album gain is not saved, so the songs that were not rewritten are on the next run.
+                    if interrupted() {
+                        return Ok((analysed.len(), changed));
+                    }
+
+                    let filename = playlist.song_filename(&song);
+
+                    let Some(song_loudness) = song.loudness.zip(song.peak) else { continue; };
+
+                    if song_loudness.0 <= SILENCE || !(album_changed || analysed.contains(&song.id)) || fs::metadata(&filename).is_err() {
+                        continue;
+                    }
+
+                    rewrite_tags(&ffmpeg_command, &filename, &filename, &replaygain_tags(song_loudness, album_loudness))?;
+                    changed += 1;
+                }
+            }
+
+            playlist.album_loudness = album_loudness;
+        },
+        LoudnessMode::Normalize => {
+            for song in unique_songs(&playlist.songs) {
+                if interrupted() {
+                    break;
+                }
+
+                let filename = playlist.song_filename(&song);
+
+                let Some((loudness, peak)) = song.loudness.zip(song.peak) else { continue; };
+
+                let gain = (playlist.loudness_target - loudness).min(MAX_TRUE_PEAK - peak);
+
+                if loudness <= SILENCE || !gain.is_finite() || gain.abs() < GAIN_TOLERANCE || fs::metadata(&filename).is_err() {
+                    continue;
+                }
+
+                pl_update_vprintln!("Changing the volume of \"{}\" by {:.2} dB", filename, gain);
+
+                normalize(&ffmpeg_command, &filename, gain)?;
+
+                //The stored measurements always describe the file as it is now.
+                for entry in playlist.songs.iter_mut().filter(|f| **f == song) {
+                    entry.loudness = Some(loudness + gain);
+                    entry.peak = Some(peak + gain);
+                }
+                changed += 1;
+            }
+
+            playlist.album_loudness = None;
+        }
+    }
+
+
+    Ok((analysed.len(), changed))
+}
+
+
+pub(crate) fn pl_loudness(options: Args, playlist_name: Option<String>, mode: Option<LoudnessMode>, target: Option<f64>, reanalyse: bool) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
+        }
+    };
+
+    let mut playlist = parse_manifest(manifest)?;
+    record_playlist(&playlist);
+    let old_header = playlist.header();
+
+
+    if let Some(mode) = mode {
+        playlist.loudness_mode = mode;
+    }
+
+    if let Some(target) = target {
+        if !(SILENCE..=0.0).contains(&target) {
+            pl_update_fatal_error!(ErrorKind::InvalidInput, "The target loudness must be between {} and 0 LUFS.", SILENCE);
+        }
+        playlist.loudness_target = target;
+    }
+
+    if playlist.loudness_mode != LoudnessMode::Replaygain {
+        playlist.album_loudness = None;
+    }
+
+    let mut manifest_changed = playlist.header() != old_header;
+
+    if reanalyse {
+        for song in playlist.songs.iter_mut() {
+            song.loudness = None;
+            song.peak = None;
+        }
+        playlist.album_loudness = None;
+        manifest_changed = true;
+    }
+
+
+    if playlist.loudness_mode == LoudnessMode::Off {
+        pl_update_println!("Loudness analysis is off for \"{}\".", playlist.title);
+    } else {
+        pl_update_println!("Analysing loudness...");
+        let (analysed, changed) = apply_loudness(&mut playlist, &options)?;
+        manifest_changed |= analysed > 0 || changed > 0;
+
+        pl_update_println!("Analysed {} songs, {} files changed.", analysed, changed);
+
+        if let Some((loudness, peak)) = playlist_loudness(&unique_songs(&playlist.songs)) {
+            pl_update_println!("Playlist loudness: {:.2} LUFS, peak {:.2} dBTP", loudness, peak);
+        }
+    }
+
+    if manifest_changed {
+        save_manifest(&playlist)?;
+    }
+
+
+    Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_stream_codec_and_bitrate() {
+        let stderr = "Input #0, mp3, from 'song.mp3':\n  Duration: 00:03:20.00, start: 0.025057, bitrate: 200 kb/s\n  Stream #0:0: Audio: mp3, 44100 Hz, stereo, fltp, 192 kb/s\n  Stream #0:1: Video: mjpeg (Baseline), yuvj420p, 1280x720, 90k tbr (attached pic)";
+
+        assert_eq!(parse_audio_encoding(stderr), (Some("mp3".to_string()), Some(192)));
+    }
+
+    #[test]
+    fn falls_back_to_file_bitrate() {
+        let stderr = "  Duration: 00:03:20.00, start: 0.007500, bitrate: 131 kb/s\n  Stream #0:0(eng): Audio: opus, 48000 Hz, stereo, fltp";
+
+        assert_eq!(parse_audio_encoding(stderr), (Some("opus".to_string()), Some(131)));
+    }
+
+    #[test]
+    fn reads_codec_with_profile() {
+        let stderr = "  Stream #0:0[0x1](und): Audio: aac (LC) (mp4a / 0x6134706D), 44100 Hz, stereo, fltp, 129 kb/s (default)";
+
+        assert_eq!(parse_audio_encoding(stderr), (Some("aac".to_string()), Some(129)));
+    }
+
+    #[test]
+    fn no_audio_stream() {
+        assert_eq!(parse_audio_encoding("song.mp3: Invalid data found when processing input"), (None, None));
+    }
+}
@@ -2,54 +2,54 @@ extern crate chrono;
 extern crate os_info;
 
 
+#[macro_use]
+mod log;
 mod push;
 mod adb;
 mod init;
 mod update;
 mod repair;
+mod verify;
+mod status;
+mod retag;
+mod loudness;
+mod cover;
+mod sources;
+mod filters;
+mod add;
+mod tags;
+mod retry;
+mod summary;
+mod history;
+mod snapshots;
+mod store;
 
 use std::io::ErrorKind;
 use core::str;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::{env, thread};
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader, Error, Read, Write};
-use std::process::{ChildStderr, ChildStdout, Command, Stdio};
-use std::sync::mpsc::{self, SyncSender};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
 use std::thread::{sleep, JoinHandle};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use chrono::Local;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use colored::Colorize;
+use cover::apply_cover_rules;
+use log::{console_enabled, init_logging, Level, LogFormat};
+use loudness::apply_loudness;
+use retag::apply_tag_rules;
+use snapshots::snapshot_manifest;
+use store::{archive_path, Store, StoreOptions};
+use summary::run_summary;
+use tags::Tags;
+use retry::{retry_delay, DownloadFailure};
 
 
-
-#[macro_export]
-macro_rules! pl_update_warn {
-    ($($x:expr),*) => {
-        eprintln!("{} [pl-update] {}", "WARNING:".yellow(),
-        format! (
-            $(
-                $x,
-            )*
-        )
-        )
-    };
-}
-
-#[macro_export]
-macro_rules! pl_update_error {
-    ($($x:expr),*) => {
-        eprintln!("{} [pl-update] {}", "ERROR:".red().bold(),
-        format! (
-            $(
-                $x,
-            )*
-        )
-        )
-    };
-}
-
 #[macro_export]
 macro_rules! pl_update_fatal_error {
     ($ekind:expr, $($e:expr),*) => {
@@ -86,27 +86,40 @@ macro_rules! pl_update_fatal_error {
 }
 
 
-#[macro_export]
-macro_rules! pl_update_ok_exit {
-    () => {
-        println!("\n\n[pl-update] Operation Completed.");
-        std::process::exit(0);
-    };
-}
 /// Playlist manager for yt-dlp
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None, author)]
 #[command(propagate_version = true)]
+#[command(after_help = "Exit codes:
+  0    Success
+  1    Fatal error
+  2    Invalid arguments, or a missing or invalid playlist directory or manifest
+  3    Partial failure, some songs or sources could not be downloaded
+  4    yt-dlp, ffmpeg or adb could not be run
+  5    Device error while pushing
+  130  Interrupted by Ctrl-C or a termination signal")]
 struct Args {
 
-    /// Print extra debugging information
+    /// Print extra debugging information, the same as --log-level debug
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
 
-    /// Suppress output
+    /// Suppress output, the same as --log-level warn
     #[arg(short, long, default_value_t = false)]
     quiet: bool,
 
+    /// How much is printed to the console, overrides --verbose and --quiet
+    #[arg(long)]
+    log_level: Option<Level>,
+
+    /// Also write the log to this file, appending to it. It records debugging output with timestamps whatever is printed to the console
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// The format of the log file
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+
 
     /// Args to pass to yt-dlp
     #[arg(long)]
@@ -118,7 +131,6 @@ struct Args {
     yt_dl_location: String,
 
 
-    
     /// The location of yt-dlp
     #[arg(long)] 
     ffmpeg_location: Option<String>,
@@ -134,10 +146,6 @@ struct Args {
             }
         }.get();
 
-        if cpu_core_count <= 0 {
-            panic!("System has no cpu cores.");
-        }
-
         if cpu_core_count >= 24 {
             cpu_core_count / 4
         } else if cpu_core_count >= 12 {
@@ -152,6 +160,39 @@ struct Args {
     threads: usize,
 
 
+    /// The number of times to retry a song whose download failed for a reason that may pass, such as a timeout or rate limit
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+
+
+    /// Seconds to wait before the first retry, doubled for each retry after it
+    #[arg(long, default_value_t = 10)]
+    retry_delay: u64,
+
+
+    /// How many manifest snapshots to keep for each playlist, the oldest are deleted first. 0 keeps them all
+    #[arg(long, default_value_t = 20)]
+    keep_snapshots: usize,
+
+
+    /// Also delete manifest snapshots older than this many days
+    #[arg(long)]
+    snapshot_max_age: Option<u64>,
+
+
+    #[command(flatten)]
+    rate_limits: RateLimits,
+
+
+    #[command(flatten)]
+    store_options: StoreOptions,
+
+
+    /// Write a JSON report of the run to this file: what was added, removed and failed, how long it took and the tool versions used
+    #[arg(long)]
+    summary_json: Option<String>,
+
+
     #[command(subcommand)]
     command: Commands
 
@@ -163,27 +204,385 @@ enum Commands {
     /// Creates a new directory for a playlist, fetches the playlist manifest
     /// and downloads all associated songs.
     Init { 
-        /// The url of the playlist to be downloaded
-        playlist_url: String 
+        /// The urls of the playlists to be downloaded. Songs from every url are merged into one playlist,
+        /// which is named after the first. If the first url is a video, every url is added to a manual playlist
+        /// that is only changed by the add command.
+        #[arg(required = true)]
+        playlist_urls: Vec<String>,
+
+        #[command(flatten)]
+        destination: init::Destination,
+
+        /// Prefix filenames with their zero padded position in the playlist.
+        #[arg(long, default_value_t = false)]
+        track_numbers: bool,
+
+        /// The audio format yt-dlp converts songs to (eg. mp3, m4a, opus, flac).
+        #[arg(long, default_value_t = DEFAULT_AUDIO_FORMAT.to_string())]
+        audio_format: String,
+
+        /// Analyse the loudness of songs after they are downloaded. Requires ffmpeg.
+        #[arg(long, value_enum, default_value_t = LoudnessMode::Off)]
+        loudness: LoudnessMode,
+
+        #[command(flatten)]
+        filters: Filters
     },
     /// Checks playlist for new or removed songs, and downloads/deletes files respectively. 
     /// Requires a valid manifest containing the playlist url.
     Update { 
         /// Optional. If provided the application will use this as the playlist directory.
-        playlist_name: Option<String> 
+        playlist_name: Option<String>,
+
+        /// Rewrite the title tag of songs that were renamed by the uploader. Requires ffmpeg.
+        #[arg(long, default_value_t = false)]
+        retag: bool,
+
+        /// Follow a change of the remote playlist's title by renaming the playlist, its directory,
+        /// its playlist file and the album tags. The directory is only renamed if it was named after the old title.
+        #[arg(long, default_value_t = false)]
+        follow_title: bool,
+
+        /// Start prefixing filenames with their zero padded position in the playlist, 
+        /// renaming files when the playlist order changes.
+        #[arg(long, default_value_t = false, conflicts_with = "no_track_numbers")]
+        track_numbers: bool,
+
+        /// Stop prefixing filenames with their position in the playlist.
+        #[arg(long, default_value_t = false)]
+        no_track_numbers: bool
     },
     /// Rebuilds the playlist manifest from the files in the directory. 
     /// Requires a playlist manifest containing at least the playlist url.
     Repair { 
         /// Optional. If provided the application will use this as the playlist directory.
-        playlist_name: Option<String> },
+        playlist_name: Option<String>,
+
+        /// Do not query the remote playlist for titles and positions.
+        #[arg(long, default_value_t = false)]
+        offline: bool
+    },
+    /// Cross-checks the manifest against the files in the playlist directory. Reports songs with no file,
+    /// files that are not in the manifest, and partial or empty downloads.
+    /// Unless an action is given for each, the user will be asked what to do.
+    Verify {
+        /// Optional. If provided the application will use this as the playlist directory.
+        playlist_name: Option<String>,
+
+        /// What to do with songs in the manifest that have no file.
+        #[arg(long, value_enum)]
+        missing: Option<MissingAction>,
+
+        /// What to do with files that are not in the manifest.
+        #[arg(long, value_enum)]
+        orphans: Option<OrphanAction>,
+
+        /// Delete partial downloads and empty files without asking.
+        #[arg(long, default_value_t = false)]
+        delete_partial: bool
+    },
+    /// Rewrites the tags of the songs in the playlist using the playlist's tag rules, without downloading them again.
+    /// Rules given here are saved to the manifest, and are applied after every download from then on.
+    Retag {
+        /// Optional. If provided the application will use this as the playlist directory.
+        playlist_name: Option<String>,
+
+        /// A regex with groups named "artist" and "title", used to split the artist out of the title.
+        /// "default" splits titles in the form "Artist - Title".
+        #[arg(long)]
+        split: Option<String>,
+
+        /// A regex removed from titles, can be given more than once. 
+        /// "default" removes suffixes like "(Official Video)" and "[Lyrics]".
+        #[arg(long)]
+        strip: Vec<String>,
+
+        /// Set the album to the playlist title.
+        #[arg(long, default_value_t = false)]
+        album: bool,
+
+        /// Set the track number to the position in the playlist.
+        #[arg(long, default_value_t = false)]
+        track_number: bool,
+
+        /// Remove the playlist's tag rules before adding any given here.
+        #[arg(long, default_value_t = false)]
+        clear: bool
+    },
+    /// Measures the loudness of the songs in the playlist with ffmpeg, and either writes ReplayGain tags or 
+    /// re-encodes the songs at the target loudness. The mode given here is saved to the manifest, and is applied 
+    /// after every download from then on. Songs are only analysed again if their file was downloaded again.
+    Loudness {
+        /// Optional. If provided the application will use this as the playlist directory.
+        playlist_name: Option<String>,
+
+        /// What to do with the measured loudness.
+        #[arg(long, value_enum)]
+        mode: Option<LoudnessMode>,
+
+        /// The loudness in LUFS songs are re-encoded at in normalize mode.
+        #[arg(long, allow_negative_numbers = true)]
+        target: Option<f64>,
+
+        /// Forget the stored measurements and analyse every song again.
+        #[arg(long, default_value_t = false)]
+        reanalyse: bool
+    },
+    /// Re-embeds the cover art of the songs in the playlist using the playlist's cover rules. Requires ffmpeg.
+    /// Rules given here are saved to the manifest, and are applied after every download from then on.
+    Cover {
+        /// Optional. If provided the application will use this as the playlist directory.
+        playlist_name: Option<String>,
+
+        /// Crop art to a square.
+        #[arg(long, default_value_t = false, conflicts_with = "no_square")]
+        square: bool,
+
+        /// Stop cropping art.
+        #[arg(long, default_value_t = false)]
+        no_square: bool,
+
+        /// Scale art down to at most this many pixels wide and high, 0 to stop scaling.
+        #[arg(long)]
+        size: Option<u32>,
+
+        /// An image to embed in every song instead of its thumbnail. It is copied into the playlist directory.
+        /// "none" stops using the playlist's image.
+        #[arg(long)]
+        image: Option<String>,
+
+        /// Only embed the image in songs that have no art of their own.
+        #[arg(long, default_value_t = false)]
+        fallback: bool,
+
+        /// Write the playlist's art to folder.jpg in the playlist directory.
+        #[arg(long, default_value_t = false, conflicts_with = "no_folder")]
+        folder: bool,
+
+        /// Stop writing folder.jpg, and remove it.
+        #[arg(long, default_value_t = false)]
+        no_folder: bool
+    },
+    /// Lists, adds or removes the sources merged into the playlist. Songs of a removed source are deleted
+    /// on the next update, unless another source also has them.
+    Sources {
+        /// Optional. If provided the application will use this as the playlist directory.
+        playlist_name: Option<String>,
+
+        /// The url of a playlist, channel or video to add, can be given more than once.
+        #[arg(long)]
+        add: Vec<String>,
+
+        /// The url of a source to remove, can be given more than once.
+        #[arg(long)]
+        remove: Vec<String>
+    },
+    /// Changes which songs of the playlist's sources are included. Filters are saved to the manifest and applied
+    /// on every update, so songs that stop passing them are removed. With no filters given, the current ones are shown.
+    Filter {
+        /// Optional. If provided the application will use this as the playlist directory.
+        playlist_name: Option<String>,
+
+        #[command(flatten)]
+        filters: Filters,
+
+        /// Remove the playlist's filters before adding any given here.
+        #[arg(long, default_value_t = false)]
+        clear: bool
+    },
+    /// Changes the rules for songs that are never downloaded, even though they are in the playlist's sources.
+    /// Songs that match a rule are removed on the next update, except songs added with add, which are only removed
+    /// when skipped. With no rules given, the current ones are shown.
+    Exclude {
+        /// Optional. If provided the application will use this as the playlist directory.
+        playlist_name: Option<String>,
+
+        /// Exclude songs whose title matches this regex, can be given more than once.
+        #[arg(long)]
+        title: Vec<String>,
+
+        /// Exclude songs longer than this many minutes, 0 to stop.
+        #[arg(long)]
+        longer_than: Option<u64>,
+
+        /// Exclude live streams, upcoming streams and recordings of streams.
+        #[arg(long, default_value_t = false, conflicts_with = "no_live")]
+        live: bool,
+
+        /// Stop excluding live streams.
+        #[arg(long, default_value_t = false)]
+        no_live: bool,
+
+        /// Remove every rule, including skipped songs, before adding any given here.
+        #[arg(long, default_value_t = false)]
+        clear: bool
+    },
+    /// Blocklists songs so they are never downloaded, even though they stay in the playlist's sources.
+    /// Skipped songs are removed on the next update.
+    Skip {
+        /// The ids of the songs to skip.
+        #[arg(required = true)]
+        ids: Vec<String>,
+
+        /// Optional. If provided the application will use this as the playlist directory.
+        #[arg(long)]
+        playlist: Option<String>,
+
+        /// Stop skipping the songs instead, they are downloaded on the next update.
+        #[arg(long, default_value_t = false)]
+        undo: bool
+    },
+    /// Downloads videos, or every video of a playlist, and adds them to the end of the playlist. 
+    /// Added songs are pinned, update does not remove them when they are not in the playlist's sources.
+    Add {
+        /// The urls to add.
+        #[arg(required = true)]
+        urls: Vec<String>,
+
+        /// Optional. If provided the application will use this as the playlist directory.
+        #[arg(long)]
+        playlist: Option<String>
+    },
+    /// Shows the playlist's settings, its songs and the state of their files.
+    Status {
+        /// Optional. If provided the application will use this as the playlist directory.
+        playlist_name: Option<String>,
+
+        /// Read the tags of every file and report files without tags or cover art.
+        #[arg(long, default_value_t = false)]
+        tags: bool
+    },
     /// Will send the files in the playlist to an android device connected on the ADB. 
     /// Requires the ADB to be installed. 
     /// If device id is not specified, and there is more than one device connected will prompt
     /// user to select device.
+    /// Only the playlist's songs, its playlist file and its folder art are sent, never its manifest or metadata.
     Push { 
         /// Optional. The device id to send to.
-        device_id: Option<String> 
+        device_id: Option<String>,
+
+        /// Also send files in the playlist directory matching this pattern, where * matches any characters
+        /// and ? matches one. Can be given more than once.
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// Do not send files matching this pattern, even songs. Can be given more than once.
+        #[arg(long)]
+        exclude: Vec<String>
+    },
+    /// Lists the runs recorded in the playlist's history: what each run added, removed and failed, and its errors.
+    History {
+        /// Optional. If provided the application will use this as the playlist directory.
+        playlist_name: Option<String>,
+
+        /// Only show this many of the most recent runs.
+        #[arg(long)]
+        limit: Option<usize>,
+
+        /// List the songs each run added, removed, renamed and failed.
+        #[arg(long, default_value_t = false)]
+        songs: bool
+    },
+    /// Lists the manifest snapshots kept for the playlist, newest first. A snapshot is taken each time the manifest changes.
+    Snapshots {
+        /// Optional. If provided the application will use this as the playlist directory.
+        playlist_name: Option<String>
+    },
+    /// Restores the manifest from a snapshot. The current manifest is kept as a snapshot, so a rollback can be undone.
+    Rollback {
+        /// The snapshot to restore, its number or filename from the snapshots command.
+        snapshot: String,
+
+        /// Optional. If provided the application will use this as the playlist directory.
+        #[arg(long)]
+        playlist: Option<String>,
+
+        /// Download the songs of the snapshot that were deleted since it was taken.
+        #[arg(long, default_value_t = false)]
+        download: bool
+    },
+    /// Shows what the shared store given by --store holds and which playlists use it.
+    Store {
+        /// Drop the store's records of playlists that no longer have a song, and delete the songs no playlist uses.
+        #[arg(long, default_value_t = false)]
+        gc: bool
+    },
+    /// Compares the playlist's manifest at two points in its history. Each point is a run number from the
+    /// history command, meaning the playlist as that run left it, "current", or the filename of a manifest snapshot.
+    Diff {
+        /// The earlier point to compare.
+        from: String,
+
+        /// The later point to compare.
+        #[arg(default_value = "current")]
+        to: String,
+
+        /// Optional. If provided the application will use this as the playlist directory.
+        #[arg(long)]
+        playlist: Option<String>
+    }
+}
+
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum MissingAction {
+    /// Only report the missing songs
+    Ignore,
+    /// Download the missing songs again
+    Download
+}
+
+impl MissingAction {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            MissingAction::Ignore => "ignore",
+            MissingAction::Download => "download"
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OrphanAction {
+    /// Only report the files
+    Ignore,
+    /// Add yt-dl files to the manifest
+    Adopt,
+    /// Move the files to a quarantine directory
+    Quarantine,
+    /// Delete the files
+    Delete
+}
+
+impl OrphanAction {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            OrphanAction::Ignore => "ignore",
+            OrphanAction::Adopt => "adopt",
+            OrphanAction::Quarantine => "quarantine",
+            OrphanAction::Delete => "delete"
+        }
+    }
+}
+
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+enum LoudnessMode {
+    /// Do not analyse songs
+    #[default]
+    Off,
+    /// Write ReplayGain track and album (playlist) gain tags
+    Replaygain,
+    /// Re-encode songs at the target loudness
+    Normalize
+}
+
+impl LoudnessMode {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            LoudnessMode::Off => "off",
+            LoudnessMode::Replaygain => "replaygain",
+            LoudnessMode::Normalize => "normalize"
+        }
     }
 }
 
@@ -193,202 +592,1318 @@ struct Song {
     title: String,
     id: String,
     url: Option<String>,
+    position: usize,
+    /// The yt-dlp extractor the song came from. Manifests written before this was recorded only had youtube songs.
+    extractor: Option<String>,
+    artist: Option<String>,
+    /// The length of the song in seconds.
+    duration: Option<u64>,
+    /// The url of the playlist source the song came from. Songs with no source came from the first.
+    source: Option<String>,
+    /// Whether the song is or was a live stream, as yt-dlp reports it.
+    live_status: Option<String>,
+    /// Added with the add command, so kept when it is not in any source.
+    pinned: bool,
+    /// The integrated loudness of the song's file in LUFS, if it has been analysed.
+    loudness: Option<f64>,
+    /// The true peak of the song's file in dBTP.
+    peak: Option<f64>,
+    /// Why the last download of the song failed, if it did.
+    failed: Option<DownloadFailure>,
 }
 
 impl PartialEq for Song {
     fn eq(&self, other: &Self) -> bool {
-        self.title == other.title && self.id == other.id
+        //Titles are not compared as uploaders can change them, see Song::is_renamed.
+        //An unknown extractor matches any, so songs whose extractor was lost can still be matched to their source.
+        self.id == other.id && match (self.extractor_name(), other.extractor_name()) {
+            (Some(extractor), Some(other_extractor)) => extractor == other_extractor,
+            _ => true,
+        }
     }
 }
 
 impl Song {
-    fn new(title: String, id: String, url: Option<String>) -> Self {
-        Song {title, id, url}
+    fn new(title: String, id: String, url: Option<String>, position: usize, extractor: Option<String>) -> Self {
+        Song {title, id, url, position, extractor, artist: None, duration: None, source: None, live_status: None, pinned: false, loudness: None, peak: None, failed: None}
+    }
+
+    /// The song's extractor in lowercase, as yt-dlp's download archive has it, or None if it is not known.
+    fn extractor_name(&self) -> Option<String> {
+        match self.extractor.as_deref() {
+            None => Some("youtube".to_string()),
+            Some(UNKNOWN_EXTRACTOR) => None,
+            Some(extractor) => Some(extractor.to_lowercase()),
+        }
+    }
+
+    fn is_youtube(&self) -> bool {
+        self.extractor_name().is_some_and(|f| f == "youtube")
     }
 
-    fn into_filename(&self, file_ext: String) -> String {
-        format!("{} [{}].{}", self.title, self.id, file_ext)
+    fn to_filename(&self, file_ext: String) -> String {
+        format!("{} [{}].{}", sanitize_title(&self.title), self.id, file_ext)
     }
 
+    /// The url of the song, or the canonical watch url for youtube songs if the manifest has none.
+    /// Other sites cannot be reached from the id alone.
     fn url(&self) -> Option<String> {
-        self.url.clone()
+        match &self.url {
+            Some(url) => Some(url.clone()),
+            None if self.is_youtube() => Some(format!("https://www.youtube.com/watch?v={}", self.id)),
+            None => None,
+        }
+    }
+
+    /// Returns true if other is the same video under a different title.
+    fn is_renamed(&self, other: &Self) -> bool {
+        self == other && self.title != other.title
+    }
+
+    fn manifest_entry(&self) -> String {
+        let mut entry = format!("title={}{SEP_CHAR}id={}{SEP_CHAR}url={}{SEP_CHAR}position={}", self.title, self.id, self.url.clone().unwrap_or_default(), self.position);
+
+        if let Some(extractor) = &self.extractor {
+            entry.push_str(&format!("{SEP_CHAR}extractor={}", extractor));
+        }
+
+        if let Some(artist) = &self.artist {
+            entry.push_str(&format!("{SEP_CHAR}artist={}", artist));
+        }
+
+        if let Some(duration) = self.duration {
+            entry.push_str(&format!("{SEP_CHAR}duration={}", duration));
+        }
+
+        if let Some(live_status) = &self.live_status {
+            entry.push_str(&format!("{SEP_CHAR}live_status={}", live_status));
+        }
+
+        if let Some(source) = &self.source {
+            entry.push_str(&format!("{SEP_CHAR}source={}", source));
+        }
+
+        if self.pinned {
+            entry.push_str(&format!("{SEP_CHAR}pinned=true"));
+        }
+
+        if let (Some(loudness), Some(peak)) = (self.loudness, self.peak) {
+            entry.push_str(&format!("{SEP_CHAR}loudness={:.2}{SEP_CHAR}peak={:.2}", loudness, peak));
+        }
+
+        if let Some(failed) = &self.failed {
+            entry.push_str(&format!("{SEP_CHAR}failed={}{SEP_CHAR}failed_reason={}", if failed.permanent { "permanent" } else { "transient" }, failed.reason));
+        }
+
+        entry.push('\n');
+        entry
     }
 }
 
 
-const SEP_CHAR: char = '\x06'; 
+/// Rules used to clean up the tags yt-dlp writes, stored per playlist.
+#[derive(Debug, Clone, Default)]
+struct TagRules {
+    /// A regex with "artist" and "title" groups, used to split the artist out of the title.
+    split: Option<String>,
+    /// Regexes removed from titles.
+    strip: Vec<String>,
+    /// Set the album to the playlist title.
+    album: bool,
+    /// Set the track number to the position in the playlist.
+    track_number: bool,
+}
 
-fn main() -> std::io::Result<()> {
-    let time: chrono::DateTime<Local> = SystemTime::now().into();
-    let info = os_info::get();
+impl TagRules {
+    const DEFAULT_SPLIT: &'static str = r"^(?P<artist>.+?)\s+[-–—]\s+(?P<title>.+)$";
+    const DEFAULT_STRIP: &'static str = r"(?i)\s*[(\[](official\s+)?(music\s+|lyric\s+|hd\s+)?(video|audio|visuali[sz]er|lyrics?|hd|hq|4k)[)\]]";
 
- 
+    fn is_empty(&self) -> bool {
+        self.split.is_none() && self.strip.is_empty() && !self.album && !self.track_number
+    }
 
-    let ver = env!("CARGO_PKG_VERSION");
+    fn header_fields(&self) -> String {
+        let mut fields = String::new();
 
-    #[cfg(debug_assertions)] 
-    env::set_var("RUST_BACKTRACE", "1");
+        if let Some(split) = &self.split {
+            fields.push_str(&format!("{SEP_CHAR}tag_split={}", split));
+        }
 
+        for strip in &self.strip {
+            fields.push_str(&format!("{SEP_CHAR}tag_strip={}", strip));
+        }
 
-    println!("pl-update version {}", ver);
+        if self.album {
+            fields.push_str(&format!("{SEP_CHAR}tag_album=true"));
+        }
 
-    #[cfg(debug_assertions)] 
-    println!("Debugging build");
+        if self.track_number {
+            fields.push_str(&format!("{SEP_CHAR}tag_track=true"));
+        }
 
-    
-    if info.architecture().is_some() {
-        println!("Running on {} {} for {}", info.os_type(), info.version(), info.architecture().unwrap());
-    } else {
-        println!("Running on {} version {}", info.os_type(), info.version());
+        fields
     }
+}
 
-    println!("Started at system time {}\n", time.format("%+"));
 
-    
-    let args = Args::parse();
-    
+/// How the cover art yt-dlp embeds is changed, stored per playlist.
+#[derive(Debug, Clone, Default)]
+struct CoverRules {
+    /// Crop art to a square, as youtube thumbnails are 16:9.
+    square: bool,
+    /// The largest width and height of art in pixels.
+    size: Option<u32>,
+    /// An image in the playlist directory embedded in every song instead of its thumbnail.
+    image: Option<String>,
+    /// Only embed the image in songs that have no art of their own.
+    fallback: bool,
+    /// Write the playlist's art to folder.jpg.
+    folder: bool,
+}
 
-    macro_rules! pl_update_println {
-        ($($x:expr),*) => {
-            if !args.quiet {
-                println!("[pl-update] {}",
-                format! (
-                        $(
-                            $x,
-                        )*
-                    )
-                )
-            }
-        };
+impl CoverRules {
+    fn is_empty(&self) -> bool {
+        !self.square && self.size.is_none() && self.image.is_none() && !self.folder
     }
-    
 
-    macro_rules! pl_update_vprintln {
-        ($($x:expr),*) => {
-            if args.verbose {
-                println!("{} [pl-update] {}", "DEBUG:".blue(),
-                format! (
-                    $(
-                        $x,
-                    )*
-                )
-
-                )
-            }
-        };
+    fn header_fields(&self) -> String {
+        let mut fields = String::new();
+
+        if self.square {
+            fields.push_str(&format!("{SEP_CHAR}cover_square=true"));
+        }
+
+        if let Some(size) = self.size {
+            fields.push_str(&format!("{SEP_CHAR}cover_size={}", size));
+        }
+
+        if let Some(image) = &self.image {
+            fields.push_str(&format!("{SEP_CHAR}cover_image={}", image));
+        }
+
+        if self.fallback {
+            fields.push_str(&format!("{SEP_CHAR}cover_fallback=true"));
+        }
+
+        if self.folder {
+            fields.push_str(&format!("{SEP_CHAR}cover_folder=true"));
+        }
+
+        fields
     }
+}
 
-    
-    pl_update_vprintln!("Args: {:?}", args);
 
-    let command = args.command.clone();
- 
+/// Limits on how hard yt-dlp may hit the sites it fetches from, shared between every yt-dlp process that runs at once.
+#[derive(clap::Args, Debug, Clone, Default)]
+struct RateLimits {
+    /// The most downloads to run at once, lowering --threads if it is higher
+    #[arg(long)]
+    max_concurrent: Option<usize>,
 
-    let ret = match command {
-        //Commands::Get => todo!(),
-        Commands::Init { playlist_url } => init::pl_init(args, playlist_url),
-        Commands::Push { device_id } => push::pl_push(args, device_id),
-        Commands::Repair { playlist_name } => repair::pl_repair(args, playlist_name),
-        Commands::Update { playlist_name } => update::pl_update(args, playlist_name)
+    /// The most requests per minute to make across every thread, when listing playlists and downloading
+    #[arg(long)]
+    requests_per_minute: Option<u32>,
+
+    /// The most bandwidth to use across every thread, in bytes per second. Accepts K, M and G suffixes, like 500K
+    #[arg(long, value_parser = parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// Seconds each thread waits between songs, and between listing each source
+    #[arg(long)]
+    sleep_interval: Option<f64>,
+}
+
+impl RateLimits {
+    /// The seconds one of workers threads waits between requests to stay under the requests per minute.
+    fn request_interval(&self, workers: usize) -> Option<f64> {
+        self.requests_per_minute.filter(|f| *f > 0).map(|rpm| 60.0 * workers as f64 / rpm as f64)
+    }
 
+    /// The yt-dlp arguments that hold one of workers threads to its share of the limits.
+    fn ytdl_args(&self, workers: usize) -> Vec<String> {
+        let mut args = Vec::new();
+        let request_interval = self.request_interval(workers);
+
+        if let Some(request_interval) = request_interval {
+            args.push("--sleep-requests".to_owned());
+            args.push(format!("{:.2}", request_interval));
+        }
+
+        //Each song takes at least one request, so songs are spaced out at least as far as requests.
+        let sleep_interval = match (self.sleep_interval, request_interval) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+
+        if let Some(sleep_interval) = sleep_interval {
+            args.push("--sleep-interval".to_owned());
+            args.push(format!("{:.2}", sleep_interval));
+        }
+
+        if let Some(limit_rate) = self.limit_rate {
+            args.push("--limit-rate".to_owned());
+            args.push((limit_rate / workers.max(1) as u64).max(1).to_string());
+        }
+
+        args
+    }
+
+    /// The wait between starting yt-dlp processes one after another.
+    fn pause(&self, minimum: Duration) -> Duration {
+        let pause = self.sleep_interval.unwrap_or(0.0).max(self.request_interval(1).unwrap_or(0.0));
+        minimum.max(Duration::from_secs_f64(pause))
+    }
+}
+
+/// Parses a rate like 500K or 1.5M into bytes per second.
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    let rate = rate.trim();
+
+    let (number, multiplier) = match rate.chars().last().map(|f| f.to_ascii_uppercase()) {
+        Some('K') => (&rate[..rate.len() - 1], 1024.0),
+        Some('M') => (&rate[..rate.len() - 1], 1024.0 * 1024.0),
+        Some('G') => (&rate[..rate.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (rate, 1.0),
     };
 
-    let new_time: chrono::DateTime<Local> = SystemTime::now().into();
+    match number.parse::<f64>() {
+        Ok(val) if val > 0.0 => Ok((val * multiplier) as u64),
+        _ => Err(format!("\"{}\" is not a rate, use bytes per second like 500K or 1.5M", rate)),
+    }
+}
 
-    let delta = new_time - time;
 
+/// Limits on which songs of the playlist's sources are included, stored per playlist.
+#[derive(clap::Args, Debug, Clone, Default, PartialEq)]
+struct Filters {
+    /// Only include songs uploaded on or after this date, as YYYYMMDD or relative like "now-1month".
+    /// Every song has to be fully extracted to find its upload date, which makes updates slower.
+    #[arg(long)]
+    date_after: Option<String>,
+
+    /// Only include songs at least this many seconds long.
+    #[arg(long)]
+    min_duration: Option<u64>,
+
+    /// Only include songs at most this many seconds long.
+    #[arg(long)]
+    max_duration: Option<u64>,
+
+    /// Only include songs whose title matches this regex.
+    #[arg(long)]
+    title_match: Option<String>,
+
+    /// Only include this many songs from each source, starting from the top (the newest for channels).
+    #[arg(long)]
+    max_items: Option<usize>,
+
+    /// A yt-dlp --match-filter songs must also pass.
+    #[arg(long)]
+    match_filter: Option<String>,
+}
+
+impl Filters {
+    fn is_empty(&self) -> bool {
+        *self == Filters::default()
+    }
+
+    /// Replaces each filter that is set in other.
+    fn merge(&mut self, other: Filters) {
+        self.date_after = other.date_after.or(self.date_after.take());
+        self.min_duration = other.min_duration.or(self.min_duration);
+        self.max_duration = other.max_duration.or(self.max_duration);
+        self.title_match = other.title_match.or(self.title_match.take());
+        self.max_items = other.max_items.or(self.max_items);
+        self.match_filter = other.match_filter.or(self.match_filter.take());
+    }
+
+    /// The filters as a single yt-dlp --match-filter, as filters given separately only have to match one.
+    fn ytdl_match_filter(&self) -> Option<String> {
+        let mut conditions = Vec::new();
+
+        //"?" lets songs with an unknown duration through.
+        if let Some(min_duration) = self.min_duration {
+            conditions.push(format!("duration >=? {}", min_duration));
+        }
+
+        if let Some(max_duration) = self.max_duration {
+            conditions.push(format!("duration <=? {}", max_duration));
+        }
+
+        if let Some(title_match) = &self.title_match {
+            conditions.push(format!("title ~= '{}'", title_match.replace('\'', "\\'").replace('&', "\\&")));
+        }
+
+        if let Some(match_filter) = &self.match_filter {
+            conditions.push(match_filter.clone());
+        }
+
+        if conditions.is_empty() {
+            None
+        } else {
+            Some(conditions.join(" & "))
+        }
+    }
+
+    fn header_fields(&self) -> String {
+        let mut fields = String::new();
+
+        if let Some(date_after) = &self.date_after {
+            fields.push_str(&format!("{SEP_CHAR}filter_date_after={}", date_after));
+        }
+
+        if let Some(min_duration) = self.min_duration {
+            fields.push_str(&format!("{SEP_CHAR}filter_min_duration={}", min_duration));
+        }
+
+        if let Some(max_duration) = self.max_duration {
+            fields.push_str(&format!("{SEP_CHAR}filter_max_duration={}", max_duration));
+        }
+
+        if let Some(title_match) = &self.title_match {
+            fields.push_str(&format!("{SEP_CHAR}filter_title={}", title_match));
+        }
+
+        if let Some(max_items) = self.max_items {
+            fields.push_str(&format!("{SEP_CHAR}filter_max_items={}", max_items));
+        }
+
+        if let Some(match_filter) = &self.match_filter {
+            fields.push_str(&format!("{SEP_CHAR}filter_match={}", match_filter));
+        }
+
+        fields
+    }
+}
+
+
+/// Songs that are never downloaded even though they are in the playlist's sources, stored per playlist.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Excludes {
+    /// The ids of skipped songs.
+    ids: Vec<String>,
+    /// Regexes matched against song titles.
+    titles: Vec<String>,
+    /// The longest a song can be, in minutes.
+    longer_than: Option<u64>,
+    /// Exclude anything yt-dlp does not report as "not_live".
+    live: bool,
+}
+
+impl Excludes {
+    fn is_empty(&self) -> bool {
+        *self == Excludes::default()
+    }
+
+    fn header_fields(&self) -> String {
+        let mut fields = String::new();
+
+        for id in &self.ids {
+            fields.push_str(&format!("{SEP_CHAR}skip={}", id));
+        }
+
+        for title in &self.titles {
+            fields.push_str(&format!("{SEP_CHAR}exclude_title={}", title));
+        }
+
+        if let Some(longer_than) = self.longer_than {
+            fields.push_str(&format!("{SEP_CHAR}exclude_longer={}", longer_than));
+        }
+
+        if self.live {
+            fields.push_str(&format!("{SEP_CHAR}exclude_live=true"));
+        }
+
+        fields
+    }
+}
+
+
+#[derive(Debug, Clone)]
+struct Manifest {
+    title: String,
+    /// The title of the first source when it differs from title, such as when init was given a name.
+    remote_title: Option<String>,
+    /// The remote titles the playlist had before, with the date each was replaced.
+    title_history: Vec<(String, String)>,
+    /// The urls of the playlists, channels and videos merged into the playlist.
+    sources: Vec<String>,
+    track_numbers: bool,
+    /// The --audio-format passed to yt-dlp.
+    audio_format: String,
+    filters: Filters,
+    excludes: Excludes,
+    tag_rules: TagRules,
+    cover_rules: CoverRules,
+    loudness_mode: LoudnessMode,
+    /// The loudness in LUFS songs are re-encoded at in normalize mode.
+    loudness_target: f64,
+    /// The loudness and true peak of the whole playlist, as last written to the ReplayGain album tags.
+    album_loudness: Option<(f64, f64)>,
+    songs: Vec<Song>,
+}
+
+impl Manifest {
+    fn new(title: String, url: String) -> Self {
+        Manifest {title, remote_title: None, title_history: Vec::new(), sources: vec![url], track_numbers: false, audio_format: DEFAULT_AUDIO_FORMAT.to_string(), filters: Filters::default(), excludes: Excludes::default(), tag_rules: TagRules::default(), cover_rules: CoverRules::default(),
+            loudness_mode: LoudnessMode::Off, loudness_target: DEFAULT_LOUDNESS_TARGET, album_loudness: None, songs: Vec::new()}
+    }
+
+    /// The extension yt-dlp gives files in the playlist's audio format.
+    fn file_ext(&self) -> &str {
+        audio_file_ext(&self.audio_format)
+    }
+
+    fn header(&self) -> String {
+        let mut header = format!("playlist_title={}{SEP_CHAR}url={}", self.title, self.sources.first().map_or("", |f| f.as_str()));
+
+        for source in self.sources.iter().skip(1) {
+            header.push_str(&format!("{SEP_CHAR}source={}", source));
+        }
+
+        if let Some(remote_title) = self.remote_title.as_ref().filter(|f| **f != self.title) {
+            header.push_str(&format!("{SEP_CHAR}remote_title={}", remote_title));
+        }
+
+        for (date, title) in &self.title_history {
+            header.push_str(&format!("{SEP_CHAR}title_history={} {}", date, title));
+        }
+
+        if self.track_numbers {
+            header.push_str(&format!("{SEP_CHAR}track_numbers=true"));
+        }
+
+        if self.audio_format != DEFAULT_AUDIO_FORMAT {
+            header.push_str(&format!("{SEP_CHAR}audio_format={}", self.audio_format));
+        }
+
+        header.push_str(&self.filters.header_fields());
+        header.push_str(&self.excludes.header_fields());
+        header.push_str(&self.tag_rules.header_fields());
+        header.push_str(&self.cover_rules.header_fields());
+
+        if self.loudness_mode != LoudnessMode::Off {
+            header.push_str(&format!("{SEP_CHAR}loudness={}", self.loudness_mode.as_str()));
+        }
+
+        if self.loudness_target != DEFAULT_LOUDNESS_TARGET {
+            header.push_str(&format!("{SEP_CHAR}loudness_target={}", self.loudness_target));
+        }
+
+        if let Some((loudness, peak)) = self.album_loudness {
+            header.push_str(&format!("{SEP_CHAR}album_loudness={:.2}{SEP_CHAR}album_peak={:.2}", loudness, peak));
+        }
+
+        header.push('\n');
+        header
+    }
+
+    fn write(&self, mut manifest: impl Write) -> Result<(), Error> {
+        manifest.write_all(self.header().as_bytes())?;
+
+        for song in &self.songs {
+            manifest.write_all(song.manifest_entry().as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Records which of songs could not be downloaded, and clears the failures of the rest.
+    fn record_downloads(&mut self, songs: &[Song], failures: &[(Song, DownloadFailure)]) {
+        for song in self.songs.iter_mut().filter(|f| songs.contains(f)) {
+            song.failed = failures.iter().find(|(f, _)| f == song).map(|(_, failure)| failure.clone());
+        }
+    }
+
+    /// The url of the source song came from.
+    fn song_source<'a>(&'a self, song: &'a Song) -> &'a str {
+        song.source.as_deref().or(self.sources.first().map(|f| f.as_str())).unwrap_or_default()
+    }
+
+    /// The name of the file holding song. If track numbers are enabled the name is prefixed with the 
+    /// zero padded position of the song's first occurrence in the playlist.
+    fn song_filename(&self, song: &Song) -> String {
+        let filename = song.to_filename(self.file_ext().to_owned());
+
+        if !self.track_numbers {
+            return filename;
+        }
+
+        let position = self.songs.iter().find(|f| *f == song).unwrap_or(song).position;
+        let width = self.songs.len().to_string().len().max(2);
+
+        format!("{:0width$} - {}", position, filename)
+    }
+}
+
+
+const SEP_CHAR: char = '\x06'; 
+
+const DEFAULT_AUDIO_FORMAT: &str = "mp3";
+
+/// Recorded as the extractor of songs whose extractor could not be found, as no extractor means youtube.
+const UNKNOWN_EXTRACTOR: &str = "Unknown";
+
+const DEFAULT_LOUDNESS_TARGET: f64 = -18.0;
+
+/// Exit codes, as listed in --help.
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_FATAL: i32 = 1;
+const EXIT_CONFIG_ERROR: i32 = 2;
+const EXIT_PARTIAL_FAILURE: i32 = 3;
+const EXIT_TOOL_MISSING: i32 = 4;
+const EXIT_DEVICE_ERROR: i32 = 5;
+/// The exit code used when pl-update is stopped by Ctrl-C or a termination signal, as shells do for SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// How long a child process is given to exit after being asked to stop, before it is killed.
+const CHILD_EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Set once pl-update has been asked to stop. Work that is under way finishes or is stopped,
+/// nothing new is started, and what was completed is saved.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Names that cannot be used for a file or directory on Windows, whatever their extension.
+const RESERVED_NAMES: [&str; 22] = ["CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"];
+
+/// Longest directory name in bytes, leaving room under the usual 255 byte limit.
+const MAX_DIR_NAME_LEN: usize = 200;
+
+/// Holds what pl-update keeps about a playlist other than its manifest, such as snapshots and history.
+const METADATA_DIR: &str = ".pl-update";
+
+
+/// The message of an error that decides the exit code, so scripts can tell failures apart.
+#[derive(Debug)]
+struct ExitCodeError {
+    code: i32,
+    message: String,
+}
+
+impl fmt::Display for ExitCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ExitCodeError {}
+
+/// Gives e the exit code pl-update uses if it ends the run. An error that already has a code keeps it.
+fn with_exit_code(e: Error, code: i32) -> Error {
+    if e.get_ref().is_some_and(|f| f.is::<ExitCodeError>()) {
+        return e;
+    }
+
+    Error::new(e.kind(), ExitCodeError { code, message: e.to_string() })
+}
+
+/// The exit code for a run that ended with e.
+fn exit_code(e: &Error) -> i32 {
+    if let Some(e) = e.get_ref().and_then(|f| f.downcast_ref::<ExitCodeError>()) {
+        return e.code;
+    }
+
+    match e.kind() {
+        ErrorKind::InvalidInput | ErrorKind::InvalidData | ErrorKind::NotFound | ErrorKind::AlreadyExists => EXIT_CONFIG_ERROR,
+        _ => EXIT_FATAL,
+    }
+}
+
+/// The extension yt-dlp gives files in audio_format.
+fn audio_file_ext(audio_format: &str) -> &str {
+    match audio_format {
+        "vorbis" => "ogg",
+        format => format,
+    }
+}
+
+/// Returns true if pl-update has been asked to stop.
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Sleeps for duration, waking early if pl-update is asked to stop.
+fn sleep_unless_interrupted(duration: Duration) {
+    let start = Instant::now();
+
+    while !interrupted() && start.elapsed() < duration {
+        sleep((duration - start.elapsed()).min(Duration::from_millis(100)));
+    }
+}
+
+/// Asks a child process to stop the way Ctrl-C would, so yt-dlp and ffmpeg can clean up after themselves.
+fn terminate_child(child: &mut Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+
+    #[cfg(not(unix))]
+    let _ = child.kill();
+}
+
+/// Waits for a child process to exit, killing it if it is still running after timeout.
+fn wait_child(child: &mut Child, timeout: Duration) -> Result<ExitStatus, Error> {
+    let start = Instant::now();
+
+    while start.elapsed() < timeout {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        sleep(Duration::from_millis(100));
+    }
+
+    let _ = child.kill(); //It may have exited since it was last checked
+    child.wait()
+}
+
+/// Leftovers from an interrupted yt-dlp download.
+fn is_partial_file(file_name: &str) -> bool {
+    file_name.ends_with(".part") || file_name.ends_with(".ytdl") || file_name.contains(".part-Frag") || file_name.contains(".temp.")
+}
+
+/// Deletes the leftovers of interrupted downloads in the current directory. Returns the number of files deleted.
+fn remove_partial_files() -> Result<usize, Error> {
+    let mut removed = 0;
+
+    for file_entry in fs::read_dir(".")? {
+        let file_entry = file_entry?;
+
+        if file_entry.file_name().to_str().is_some_and(is_partial_file) {
+            fs::remove_file(file_entry.path())?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Replaces the characters in a title the same way yt-dlp does when it uses the title in a file name.
+fn sanitize_title(title: &str) -> String {
+    title.chars().filter_map(|c| match c {
+        '/' => Some('\u{29F8}'),
+        '\\' => Some('\u{29F9}'),
+        '"' | '*' | ':' | '<' | '>' | '?' | '|' => char::from_u32(c as u32 + 0xfee0), //Full width counterparts
+        '\n' => Some(' '),
+        c if c.is_ascii_control() => None,
+        c => Some(c),
+    }).collect()
+}
+
+/// Splits a file name in the form "title [id].ext" into its title and id.
+/// Ids can be any length, as they differ between the sites yt-dlp supports.
+/// Returns None if the file name does not end with an id and file_ext.
+fn parse_song_filename(file_name: &str, file_ext: &str) -> Option<(String, String)> {
+    let stem = file_name.strip_suffix(file_ext)?.strip_suffix('.')?.strip_suffix(']')?;
+    let (song_name, id) = stem.rsplit_once(" [")?;
+
+    if id.is_empty() || id.contains(|c: char| c.is_whitespace() || c == '[' || c == ']') {
+        return None;
+    }
+
+    Some((song_name.to_string(), id.to_string()))
+}
+
+/// The extractor to record for a song found without one, which is UNKNOWN_EXTRACTOR if it cannot be guessed.
+fn recorded_extractor(id: &str, url: Option<&str>) -> String {
+    guess_extractor(id, url).unwrap_or(UNKNOWN_EXTRACTOR.to_string())
+}
+
+/// Guesses the extractor of a song from its url, or its id if the url is not known.
+fn guess_extractor(id: &str, url: Option<&str>) -> Option<String> {
+    const YOUTUBE_ID_LEN: usize = 11;
+
+    if let Some(url) = url {
+        let host = url.split("://").nth(1)?.split('/').next()?;
+        let domain = host.rsplit('.').nth(1)?; //The name before the top level domain
+
+        return match domain {
+            "youtube" | "youtu" => Some("Youtube".to_string()),
+            domain => {
+                let mut chars = domain.chars();
+                chars.next().map(|first| first.to_uppercase().chain(chars).collect())
+            }
+        };
+    }
+
+    if id.len() == YOUTUBE_ID_LEN && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        Some("Youtube".to_string())
+    } else {
+        None
+    }
+}
+
+/// Splits the "<position> - " prefix from the name of a track numbered file.
+fn split_track_number(song_name: &str) -> (Option<usize>, &str) {
+    match song_name.split_once(" - ") {
+        Some((number, rest)) if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) => (number.parse().ok(), rest),
+        _ => (None, song_name),
+    }
+}
+
+/// Returns the songs with every repeated id after its first occurrence removed, keeping playlist order.
+fn unique_songs(songs: &[Song]) -> Vec<Song> {
+    let mut unique: Vec<Song> = Vec::with_capacity(songs.len());
+
+    for song in songs {
+        if !unique.contains(song) {
+            unique.push(song.clone());
+        }
+    }
+
+    unique
+}
+
+/// Returns each song that appears more than once in the playlist, along with the number of times it appears.
+fn duplicate_songs(songs: &[Song]) -> Vec<(Song, usize)> {
+    let mut duplicates: Vec<(Song, usize)> = Vec::new();
+
+    for song in unique_songs(songs) {
+        let count = songs.iter().filter(|f| **f == song).count();
+        if count > 1 {
+            duplicates.push((song, count));
+        }
+    }
+
+    duplicates
+}
+
+fn report_duplicates(songs: &[Song]) {
+    for (song, count) in duplicate_songs(songs) {
+        pl_update_warn!("\"{}\" [{}] appears {} times in the playlist, it will only be downloaded once.", song.title, song.id, count);
+    }
+}
+
+/// Returns the songs present in both playlists whose order relative to the other songs changed.
+/// Songs are only counted as moved if they are not part of the longest run of songs that kept their order,
+/// so a song added or removed near the top of the playlist does not count as moving every song after it.
+fn moved_songs(old_songs: &[Song], new_songs: &[Song]) -> Vec<Song> {
+    let common: Vec<(&Song, usize)> = new_songs.iter().filter_map(|new_song| 
+        old_songs.iter().position(|old_song| old_song == new_song).map(|old_index| (new_song, old_index))
+    ).collect();
+
+    //Longest increasing subsequence of the old indices, in new playlist order.
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = vec![None; common.len()];
+
+    for (i, (_, old_index)) in common.iter().enumerate() {
+        let slot = tails.partition_point(|&t| common[t].1 < *old_index);
+
+        if slot > 0 {
+            previous[i] = Some(tails[slot - 1]);
+        }
+
+        if slot == tails.len() {
+            tails.push(i);
+        } else {
+            tails[slot] = i;
+        }
+    }
+
+    let mut in_order = vec![false; common.len()];
+    let mut next = tails.last().copied();
+
+    while let Some(i) = next {
+        in_order[i] = true;
+        next = previous[i];
+    }
+
+    common.into_iter().zip(in_order).filter(|(_, kept)| !kept).map(|((song, _), _)| song.clone()).collect()
+}
+
+/// The urls to download songs from. Songs with no known url are left out with a warning.
+fn song_urls(songs: &[Song]) -> Vec<String> {
+    songs.iter().filter_map(|song| {
+        let url = song.url();
+        if url.is_none() {
+            pl_update_warn!("\"{}\" [{}] has no url in the manifest and cannot be downloaded.", song.title, song.id);
+        }
+        url
+    }).collect()
+}
+
+/// Fills in a song rebuilt from its file name with the tags embedded in its file.
+/// Returns true if the original title was recovered from the tags.
+fn apply_tags(song: &mut Song, tags: &Tags) -> bool {
+    let mut recovered_title = false;
+
+    //The file name has the title with characters that are not allowed in file names replaced,
+    //the embedded title is the original.
+    if let Some(tag_title) = &tags.title {
+        if *tag_title != song.title && sanitize_title(tag_title) == song.title {
+            song.title = tag_title.clone();
+            recovered_title = true;
+        }
+    }
+
+    if song.url.is_none() {
+        song.url = tags.source_url();
+    }
+
+    if song.artist.is_none() {
+        song.artist = tags.artist.clone();
+    }
+
+    if song.duration.is_none() {
+        song.duration = tags.duration.map(|f| f.round() as u64);
+    }
+
+    recovered_title
+}
+
+/// Renames freshly downloaded songs to the name they have in the playlist.
+fn place_downloaded_songs(playlist: &Manifest, songs: &[Song]) -> Result<(), Error> {
+    for song in songs {
+        let filename = song.to_filename(playlist.file_ext().to_owned());
+        let playlist_filename = playlist.song_filename(song);
+
+        if filename != playlist_filename && fs::metadata(&filename).is_ok() {
+            fs::rename(&filename, playlist_filename)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the playlist's cover art, tag and loudness rules to the files of songs after they were downloaded.
+/// Failures are only warned about, as they leave the files as they were and the rules are applied again later.
+fn apply_file_rules(playlist: &mut Manifest, songs: &[Song], options: &Args) {
+    //Once stopped, only what is needed to save the downloaded songs is done.
+    if interrupted() {
+        return;
+    }
+
+    match apply_cover_rules(playlist, songs, options) {
+        Ok(0) => {},
+        Ok(recovered_art) => pl_update_println!("Changed the cover art of {} files.", recovered_art),
+        Err(e) => pl_update_warn!("Could not apply the cover art rules: {}", e),
+    }
+
+    match apply_tag_rules(playlist, options) {
+        Ok(0) => {},
+        Ok(retagged) => pl_update_println!("Retagged {} files.", retagged),
+        Err(e) => pl_update_warn!("Could not apply the tag rules: {}", e),
+    }
+
+    if playlist.loudness_mode != LoudnessMode::Off {
+        match apply_loudness(playlist, options) {
+            Ok((analysed, changed)) => pl_update_println!("Analysed the loudness of {} songs, {} files changed.", analysed, changed),
+            Err(e) => pl_update_warn!("Could not apply the loudness mode: {}", e),
+        }
+    }
+}
+
+/// Records which songs were downloaded, gives their files their playlist names and writes the manifest and m3u8.
+/// The manifest is written before the file rules are applied, so the downloads are kept whatever the rules do.
+fn save_downloads(playlist: &mut Manifest, songs: &[Song], failures: &[(Song, DownloadFailure)], options: &Args) -> Result<(), Error> {
+    playlist.record_downloads(songs, failures);
+    place_downloaded_songs(playlist, songs)?;
+
+    //Measurements of old files do not apply to the new downloads.
+    for song in playlist.songs.iter_mut().filter(|f| songs.contains(f)) {
+        song.loudness = None;
+        song.peak = None;
+    }
+
+    playlist.write(File::create("playlist.manifest")?)?;
+
+    apply_file_rules(playlist, songs, options);
+
+    //The loudness measurements are stored in the manifest.
+    if playlist.loudness_mode != LoudnessMode::Off {
+        playlist.write(File::create("playlist.manifest")?)?;
+    }
+
+    write_playlist_file(playlist)
+}
+
+/// Asks the user to pick one of choices, each choice is selected by its first letter.
+/// Returns the index of the selected choice.
+fn prompt_choice(question: &str, choices: &[&str]) -> Result<usize, Error> {
+    let options: Vec<String> = choices.iter().map(|f| format!("[{}]{}", &f[..1], &f[1..])).collect();
+
+    loop {
+        print!("{} {}: ", question, options.join(", "));
+        std::io::stdout().flush()?;
+
+        let mut buffer = String::new();
+        if std::io::stdin().read_line(&mut buffer)? == 0 {
+            pl_update_fatal_error!(ErrorKind::UnexpectedEof, "No answer was given to \"{}\"", question);
+        }
+
+        let answer = buffer.trim().to_lowercase();
+
+        if let Some(index) = choices.iter().position(|f| !answer.is_empty() && f.to_lowercase().starts_with(&answer)) {
+            return Ok(index);
+        }
+    }
+}
+
+/// Renames the files of songs in both playlists whose file name changed, such as when
+/// songs were added and the track number width grew.
+fn rename_changed_files(old_playlist: &Manifest, new_playlist: &Manifest) -> Result<(), Error> {
+    for song in unique_songs(&old_playlist.songs) {
+        if !new_playlist.songs.contains(&song) {
+            continue;
+        }
+
+        let filename = old_playlist.song_filename(&song);
+        let new_filename = new_playlist.song_filename(&song);
+
+        if filename != new_filename && fs::metadata(&filename).is_ok() {
+            fs::rename(filename, new_filename)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the current manifest aside under a timestamped name, and writes playlist in its place.
+fn save_manifest(playlist: &Manifest) -> Result<(), Error> {
+    match snapshot_manifest() {
+        Ok(_) => {},
+        Err(e) => {pl_update_fatal_error!(e.kind(), "Could not rename old playlist manifest: {}", e);}
+    };
+
+    playlist.write(File::create_new("playlist.manifest")?)
+}
+
+/// Makes a title safe to use as a directory name on any platform.
+fn sanitize_dir_name(title: &str) -> String {
+    let mut name: String = title.chars().map(|c| if "/\\:*?\"<>|".contains(c) || c.is_control() { '_' } else { c }).collect();
+
+    if name.len() > MAX_DIR_NAME_LEN {
+        let mut end = MAX_DIR_NAME_LEN;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+
+    //Windows drops trailing dots and spaces, and leading ones hide the directory or are easy to miss.
+    let name = name.trim_matches(|c: char| c == '.' || c.is_whitespace());
+
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|f| f.eq_ignore_ascii_case(stem)) {
+        return format!("{}_", name);
+    }
+
+    if name.is_empty() {
+        "playlist".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+
+/// The first of name, "name (2)", "name (3)"... in parent that does not exist or is an empty directory.
+fn unused_dir_name(parent: &Path, name: &str) -> String {
+    let is_free = |dir: &str| match fs::read_dir(parent.join(dir)) {
+        Ok(mut directory) => directory.next().is_none(),
+        Err(e) => e.kind() == ErrorKind::NotFound,
+    };
+
+    let mut candidate = name.to_string();
+    let mut count = 1;
+
+    while !is_free(&candidate) {
+        count += 1;
+        candidate = format!("{} ({})", name, count);
+    }
+
+    candidate
+}
+
+
+fn playlist_filename(playlist_title: &str) -> String {
+    let name: String = playlist_title.chars().map(|c| if "/\\:*?\"<>|".contains(c) || c.is_control() { '_' } else { c }).collect();
+    format!("{}.m3u8", name)
+}
+
+/// Writes an m3u8 playlist for the songs in playlist order, duplicates included.
+/// Songs without a file in the current directory are left out.
+fn write_playlist_file(playlist: &Manifest) -> Result<(), Error> {
+    let mut playlist_file = File::create(playlist_filename(&playlist.title))?;
+
+    playlist_file.write_all(b"#EXTM3U\n")?;
+
+    for song in &playlist.songs {
+        let filename = playlist.song_filename(song);
+
+        if fs::metadata(&filename).is_err() {
+            continue;
+        }
+
+        playlist_file.write_all(format!("#EXTINF:-1,{}\n{}\n", song.title, filename).as_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let time: chrono::DateTime<Local> = SystemTime::now().into();
+    let info = os_info::get();
+
+
+    let ver = env!("CARGO_PKG_VERSION");
+
+    #[cfg(debug_assertions)] 
+    env::set_var("RUST_BACKTRACE", "1");
+
+
+    println!("pl-update version {}", ver);
+
+    #[cfg(debug_assertions)] 
+    println!("Debugging build");
+
+    
+    if info.architecture().is_some() {
+        println!("Running on {} {} for {}", info.os_type(), info.version(), info.architecture().unwrap());
+    } else {
+        println!("Running on {} version {}", info.os_type(), info.version());
+    }
+
+    println!("Started at system time {}\n", time.format("%+"));
+
+    
+    let matches = Args::command().get_matches();
+    let mut args = match Args::from_arg_matches(&matches) {
+        Ok(val) => val,
+        Err(e) => e.exit(),
+    };
+
+    let console_level = args.log_level.unwrap_or(if args.verbose { Level::Debug } else if args.quiet { Level::Warn } else { Level::Info });
+
+    if let Err(e) = init_logging(console_level, args.log_format, args.log_file.as_deref()) {
+        eprintln!("\n{} [pl-update] Could not open log file \"{}\": {}\n", "FATAL ERROR:".red().bold(), args.log_file.unwrap_or_default().display(), e);
+        std::process::exit(EXIT_CONFIG_ERROR);
+    }
+
+    log::log_to_file(Level::Info, &format!("pl-update version {} on {}, started at {}", ver, info, time.format("%+")));
+
+
+    pl_update_vprintln!("Args: {:?}", args);
+
+    //The first Ctrl-C lets the command stop cleanly, a second one quits straight away.
+    let handler = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+
+        pl_update_warn!("Interrupted, stopping once running downloads have stopped. Press Ctrl-C again to quit immediately.");
+    });
+
+    if let Err(e) = handler {
+        pl_update_warn!("Could not set the Ctrl-C handler, interrupting pl-update may leave partial files: {}", e);
+    }
+
+    let command = args.command.clone();
+    //Commands change into the playlist directory, so paths are fixed before they run.
+    args.store_options.store = args.store_options.store.map(|f| std::path::absolute(&f).unwrap_or(f));
+    let summary_json = args.summary_json.as_ref().map(|f| std::path::absolute(f).unwrap_or_else(|_| PathBuf::from(f)));
+    let (keep_snapshots, snapshot_max_age) = (args.keep_snapshots, args.snapshot_max_age);
+ 
+
+    let ret = match command {
+        //Commands::Get => todo!(),
+        Commands::Init { playlist_urls, destination, track_numbers, audio_format, loudness, filters } => init::pl_init(args, playlist_urls, destination, track_numbers, audio_format, loudness, filters),
+        Commands::Add { urls, playlist } => add::pl_add(args, playlist, urls),
+        Commands::Filter { playlist_name, filters, clear } => filters::pl_filter(args, playlist_name, filters, clear),
+        Commands::Exclude { playlist_name, title, longer_than, live, no_live, clear } => {
+            let live = if live { Some(true) } else if no_live { Some(false) } else { None };
+            filters::pl_exclude(args, playlist_name, title, longer_than, live, clear)
+        },
+        Commands::Skip { ids, playlist, undo } => filters::pl_skip(args, playlist, ids, undo),
+        Commands::Sources { playlist_name, add, remove } => sources::pl_sources(args, playlist_name, add, remove),
+        Commands::Loudness { playlist_name, mode, target, reanalyse } => loudness::pl_loudness(args, playlist_name, mode, target, reanalyse),
+        Commands::Push { device_id, include, exclude } => push::pl_push(args, device_id, include, exclude).map_err(|e| with_exit_code(e, EXIT_DEVICE_ERROR)),
+        Commands::Retag { playlist_name, split, strip, album, track_number, clear } => retag::pl_retag(args, playlist_name, split, strip, album, track_number, clear),
+        Commands::Cover { playlist_name, square, no_square, size, image, fallback, folder, no_folder } => {
+            let square = if square { Some(true) } else if no_square { Some(false) } else { None };
+            let folder = if folder { Some(true) } else if no_folder { Some(false) } else { None };
+            cover::pl_cover(args, playlist_name, square, size, image, fallback, folder)
+        },
+        Commands::Status { playlist_name, tags } => status::pl_status(args, playlist_name, tags),
+        Commands::History { playlist_name, limit, songs } => history::pl_history(args, playlist_name, limit, songs),
+        Commands::Diff { from, to, playlist } => history::pl_diff(args, playlist, from, to),
+        Commands::Snapshots { playlist_name } => snapshots::pl_snapshots(args, playlist_name),
+        Commands::Rollback { snapshot, playlist, download } => snapshots::pl_rollback(args, playlist, snapshot, download),
+        Commands::Store { gc } => store::pl_store(args, gc),
+        Commands::Verify { playlist_name, missing, orphans, delete_partial } => verify::pl_verify(args, playlist_name, missing, orphans, delete_partial),
+        Commands::Repair { playlist_name, offline } => repair::pl_repair(args, playlist_name, offline),
+        Commands::Update { playlist_name, retag, follow_title, track_numbers, no_track_numbers } => {
+            let track_numbers = if track_numbers { Some(true) } else if no_track_numbers { Some(false) } else { None };
+            update::pl_update(args, playlist_name, retag, follow_title, track_numbers)
+        }
+
+    };
+
+    let new_time: chrono::DateTime<Local> = SystemTime::now().into();
+
+    let delta = new_time - time;
+
+
+    let code = if interrupted() {
+        if let Err(e) = &ret {
+            pl_update_vprintln!("Stopped with: {}", e);
+        }
+
+        eprintln!("\n{} [pl-update] Interrupted after {}m {}s, completed work was saved.\n", "STOPPED:".yellow().bold(), delta.num_minutes(), delta.num_seconds());
+        log::log_to_file(Level::Warn, &format!("Interrupted after {}m {}s", delta.num_minutes(), delta.num_seconds()));
+        EXIT_INTERRUPTED
+    } else {
+        match &ret {
+            Ok(_) => {
+                pl_update_println!("Operation completed in {}m {}s", delta.num_minutes(), delta.num_seconds());
+
+                if run_summary().is_partial() {
+                    pl_update_warn!("Some songs or sources could not be downloaded, see above.");
+                    EXIT_PARTIAL_FAILURE
+                } else {
+                    EXIT_SUCCESS
+                }
+            },
+            Err(e) => {
+                eprintln!("\n{} [pl-update] {}\n", "FATAL ERROR:".red().bold(), e);
+                log::log_to_file(Level::Error, &format!("Fatal error: {}", e));
+                exit_code(e)
+            }
+        }
+    };
 
-    match ret {
-        Ok(_) => {
-            println!("[pl-update] Operation completed in {}m {}s", delta.num_minutes(), delta.num_seconds());
 
+    let mut summary = run_summary();
+    summary.command = matches.subcommand_name().unwrap_or_default().to_string();
+    summary.started = time.to_rfc3339();
+    summary.duration_seconds = (delta.num_milliseconds() as f64) / 1000.0;
+    summary.exit_code = code;
+    summary.error = ret.err().map(|e| e.to_string());
 
-            Ok(())
-        
-        },
-        Err(e) => {
-            eprintln!("\n{} [pl-update] {}\n", "FATAL ERROR:".red().bold(), e);
-            Err(e)
+    if let Some(summary_json) = summary_json {
+        if let Err(e) = summary.write(&summary_json) {
+            pl_update_warn!("Could not write the run summary to \"{}\": {}", summary_json.display(), e);
         }
     }
 
+    if let Err(e) = history::append_history(&summary) {
+        pl_update_warn!("Could not add the run to the playlist history: {}", e);
+    }
 
-    
- 
+    if let Some(playlist_dir) = summary.playlist_dir.as_deref().filter(|_| summary.snapshot.is_some()) {
+        match snapshots::prune_snapshots(playlist_dir, keep_snapshots, snapshot_max_age) {
+            Ok(0) => {},
+            Ok(deleted) => pl_update_vprintln!("Deleted {} old manifest snapshots.", deleted),
+            Err(e) => pl_update_warn!("Could not delete old manifest snapshots: {}", e),
+        }
+    }
 
+    std::process::exit(code);
 }
 
 
-fn find_yt_dl(verbose: bool, ytdl_command: &String) -> Result<(), Error> {
+fn find_yt_dl(ytdl_command: &String) -> Result<(), Error> {
 
    
     let ytdl_check: Result<std::process::Output, Error> = Command::new(ytdl_command.clone()).arg("--version").output();
     
-    if ytdl_check.is_ok() {
-        let out = &ytdl_check.unwrap().stdout;
-        let ver = str::from_utf8(out).unwrap().trim();
-        if verbose {
-            println!("{} [pl-update] Found {} version {}", "DEBUG:".blue(), ytdl_command, ver);
-        }
-        return Ok(());
-        
-    } else {
-        let e = ytdl_check.unwrap_err();
-        pl_update_fatal_error!(e.kind(), "YT-DL could not be launched. Check that it is in the system path or current directory and is accessible. \nReason: {}", e);
-    
+    match ytdl_check {
+        Ok(output) => {
+            let ver = str::from_utf8(&output.stdout).unwrap().trim();
+            pl_update_vprintln!("Found {} version {}", ytdl_command, ver);
+            run_summary().tools.insert("yt-dlp".to_string(), ver.to_string());
+            Ok(())
+        },
+        Err(e) => {
+            let e = Error::new(e.kind(), format!("YT-DL could not be launched. Check that it is in the system path or current directory and is accessible. \nReason: {}", e));
+            Err(with_exit_code(e, EXIT_TOOL_MISSING))
+        },
     }
-        
-    
 
-}
 
+}
 
-fn find_ffmpeg(verbose: bool, ffmpeg_command: &String) -> Result<(), Error> {
 
+fn find_ffmpeg(ffmpeg_command: &String) -> Result<(), Error> {
 
 
     let ffmpeg_check: Result<std::process::Output, Error> = Command::new(ffmpeg_command.clone()).arg("-version").output();
         
-        if ffmpeg_check.is_ok() {
-            let out = &ffmpeg_check.unwrap().stdout;
-            let out_data = str::from_utf8(out).unwrap().split(" ").collect::<Vec<_>>();
+        if let Ok(output) = ffmpeg_check {
+            let out_data = str::from_utf8(&output.stdout).unwrap().split(" ").collect::<Vec<_>>();
             let ver = out_data.get(2).unwrap();
-            if verbose {
-                println!("{} [pl-update] Found {} version {}", "DEBUG:".blue(), ffmpeg_command, ver);
-            }
+            pl_update_vprintln!("Found {} version {}", ffmpeg_command, ver);
+            run_summary().tools.insert("ffmpeg".to_string(), ver.to_string());
             return Ok(());
             
         } 
 
-    pl_update_fatal_error!(ErrorKind::NotFound, "FFMPEG could not be found. Check that it is in the system path or current directory and is accessible.");
+    let e = Error::new(ErrorKind::NotFound, "FFMPEG could not be found. Check that it is in the system path or current directory and is accessible.");
+    Err(with_exit_code(e, EXIT_TOOL_MISSING))
+
+
+}
+
+/// Copies source to dest with the given tags replaced, using ffmpeg so every format yt-dlp produces is supported.
+/// Source and dest may be the same file.
+fn rewrite_tags(ffmpeg_command: &String, source: &str, dest: &str, tags: &[(&str, String)]) -> Result<(), Error> {
+
+    let file_ext = dest.rsplit_once('.').map_or("", |f| f.1);
+    let tmp_filename = format!("{}.retag.{}", dest, file_ext);
+
+    let mut ffmpeg = Command::new(ffmpeg_command);
+    ffmpeg.args(["-y", "-loglevel", "error", "-i", source, "-map", "0", "-codec", "copy"]);
+
+    if file_ext == "mp3" {
+        ffmpeg.args(["-id3v2_version", "3"]);
+    }
+
+    for (key, value) in tags {
+        ffmpeg.arg("-metadata").arg(format!("{}={}", key, value));
+    }
+
+    let ffmpeg_output = ffmpeg
+        .arg(&tmp_filename)
+        .output()?;
+
+    if !ffmpeg_output.status.success() {
+        let _ = fs::remove_file(&tmp_filename);
+        pl_update_fatal_error!(ErrorKind::Other, "FFMPEG could not rewrite tags of \"{}\": {}", source, String::from_utf8_lossy(&ffmpeg_output.stderr).trim());
+    }
+
+    fs::rename(&tmp_filename, dest)?;
 
+    if source != dest {
+        fs::remove_file(source)?;
+    }
 
+    Ok(())
 }
 
-fn parse_ytdl_stderr(mut std_err_reader: BufReader<ChildStderr>, tx: SyncSender<String>, procid: u32) -> Result<usize, String> {
+/// Forwards yt-dlp's stderr to tx, and returns the errors it printed.
+fn parse_ytdl_stderr(mut std_err_reader: BufReader<ChildStderr>, tx: SyncSender<String>, procid: u32) -> Vec<String> {
     let mut err_str: String = String::new();
     let mut err_bytes_read = 1;
-    let mut unavailable_songs: usize = 0;
+    let mut errors = Vec::new();
 
         while err_bytes_read > 0 {
 
 
-            
             let err_val = std_err_reader.read_line(&mut err_str);
 
             let out_str;
 
-            if err_val.is_err() {
-                pl_update_error!("Read from YT-DL STDERR buffer failed with error: \"{}\"!", err_val.unwrap_err());
-                err_str.clear();
-                continue;
-
-            } else {
-               err_bytes_read = err_val.unwrap();
+            match err_val {
+                Ok(val) => err_bytes_read = val,
+                Err(e) => {
+                    pl_update_error!("Read from YT-DL STDERR buffer failed with error: \"{}\"!", e);
+                    err_str.clear();
+                    continue;
+                }
             }
 
 
-
             if err_str.is_empty() {
                 out_str = String::new();
             } else if err_str.starts_with("[debug] ") {
@@ -398,12 +1913,11 @@ fn parse_ytdl_stderr(mut std_err_reader: BufReader<ChildStderr>, tx: SyncSender<
                 out_str = format!("[thread {}] {}{}", procid, "WARNING:".yellow(), err_str.split_off("WARNING:".len()));
 
             } else if err_str.starts_with("ERROR:") {
+                errors.push(err_str.trim_end().to_string());
+
                 if err_str.contains("Video unavailable.") {
                     err_str.pop();
                     err_str.push_str(".\n"); //Add a period because I can.
-                    unavailable_songs += 1;
-                } else if err_str.contains("Unsupported URL:") {
-                    return Err(err_str.split_off("ERROR:".len()));
                 }
 
                 out_str = format!("[thread {}] {}{}", procid, "ERROR:".red().bold(), err_str.split_off("ERROR:".len()));
@@ -419,7 +1933,7 @@ fn parse_ytdl_stderr(mut std_err_reader: BufReader<ChildStderr>, tx: SyncSender<
         }
 
         drop(tx);
-    return Ok(unavailable_songs);
+    errors
 
 
 }
@@ -436,9 +1950,7 @@ fn parse_ytdl_stdout(mut std_out_reader: BufReader<ChildStdout>, tx: SyncSender<
         bytes_read = std_out_reader.read_line(&mut buffer_str).unwrap(); //Read a line from YTDL's output.
         if bytes_read > 0 {
 
-            let out_str;
-
-            out_str = format!("[thread {}] {}", procid, buffer_str);
+            let out_str = format!("[thread {}] {}", procid, buffer_str);
 
             tx.send(out_str).unwrap();
             buffer_str.clear();
@@ -452,41 +1964,56 @@ fn parse_ytdl_stdout(mut std_out_reader: BufReader<ChildStdout>, tx: SyncSender<
 
 }
 
-fn update_manifest(mut manifest: File, playlist_title: String, playlist_url: &String, options: &Args) -> Result<(), Error> {
+/// Lists the songs of one source that pass the filters with yt-dlp, one song per line in the format the manifest uses.
+fn fetch_source(source: &str, filters: &Filters, options: &Args) -> Result<String, Error> {
 
-    manifest.write(format!("playlist_title={}{SEP_CHAR}url={}\n", playlist_title, playlist_url).as_bytes())?;
-    
     let mut output_args = Vec::new();
     let command_name = &options.yt_dl_location;
 
-    if options.verbose {
+    if console_enabled(Level::Debug) {
         output_args.push("--verbose".to_owned());
         output_args.push("--quiet".to_owned());
     }
 
     output_args.push("--windows-filenames".to_owned());
     output_args.push("--simulate".to_owned());
-    output_args.push("--flat-playlist".to_owned());
+    output_args.append(&mut options.rate_limits.ytdl_args(1));
+
+    //Flat listings do not have upload dates. Fully extracting every song fails on unavailable songs,
+    //which are skipped as they could not be downloaded anyway. Other errors fail the listing, see below.
+    let full_extraction = filters.date_after.is_some();
+
+    if let Some(date_after) = &filters.date_after {
+        output_args.push("--dateafter".to_owned());
+        output_args.push(date_after.clone());
+        output_args.push("--ignore-errors".to_owned());
+    } else {
+        output_args.push("--flat-playlist".to_owned());
+    }
+
+    if let Some(match_filter) = filters.ytdl_match_filter() {
+        output_args.push("--match-filter".to_owned());
+        output_args.push(match_filter);
+    }
+
+    if let Some(max_items) = filters.max_items {
+        output_args.push("--max-downloads".to_owned());
+        output_args.push(max_items.to_string());
+    }
+
     output_args.push("--lazy-playlist".to_owned());
-    output_args.push(playlist_url.clone());
+    output_args.push(source.to_owned());
     
     output_args.push("--print".to_owned());
 
     
-    output_args.push(format!("title=%(title)s{SEP_CHAR}id=%(id)s{SEP_CHAR}url=%(webpage_url)s"));
-
-
-    if !options.quiet {
-        println!("Fetching contents of playlist \"{playlist_title}\"");
-    } 
+    output_args.push(format!("title=%(title)s{SEP_CHAR}id=%(id)s{SEP_CHAR}url=%(webpage_url)s{SEP_CHAR}position=%(playlist_index)s{SEP_CHAR}extractor=%(ie_key,extractor_key)s{SEP_CHAR}artist=%(artist,channel,uploader)s{SEP_CHAR}duration=%(duration)s{SEP_CHAR}live_status=%(live_status)s"));
 
 
-    if options.verbose {
-        println!("Running {} with arguments {:?}", command_name, output_args);
-    }
+    pl_update_vprintln!("Running {} with arguments {:?}", command_name, output_args);
   
 
-    let mut ytdl_process = Command::new(&command_name)
+    let mut ytdl_process = Command::new(command_name)
     .args(&output_args)
     .stdout(Stdio::piped())
     .stderr(Stdio::piped())
@@ -499,38 +2026,208 @@ fn update_manifest(mut manifest: File, playlist_title: String, playlist_url: &St
     let procid = ytdl_process.id();
     let ytdl_err_handler = thread::spawn(move || parse_ytdl_stderr(err_reader, tx, procid));
 
-    let ytdl_out_handler: JoinHandle<Result<(),Error>> = thread::spawn(move || {
-    let buf = &mut vec![];
-    out_reader.read_to_end(buf)?;
+    let ytdl_out_handler: JoinHandle<Result<String, Error>> = thread::spawn(move || {
+        let mut buf = String::new();
+        out_reader.read_to_string(&mut buf)?;
+        Ok(buf)
+    });
+
+    let mut terminated = false;
 
-    manifest.write_all(&buf)?;
+    while !ytdl_err_handler.is_finished() || !ytdl_out_handler.is_finished() {
+        //Checked on every message, as yt-dlp may never pause long enough for the receive to time out.
+        if interrupted() && !terminated {
+            terminate_child(&mut ytdl_process);
+            terminated = true;
+        }
 
-    Ok(())
+        if let Ok(val) = rx.recv_timeout(Duration::from_millis(100)) {
+            print!("{}", val);
+        }
+    }
 
-    });
+    let entries = ytdl_out_handler.join().unwrap()?;
+    let errors = ytdl_err_handler.join().unwrap_or_default();
 
-    while !ytdl_err_handler.is_finished() || !ytdl_out_handler.is_finished() {
-        print!("{}", rx.recv().unwrap_or("".to_string()));
+    const MAX_DOWNLOADS_REACHED: i32 = 101;
+
+    let status = wait_child(&mut ytdl_process, CHILD_EXIT_TIMEOUT)?;
+
+    if interrupted() {
+        pl_update_fatal_error!(ErrorKind::Interrupted, "Interrupted while fetching \"{}\".", source);
     }
 
-    
+    //yt-dlp exits with MAX_DOWNLOADS_REACHED once --max-downloads songs are listed.
+    if !(status.success() || status.code() == Some(MAX_DOWNLOADS_REACHED) || (full_extraction && !entries.is_empty())) {
+        pl_update_fatal_error!(ErrorKind::Other, "YT-DL could not fetch the contents of \"{}\".", source);
+    }
 
-    Ok(())
+    //A song that could not be extracted for a reason that may pass, such as a rate limit, is missing from the listing
+    //and would be taken as removed from the playlist.
+    if let Some(error) = errors.iter().filter(|_| full_extraction).find(|f| !DownloadFailure::from_error(f).permanent) {
+        pl_update_fatal_error!(ErrorKind::Other, "YT-DL could not list every song of \"{}\", so it was not updated: {}", source, DownloadFailure::from_error(error).reason);
+    }
+
+    Ok(entries)
+}
+
+/// The title of the playlist at source, or None if it has none.
+fn fetch_title(source: &str, options: &Args) -> Result<Option<String>, Error> {
+    let command_name = &options.yt_dl_location;
+    let mut output_args: Vec<String> = ["--simulate", "--flat-playlist", "--lazy-playlist", "--playlist-items=1", "--print", "%(playlist)s", source].map(|f| f.to_owned()).into();
+    output_args.append(&mut options.rate_limits.ytdl_args(1));
+
+    pl_update_vprintln!("Running {} with arguments {:?}", command_name, output_args);
+
+    let ytdl_output = Command::new(command_name)
+        .args(output_args)
+        .output()?;
+
+    if !ytdl_output.status.success() {
+        pl_update_fatal_error!(ErrorKind::Other, "YT-DL could not fetch the title of \"{}\": {}", source, String::from_utf8_lossy(&ytdl_output.stderr).trim());
+    }
+
+    let title = String::from_utf8_lossy(&ytdl_output.stdout).lines().next().unwrap_or_default().trim().to_string();
+
+    if title.is_empty() || title == "NA" {
+        Ok(None)
+    } else {
+        Ok(Some(title))
+    }
 }
 
-fn parse_manifest(manifest: File) -> Result<(Vec<Song>, String, String), Error> {
+/// Lists the songs of one url that pass the filters.
+fn fetch_songs(url: &str, filters: &Filters, options: &Args) -> Result<Vec<Song>, Error> {
+    let entries = fetch_source(url, filters, options)?;
 
-    let mut playlist_title = "".to_string();
-    let mut playlist_url = "".to_string();
+    Ok(entries.lines().enumerate().filter_map(|(i, entry)| parse_song_entry(entry, i + 1)).collect())
+}
+
+/// Writes the manifest header followed by the songs of every source of the playlist, in source order.
+/// Songs already listed by an earlier source are left out, so a song in several sources is only downloaded once.
+/// Returns the sources that could not be fetched, a playlist with only one source fails instead.
+fn update_manifest(mut manifest: File, playlist: &Manifest, options: &Args) -> Result<Vec<String>, Error> {
+
+    let playlist_title = &playlist.title;
+    let start = Instant::now();
+
+    if !playlist.sources.is_empty() {
+        find_yt_dl(&options.yt_dl_location)?;
+    }
+
+    manifest.write_all(playlist.header().as_bytes())?;
+
+    pl_update_println!("Fetching contents of playlist \"{playlist_title}\"");
+
+    let mut listed_songs: Vec<Song> = Vec::new();
+    let mut failed_sources = Vec::new();
+    let mut position = 0;
+
+    for (i, source) in playlist.sources.iter().enumerate() {
+        if i > 0 {
+            sleep_unless_interrupted(options.rate_limits.pause(Duration::ZERO));
+        }
+
+        let entries = match fetch_source(source, &playlist.filters, options) {
+            Ok(val) => val,
+            Err(e) if playlist.sources.len() > 1 && e.kind() != ErrorKind::Interrupted => {
+                pl_update_warn!("{}", e);
+                failed_sources.push(source.clone());
+                continue;
+            },
+            Err(e) => return Err(e),
+        };
+
+        let mut source_songs = Vec::new();
+
+        for entry in entries.lines().filter(|f| !f.is_empty()) {
+            let mut fields: Vec<String> = entry.split(SEP_CHAR).map(|f| f.to_string()).collect();
+            //Compared as songs, so songs of two sites that happen to share an id are both kept.
+            let song = parse_song_entry(entry, 0);
+
+            //Repeats within one source are kept, they are duplicates in the playlist itself.
+            if song.as_ref().is_some_and(|f| listed_songs.contains(f) && !source_songs.contains(f)) {
+                continue;
+            }
+
+            //Flat listings are not counted towards --max-downloads.
+            if playlist.filters.max_items.is_some_and(|f| source_songs.len() >= f) {
+                break;
+            }
+
+            //Positions are numbered across sources, as each source counts from 1.
+            position += 1;
+
+            for field in fields.iter_mut().filter(|f| f.starts_with("position=")) {
+                *field = format!("position={}", position);
+            }
+
+            if playlist.sources.len() > 1 {
+                fields.push(format!("source={}", source));
+            }
+
+            manifest.write_all(format!("{}\n", fields.join(&SEP_CHAR.to_string())).as_bytes())?;
+            source_songs.extend(song);
+        }
+
+        listed_songs.append(&mut source_songs);
+    }
+
+    let mut summary = run_summary();
+    summary.phase("listing", start);
+    summary.failed_sources.extend(failed_sources.iter().cloned());
+
+    Ok(failed_sources)
+}
+
+/// Parses a song line of a manifest, or of a yt-dlp listing. Returns None if the line is not a song.
+fn parse_song_entry(entry: &str, position: usize) -> Option<Song> {
+    let vals: Vec<&str> = entry.split(SEP_CHAR).collect();
+
+    if vals.len() < 3 {
+        return None;
+    }
+
+    let title = vals[0].strip_prefix("title=")?.to_string();
+    let id = vals[1].strip_prefix("id=")?.to_string();
+    let url = vals[2].strip_prefix("url=")?;
+
+    let url = if url.is_empty() { None } else { Some(url.to_string()) };
+
+    let mut song = Song::new(title, id, url, position, None);
+
+    for field in vals.iter().skip(3) {
+        match field.split_once('=') {
+            Some((_, "NA")) | Some((_, "")) => {}, //Fields yt-dlp had no value for
+            Some(("position", val)) => song.position = val.parse().unwrap_or(song.position),
+            Some(("extractor", val)) => song.extractor = Some(val.to_string()),
+            Some(("artist", val)) => song.artist = Some(val.to_string()),
+            Some(("duration", val)) => song.duration = val.parse::<f64>().ok().map(|f| f.round() as u64),
+            Some(("live_status", val)) => song.live_status = Some(val.to_string()),
+            Some(("source", val)) => song.source = Some(val.to_string()),
+            Some(("pinned", val)) => song.pinned = val == "true",
+            Some(("loudness", val)) => song.loudness = val.parse().ok(),
+            Some(("peak", val)) => song.peak = val.parse().ok(),
+            Some(("failed", val)) => song.failed = Some(DownloadFailure { permanent: val == "permanent", reason: String::new() }),
+            Some(("failed_reason", val)) => if let Some(failed) = song.failed.as_mut() {
+                failed.reason = val.to_string();
+            },
+            _ => {}
+        }
+    }
+
+    Some(song)
+}
+
+fn parse_manifest(manifest: impl Read) -> Result<Manifest, Error> {
+
+    let mut playlist = Manifest::new("".to_string(), "".to_string());
 
-  
 
     let file_reader = BufReader::new(manifest);
 
     let entries = file_reader.lines();
 
-    let mut songs = Vec::new();
-
 
     let mut line_num = 0;
     for entry in entries {
@@ -543,72 +2240,200 @@ fn parse_manifest(manifest: File) -> Result<(Vec<Song>, String, String), Error>
         if line_num == 1 {
             let first: Vec<&str> = entry_.split(SEP_CHAR).collect();
             
-            let name_ = first.get(0).expect("manifest should contain '\\x06'");
+            let name_ = first.first().expect("manifest should contain '\\x06'");
             let url_ = first.get(1).expect("manifest should contain '\\x06'");
 
 
-            playlist_title = name_.split_once("playlist_title=").expect("manifest should contain playlist name").1.to_string();
-            playlist_url = url_.split_once("url=").expect("manifest should contain playlist name").1.to_string();
+            playlist.title = name_.split_once("playlist_title=").expect("manifest should contain playlist name").1.to_string();
+            let url = url_.split_once("url=").expect("manifest should contain playlist name").1.to_string();
+            playlist.sources = if url.is_empty() { Vec::new() } else { vec![url] };
+
+            let mut album_loudness = None;
+            let mut album_peak = None;
+
+            for option in first.iter().skip(2) {
+                match option.split_once('=') {
+                    Some(("track_numbers", val)) => playlist.track_numbers = val == "true",
+                    Some(("source", val)) => playlist.sources.push(val.to_string()),
+                    Some(("remote_title", val)) => playlist.remote_title = Some(val.to_string()),
+                    Some(("title_history", val)) => match val.split_once(' ') {
+                        Some((date, title)) => playlist.title_history.push((date.to_string(), title.to_string())),
+                        None => pl_update_warn!("Invalid title history \"{}\" in manifest, it will be ignored.", val),
+                    },
+                    Some(("audio_format", val)) => playlist.audio_format = val.to_string(),
+                    Some(("filter_date_after", val)) => playlist.filters.date_after = Some(val.to_string()),
+                    Some(("filter_min_duration", val)) => playlist.filters.min_duration = val.parse().ok(),
+                    Some(("filter_max_duration", val)) => playlist.filters.max_duration = val.parse().ok(),
+                    Some(("filter_title", val)) => playlist.filters.title_match = Some(val.to_string()),
+                    Some(("filter_max_items", val)) => playlist.filters.max_items = val.parse().ok(),
+                    Some(("filter_match", val)) => playlist.filters.match_filter = Some(val.to_string()),
+                    Some(("skip", val)) => playlist.excludes.ids.push(val.to_string()),
+                    Some(("exclude_title", val)) => playlist.excludes.titles.push(val.to_string()),
+                    Some(("exclude_longer", val)) => playlist.excludes.longer_than = val.parse().ok(),
+                    Some(("exclude_live", val)) => playlist.excludes.live = val == "true",
+                    Some(("tag_split", val)) => playlist.tag_rules.split = Some(val.to_string()),
+                    Some(("tag_strip", val)) => playlist.tag_rules.strip.push(val.to_string()),
+                    Some(("tag_album", val)) => playlist.tag_rules.album = val == "true",
+                    Some(("tag_track", val)) => playlist.tag_rules.track_number = val == "true",
+                    Some(("cover_square", val)) => playlist.cover_rules.square = val == "true",
+                    Some(("cover_size", val)) => playlist.cover_rules.size = val.parse().ok(),
+                    Some(("cover_image", val)) => playlist.cover_rules.image = Some(val.to_string()),
+                    Some(("cover_fallback", val)) => playlist.cover_rules.fallback = val == "true",
+                    Some(("cover_folder", val)) => playlist.cover_rules.folder = val == "true",
+                    Some(("loudness", val)) => match LoudnessMode::from_str(val, true) {
+                        Ok(mode) => playlist.loudness_mode = mode,
+                        Err(_) => pl_update_warn!("Unknown loudness mode \"{}\" in manifest, loudness analysis will be off.", val),
+                    },
+                    Some(("loudness_target", val)) => playlist.loudness_target = val.parse().unwrap_or(DEFAULT_LOUDNESS_TARGET),
+                    Some(("album_loudness", val)) => album_loudness = val.parse().ok(),
+                    Some(("album_peak", val)) => album_peak = val.parse().ok(),
+                    _ => pl_update_warn!("Unknown playlist option \"{}\" in manifest, it will be ignored.", option),
+                }
+            }
+
+            playlist.album_loudness = album_loudness.zip(album_peak);
             continue;
         }
 
-        
 
+        //Manifests written before positions were tracked are in playlist order.
+        match parse_song_entry(&entry_, line_num - 1) {
+            Some(song) => playlist.songs.push(song),
+            None => {
+                pl_update_fatal_error!(ErrorKind::InvalidData, "Error while parsing playlist manifest at line: {line_num}");
+            }
+        }
+
+
+    }
+
+    if line_num < 1 {
+        pl_update_fatal_error!(ErrorKind::UnexpectedEof, "Unexpected EOF while parsing playlist manifest.");
+    }
+
+
+    Ok(playlist)
 
-        let vals: Vec<&str> = entry_.split(SEP_CHAR).collect();
+}
+
+
+/// Downloads songs into the current directory, retrying songs that failed for a reason that may pass.
+/// Returns the songs that could not be downloaded, and why. With a store, songs already in it are linked instead.
+fn download(songs: &[Song], audio_format: &str, options: &Args) -> Result<Vec<(Song, DownloadFailure)>, Error> {
+    let file_ext = audio_file_ext(audio_format);
+
+    //The store is locked only while it is changed, not while songs download, so runs for other playlists can use it.
+    let songs = match Store::open(&options.store_options)? {
+        Some(mut store) => store.link_stored(songs, file_ext)?,
+        None => songs.to_vec(),
+    };
+    let songs = songs.as_slice();
+
+    //Songs with no url cannot be tried.
+    let mut pending: Vec<Song> = songs.iter().filter(|f| f.url().is_some()).cloned().collect();
+    let mut failures = Vec::new();
+    let max_attempts = options.retries + 1;
+    let start = Instant::now();
 
-        let title_ = vals.get(0).unwrap();
-        let id_ = vals.get(1).unwrap();
-        let url_ = vals.get(2).unwrap();
+    find_yt_dl(&options.yt_dl_location)?;
 
+    for attempt in 1..=max_attempts {
+        let urls = song_urls(&pending);
 
-        if !title_.starts_with("title=") || !id_.starts_with("id=") || !url_.starts_with("url=") {
-            pl_update_fatal_error!(ErrorKind::InvalidData, "Error while parsing playlist manifest at line: {line_num}");
+        if urls.is_empty() {
+            break;
         }
 
-        let title = title_.split_at("title=".len()).1.to_string();
-        let id = id_.split_at("id=".len()).1.to_string();
-    
-        let url = if url_.len() > "url=".len() {
-            Some(url_.split_at("url=".len()).1.to_string())
-        } else {
-            None
-        };
+        let errors = run_download(urls, audio_format, options)?;
+        let mut retry = Vec::new();
 
-        
-        songs.push(Song::new(title, id, url));
+        //yt-dlp does not name the song in every error, such as some timeouts, so a song without a file was not downloaded whatever was printed.
+        let file_names = file_names()?;
+        let unattributed = errors.iter().find(|error| !pending.iter().any(|song| error_is_for(error, song)));
+
+        for song in pending {
+            if has_file(&file_names, &song, file_ext) {
+                continue;
+            }
+
+            let failure = match errors.iter().find(|error| error_is_for(error, &song)) {
+                Some(error) => DownloadFailure::from_error(error),
+                None if interrupted() => DownloadFailure { permanent: false, reason: "Interrupted".to_string() },
+                //An error that names no song cannot be known to be permanent for this one.
+                None => DownloadFailure { permanent: false, reason: unattributed.map_or("yt-dlp did not write the file".to_string(), |f| DownloadFailure::from_error(f).reason) },
+            };
+
+            if failure.permanent || attempt == max_attempts || interrupted() {
+                failures.push((song, failure));
+            } else {
+                retry.push(song);
+            }
+        }
 
+        if interrupted() || retry.is_empty() {
+            break;
+        }
 
+        let delay = retry_delay(options.retry_delay, attempt);
+        pl_update_warn!("{} songs could not be downloaded, retrying in {}s (attempt {} of {}).", retry.len(), delay.as_secs(), attempt + 1, max_attempts);
+        sleep_unless_interrupted(delay);
 
+        pending = retry;
     }
 
-    if line_num <= 1 {
-        pl_update_fatal_error!(ErrorKind::UnexpectedEof, "Unexpected EOF while parsing playlist manifest.");
+    let file_names = file_names()?;
+    let downloaded = songs.iter().filter(|f| f.url().is_some() && has_file(&file_names, f, file_ext)).count();
+
+    if let Some(mut store) = Store::open(&options.store_options)? {
+        let downloaded_songs: Vec<Song> = songs.iter().filter(|f| !failures.iter().any(|(song, _)| song == *f)).cloned().collect();
+        store.adopt_downloads(&downloaded_songs, file_ext)?;
     }
 
+    let mut summary = run_summary();
+    summary.phase("download", start);
+    summary.downloaded += downloaded;
+    summary.failed.extend(failures.iter().map(|f| f.into()));
+    drop(summary);
 
+    let permanent = failures.iter().filter(|(_, f)| f.permanent).count();
+    pl_update_println!("Downloaded {} songs. {} songs were unavailable, {} more failed and will be retried on the next run.", downloaded, permanent, failures.len() - permanent);
+
+    Ok(failures)
+}
 
-    Ok((songs, playlist_title, playlist_url))
+/// The names of the files in the current directory.
+fn file_names() -> Result<Vec<String>, Error> {
+    Ok(fs::read_dir(".")?.filter_map(|f| f.ok()?.file_name().into_string().ok()).collect())
+}
 
+/// Whether file_names has the file yt-dlp writes for song.
+fn has_file(file_names: &[String], song: &Song, file_ext: &str) -> bool {
+    let file_suffix = format!("[{}].{}", song.id, file_ext);
+    file_names.iter().any(|f| f.ends_with(&file_suffix))
 }
 
+/// Whether an error printed by yt-dlp names song, by its id or url.
+fn error_is_for(error: &str, song: &Song) -> bool {
+    error.contains(&format!("] {}: ", song.id)) || song.url().is_some_and(|url| error.contains(&url))
+}
 
-fn download(mut urls: Vec<String>, options: &Args) -> Result<(), Error> {
+/// Runs yt-dlp on urls, split between threads. Returns the errors yt-dlp printed.
+fn run_download(mut urls: Vec<String>, audio_format: &str, options: &Args) -> Result<Vec<String>, Error> {
 
     
     let ffmpeg_command = options.ffmpeg_location.clone().unwrap_or("ffmpeg".to_string());
   
-    find_ffmpeg(options.verbose, &ffmpeg_command)?;
+    find_ffmpeg(&ffmpeg_command)?;
 
 
-    let mut max_threads = options.threads;
+    let mut max_threads = options.threads.min(options.rate_limits.max_concurrent.unwrap_or(usize::MAX)).max(1);
     
-    if !options.quiet && max_threads > 1 {
-        println!("Cores available: {}, Using: {}", std::thread::available_parallelism()?.get() , max_threads);
+    if max_threads > 1 {
+        pl_update_println!("Cores available: {}, Using: {}", std::thread::available_parallelism()?.get() , max_threads);
     }
 
     let mut output_args = vec!["--extract-audio".to_owned(),
-        "--audio-format=mp3".to_owned(), "--embed-thumbnail".to_owned(), "--add-metadata".to_owned()];
+        format!("--audio-format={}", audio_format), "--embed-thumbnail".to_owned(), "--add-metadata".to_owned()];
 
 
     if options.ffmpeg_location.is_some() {
@@ -617,11 +2442,16 @@ fn download(mut urls: Vec<String>, options: &Args) -> Result<(), Error> {
     
     }
 
- 
 
-    if options.verbose {
+    if let Some(archive) = archive_path(&options.store_options, audio_file_ext(audio_format)) {
+        output_args.push("--download-archive".to_owned());
+        output_args.push(archive.to_string_lossy().to_string());
+    }
+
+
+    if console_enabled(Level::Debug) {
         output_args.push("--verbose".to_owned());
-    } else if options.quiet {
+    } else if !console_enabled(Level::Info) {
         output_args.push("--quiet".to_owned());
     }
 
@@ -655,7 +2485,7 @@ fn download(mut urls: Vec<String>, options: &Args) -> Result<(), Error> {
     while i < max_threads {
 
         
-        if urls.len() % urls_per_thread == 0 {
+        if urls.len().is_multiple_of(urls_per_thread) {
             range_end = urls_per_thread;
         } else {
             range_end = urls_per_thread + 1;
@@ -669,17 +2499,13 @@ fn download(mut urls: Vec<String>, options: &Args) -> Result<(), Error> {
 
     } 
 
-    if urls.len() != 0 {
+    if !urls.is_empty() {
         pl_update_warn!("Parser is dumb and dropped {} urls, sorry.", urls.len());
     }
 
  
-    if options.verbose {
-        println!("{} [pl-update] URL vecs for threads {:?}", "DEBUG:".blue(), split_url_vecs);
-    }
-    
+    pl_update_trace!("URL vecs for threads {:?}", split_url_vecs);
 
-    
 
     let mut ytdl_threads = Vec::new();
     let mut output_handlers = Vec::new();
@@ -689,17 +2515,18 @@ fn download(mut urls: Vec<String>, options: &Args) -> Result<(), Error> {
 
     for thread_urls in split_url_vecs {
 
+        if interrupted() {
+            break;
+        }
 
         let mut ytdl_thread = Command::new(options.yt_dl_location.clone())
-                .args([output_args.clone(), thread_urls].concat())
+                .args([output_args.clone(), options.rate_limits.ytdl_args(max_threads), thread_urls].concat())
                 .stderr(Stdio::piped())
                 .stdout(Stdio::piped()) //Set ytdl to have a piped output so we can use its output later.
                 .spawn()?; //Run YTDL as a child process.
 
 
-        if !options.quiet {
-            println!("[pl-update] Started download thread with id: {}", ytdl_thread.id());
-        }
+        pl_update_println!("Started download thread with id: {}", ytdl_thread.id());
 
         let threadid = ytdl_thread.id();
 
@@ -709,7 +2536,7 @@ fn download(mut urls: Vec<String>, options: &Args) -> Result<(), Error> {
         let txerr = tx.clone();
         let txout = tx.clone();
 
-        let child_err_handler: JoinHandle<Result<usize, String>> = 
+        let child_err_handler: JoinHandle<Vec<String>> = 
                     thread::spawn(move || parse_ytdl_stderr(err_reader, txerr, threadid));
 
         let child_out_handler: JoinHandle<()> =
@@ -720,19 +2547,29 @@ fn download(mut urls: Vec<String>, options: &Args) -> Result<(), Error> {
         err_handlers.push(child_err_handler);
 
         ytdl_threads.push(ytdl_thread);
-        sleep(Duration::from_millis(200)); //Wait 1s before creating each thread to spread out the load a lil bit
+        sleep_unless_interrupted(options.rate_limits.pause(Duration::from_millis(200))); //Wait before creating each thread to spread out the load a lil bit
 
     }
     
     drop(tx);
 
 
+    let mut terminated = false;
+
     loop {
-        match rx.recv() {
+        //Checked on every message, as yt-dlp may never pause long enough for the receive to time out.
+        if interrupted() && !terminated {
+            ytdl_threads.iter_mut().for_each(terminate_child);
+            terminated = true;
+        }
+
+        match rx.recv_timeout(Duration::from_millis(100)) {
             Ok(recv_string) => {
                 print!("{}", recv_string);
+                log::log_to_file(Level::Debug, recv_string.trim_end());
             },
-            Err(_) => {
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => {
                 break;
             }
 
@@ -740,31 +2577,255 @@ fn download(mut urls: Vec<String>, options: &Args) -> Result<(), Error> {
     }
 
 
-    let mut unavailable_songs = 0;
+    let mut errors = Vec::new();
 
-   
+    for thread in err_handlers {
+        errors.append(&mut thread.join().unwrap());
+    }
 
+    for mut child in ytdl_threads {
+        wait_child(&mut child, CHILD_EXIT_TIMEOUT)?;
 
-    for thread in err_handlers {
-        unavailable_songs += thread.join().unwrap().unwrap();
+        pl_update_println!("Closed download thread with id: {}", child.id());
     }
 
-    let downloaded_songs = urls.len() - unavailable_songs;
+    if interrupted() {
+        let removed = remove_partial_files()?;
 
-    for mut child in ytdl_threads {
-        if !options.quiet {
-           println!("[pl-update] Closed download thread with id: {}", child.id());
+        if removed > 0 {
+            pl_update_println!("Deleted {} partially downloaded files.", removed);
         }
-        child.kill()?;
     }
 
 
-    if !options.quiet {
-        println!("[pl-update] Downloaded {} songs. {} songs were unavailable for download.", downloaded_songs, unavailable_songs);
+    Ok(errors)
+
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Mutex;
+
+    /// Commands work in the current directory, which every test shares.
+    static CURRENT_DIR: Mutex<()> = Mutex::new(());
+
+    fn songs(ids: &[&str]) -> Vec<Song> {
+        ids.iter().enumerate().map(|(i, id)| Song::new(format!("Song {}", id), id.to_string(), None, i + 1, None)).collect()
     }
 
-    Ok(())
+    /// Runs test in a new empty current directory, one test at a time.
+    pub(crate) fn in_temp_dir(name: &str, test: impl FnOnce()) {
+        let _lock = CURRENT_DIR.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = env::temp_dir().join(format!("pl-update-{}-test-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+
+        let previous_dir = env::current_dir().unwrap();
+        env::set_current_dir(&dir).unwrap();
+        let result = panic::catch_unwind(AssertUnwindSafe(test));
+        env::set_current_dir(previous_dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        if let Err(e) = result {
+            panic::resume_unwind(e);
+        }
+    }
 
-}
+    /// Options that point at an ffmpeg that does not exist, so every file rule fails.
+    pub(crate) fn options_without_ffmpeg() -> Args {
+        Args::parse_from(["pl-update", "--ffmpeg-location", "pl-update-missing-ffmpeg", "status"])
+    }
+
+
+    #[test]
+    fn finds_no_moves_in_same_order() {
+        let old = songs(&["a", "b", "c", "d"]);
+
+        assert!(moved_songs(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn added_and_removed_songs_do_not_move_others() {
+        let old = songs(&["a", "b", "c", "d"]);
+        let new = songs(&["x", "a", "c", "d", "y"]);
+
+        assert!(moved_songs(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn finds_moved_song() {
+        let old = songs(&["a", "b", "c", "d", "e"]);
+        let new = songs(&["a", "c", "d", "b", "e"]);
+
+        assert_eq!(moved_songs(&old, &new), songs(&["b"]));
+    }
+
+    #[test]
+    fn pads_track_numbers_to_playlist_length() {
+        let mut playlist = Manifest::new("Mix".to_string(), String::new());
+        playlist.track_numbers = true;
+        playlist.songs = songs(&["a", "b", "c"]);
+
+        assert_eq!(playlist.song_filename(&playlist.songs[2]), "03 - Song c [c].mp3");
+
+        playlist.songs = songs(&["a"; 120]);
+        assert_eq!(playlist.song_filename(&playlist.songs[0]), "001 - Song a [a].mp3");
+    }
+
+    #[test]
+    fn reads_back_positions_and_track_numbers() {
+        let mut playlist = Manifest::new("Mix".to_string(), "https://www.youtube.com/playlist?list=PL1".to_string());
+        playlist.track_numbers = true;
+        playlist.songs = songs(&["a", "b"]);
+        playlist.songs[0].position = 2;
+        playlist.songs[1].position = 1;
+
+        let mut manifest = Vec::new();
+        playlist.write(&mut manifest).unwrap();
+        let parsed = parse_manifest(manifest.as_slice()).unwrap();
 
+        assert_eq!(parsed.header(), playlist.header());
+        assert_eq!(parsed.songs.iter().map(|f| (f.id.as_str(), f.position)).collect::<Vec<_>>(), [("a", 2), ("b", 1)]);
+    }
+
+    #[test]
+    fn reads_back_download_failures() {
+        let mut playlist = Manifest::new("Mix".to_string(), String::new());
+        playlist.songs = songs(&["a", "b", "c"]);
+        let failures = [
+            (playlist.songs[0].clone(), DownloadFailure { permanent: true, reason: "Video unavailable".to_string() }),
+            (playlist.songs[1].clone(), DownloadFailure { permanent: false, reason: "HTTP Error 429: Too Many Requests".to_string() }),
+        ];
+        playlist.record_downloads(&playlist.songs.clone(), &failures);
+
+        let mut manifest = Vec::new();
+        playlist.write(&mut manifest).unwrap();
+        let parsed = parse_manifest(manifest.as_slice()).unwrap();
+
+        assert_eq!(parsed.songs.iter().map(|f| f.failed.clone()).collect::<Vec<_>>(), [Some(failures[0].1.clone()), Some(failures[1].1.clone()), None]);
+    }
+
+    #[test]
+    fn saves_download_failures_when_file_rules_fail() {
+        in_temp_dir("save-downloads", || {
+            let mut playlist = Manifest::new("Mix".to_string(), String::new());
+            playlist.cover_rules.square = true;
+            playlist.loudness_mode = LoudnessMode::Normalize;
+            playlist.songs = songs(&["a", "b"]);
+            fs::write(playlist.songs[0].to_filename(playlist.file_ext().to_owned()), "audio").unwrap();
+
+            let failures = [(playlist.songs[1].clone(), DownloadFailure { permanent: false, reason: "HTTP Error 429: Too Many Requests".to_string() })];
+            let downloaded = playlist.songs.clone();
+            save_downloads(&mut playlist, &downloaded, &failures, &options_without_ffmpeg()).unwrap();
+
+            let parsed = parse_manifest(File::open("playlist.manifest").unwrap()).unwrap();
+            assert_eq!(parsed.songs.iter().map(|f| f.failed.clone()).collect::<Vec<_>>(), [None, Some(failures[0].1.clone())]);
+
+            let filename = playlist.song_filename(&playlist.songs[0]);
+            assert!(fs::metadata(&filename).is_ok());
+            assert!(fs::read_to_string(playlist_filename(&playlist.title)).unwrap().contains(&filename));
+        });
+    }
+
+    #[test]
+    fn matches_downloaded_files_and_errors_to_songs() {
+        let song = songs(&["dQw4w9WgXcQ"]).remove(0);
+        let file_names = vec!["Other [abc].mp3".to_string(), "Song [dQw4w9WgXcQ].mp3.part".to_string()];
+
+        assert!(!has_file(&file_names, &song, "mp3"));
+        assert!(has_file(&["Song [dQw4w9WgXcQ].mp3".to_string()], &song, "mp3"));
+
+        assert!(error_is_for("ERROR: [youtube] dQw4w9WgXcQ: Video unavailable", &song));
+        assert!(error_is_for("ERROR: Unable to reach https://www.youtube.com/watch?v=dQw4w9WgXcQ", &song));
+        assert!(!error_is_for("ERROR: [youtube] abc: Video unavailable", &song));
+    }
+
+    #[test]
+    fn parses_song_filename() {
+        assert_eq!(parse_song_filename("Song - Live [dQw4w9WgXcQ].mp3", "mp3"), Some(("Song - Live".to_string(), "dQw4w9WgXcQ".to_string())));
+        assert_eq!(parse_song_filename("Song [a] [1234567890].opus", "opus"), Some(("Song [a]".to_string(), "1234567890".to_string())));
+    }
+
+    #[test]
+    fn rejects_filenames_without_id() {
+        assert_eq!(parse_song_filename("Song.mp3", "mp3"), None);
+        assert_eq!(parse_song_filename("Song [].mp3", "mp3"), None);
+        assert_eq!(parse_song_filename("Song [has space].mp3", "mp3"), None);
+        assert_eq!(parse_song_filename("Song [dQw4w9WgXcQ].m4a", "mp3"), None);
+        assert_eq!(parse_song_filename("Song [dQw4w9WgXcQ]mp3", "mp3"), None);
+    }
+
+    #[test]
+    fn songs_match_on_id_and_extractor() {
+        let youtube = Song::new("A".to_string(), "123".to_string(), None, 1, Some("Youtube".to_string()));
+        let old_youtube = Song::new("B".to_string(), "123".to_string(), None, 2, None);
+        let soundcloud = Song::new("A".to_string(), "123".to_string(), None, 1, Some("Soundcloud".to_string()));
+        let unknown = Song::new("A".to_string(), "123".to_string(), None, 1, Some(UNKNOWN_EXTRACTOR.to_string()));
+        let other_id = Song::new("A".to_string(), "456".to_string(), None, 1, Some("Youtube".to_string()));
+
+        assert_eq!(youtube, old_youtube);
+        assert_ne!(youtube, soundcloud);
+        assert_eq!(unknown, soundcloud);
+        assert_eq!(unknown, youtube);
+        assert_ne!(youtube, other_id);
+    }
+
+    #[test]
+    fn guesses_extractor() {
+        assert_eq!(guess_extractor("x", Some("https://www.youtube.com/watch?v=x")), Some("Youtube".to_string()));
+        assert_eq!(guess_extractor("x", Some("https://youtu.be/x")), Some("Youtube".to_string()));
+        assert_eq!(guess_extractor("1", Some("https://soundcloud.com/user/song")), Some("Soundcloud".to_string()));
+        assert_eq!(guess_extractor("dQw4w9WgXcQ", None), Some("Youtube".to_string()));
+        assert_eq!(guess_extractor("12345", None), None);
+        assert_eq!(recorded_extractor("12345", None), UNKNOWN_EXTRACTOR);
+    }
+
+    #[test]
+    fn splits_track_number() {
+        assert_eq!(split_track_number("07 - Song - Live"), (Some(7), "Song - Live"));
+        assert_eq!(split_track_number("Artist - Song"), (None, "Artist - Song"));
+        assert_eq!(split_track_number(" - Song"), (None, " - Song"));
+    }
+
+    #[test]
+    fn sanitizes_title_like_yt_dlp() {
+        assert_eq!(sanitize_title("AC/DC: Live?"), "AC\u{29F8}DC\u{FF1A} Live\u{FF1F}");
+        assert_eq!(sanitize_title("a\\b \"c\" <d> | *e*"), "a\u{29F9}b \u{FF02}c\u{FF02} \u{FF1C}d\u{FF1E} \u{FF5C} \u{FF0A}e\u{FF0A}");
+        assert_eq!(sanitize_title("Line\nbreak\u{7}"), "Line break");
+    }
+
+    #[test]
+    fn song_filename_parses_back() {
+        let song = Song::new("What? / Why".to_string(), "dQw4w9WgXcQ".to_string(), None, 1, None);
+
+        assert_eq!(parse_song_filename(&song.to_filename("mp3".to_string()), "mp3"), Some(("What\u{FF1F} \u{29F8} Why".to_string(), song.id.clone())));
+    }
 
+    #[test]
+    fn orders_songs_without_positions_by_line() {
+        let manifest = format!("playlist_title=Mix{SEP_CHAR}url=\ntitle=A{SEP_CHAR}id=a{SEP_CHAR}url=\ntitle=B{SEP_CHAR}id=b{SEP_CHAR}url=\n");
+        let parsed = parse_manifest(manifest.as_bytes()).unwrap();
+
+        assert_eq!(parsed.songs.iter().map(|f| f.position).collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn sanitizes_dir_name() {
+        assert_eq!(sanitize_dir_name("Rock/Metal: Best?"), "Rock_Metal_ Best_");
+        assert_eq!(sanitize_dir_name(" ..Hidden mix.. "), "Hidden mix");
+        assert_eq!(sanitize_dir_name("con"), "con_");
+        assert_eq!(sanitize_dir_name("Aux.mix"), "Aux.mix_");
+        assert_eq!(sanitize_dir_name("..."), "playlist");
+    }
+
+    #[test]
+    fn truncates_long_dir_names_on_char_boundary() {
+        let name = sanitize_dir_name(&"\u{00e9}".repeat(MAX_DIR_NAME_LEN));
+
+        assert_eq!(name, "\u{00e9}".repeat(MAX_DIR_NAME_LEN / 2));
+    }
+}
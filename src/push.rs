@@ -1,10 +1,14 @@
 use std::env;
+use std::fs;
+use std::fs::File;
 use std::io;
 use std::io::ErrorKind;
 
+use regex::Regex;
 
 use crate::adb;
-use crate::Args;
+use crate::cover::FOLDER_COVER;
+use crate::{parse_manifest, playlist_filename, run_summary, unique_songs, with_exit_code, Args, Manifest, EXIT_CONFIG_ERROR, EXIT_TOOL_MISSING};
 
 //use adb::AndroidDevice;
 use adb::DeviceManager;
@@ -13,94 +17,130 @@ use adb::DeviceStatus;
 
 use std::io::Error;
 
-use colored::Colorize;
 
 use crate::pl_update_fatal_error;
-//use crate::pl_update_error;
-use crate::pl_update_warn;
 
-pub(crate) fn pl_push(options: Args, device_id: Option<String>) -> std::io::Result<()> {
-    let mut device_manager;
-    let devices;
-    let target_device; //Initialize the value to stop the compiler from complaining
 
+/// Turns a pattern where * matches any characters and ? matches one into a regex matching whole file names.
+fn glob_regex(pattern: &str) -> Result<Regex, Error> {
+    let regex: String = pattern.chars().map(|c| match c {
+        '*' => ".*".to_string(),
+        '?' => ".".to_string(),
+        c => regex::escape(&c.to_string()),
+    }).collect();
 
-    macro_rules! pl_update_println {
-        ($($x:expr),*) => {
-            if !options.quiet {
-                println!("[pl-update] {}",
-                format! (
-                        $(
-                            $x,
-                        )*
-                    )
-                )
-            }
-        };
+    match Regex::new(&format!("^{}$", regex)) {
+        Ok(val) => Ok(val),
+        Err(e) => {
+            pl_update_fatal_error!(ErrorKind::InvalidInput, "\"{}\" is not a valid pattern: {}", pattern, e);
+        }
     }
-    
-    
+}
+
+
+/// The files in the current directory that are pushed for playlist: its songs, its playlist file and its folder art
+/// if it writes one, with files matching include added and files matching exclude left out.
+fn files_to_push(playlist: &Manifest, include: &[String], exclude: &[String]) -> Result<Vec<String>, Error> {
+    let include = include.iter().map(|f| glob_regex(f)).collect::<Result<Vec<_>, _>>()?;
+    let exclude = exclude.iter().map(|f| glob_regex(f)).collect::<Result<Vec<_>, _>>()?;
+
+    let mut files: Vec<String> = unique_songs(&playlist.songs).iter().map(|f| playlist.song_filename(f)).collect();
+    files.push(playlist_filename(&playlist.title));
 
-    macro_rules! pl_update_vprintln {
-        ($($x:expr),*) => {
-            if options.verbose {
-                println!("{} [pl-update] {}", "DEBUG:".blue(),
-                format! (
-                    $(
-                        $x,
-                    )*
-                )
-
-                )
+    if playlist.cover_rules.folder {
+        files.push(FOLDER_COVER.to_string());
+    }
+
+    if !include.is_empty() {
+        for file_entry in fs::read_dir(".")? {
+            let file_name = file_entry?.file_name().to_string_lossy().to_string();
+
+            if include.iter().any(|f| f.is_match(&file_name)) && !files.contains(&file_name) {
+                files.push(file_name);
             }
-        };
+        }
+    }
+
+    //Songs that failed to download have no file, and directories such as the metadata directory are never pushed.
+    files.retain(|file_name| fs::metadata(file_name).is_ok_and(|f| f.is_file()) && !exclude.iter().any(|f| f.is_match(file_name)));
+
+    Ok(files)
+}
+
+
+pub(crate) fn pl_push(options: Args, device_id: Option<String>, include: Vec<String>, exclude: Vec<String>) -> std::io::Result<()> {
+    let mut device_manager;
+    let target_device; //Initialize the value to stop the compiler from complaining
+
+
+    //Problems with the playlist are not device errors.
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            let e = Error::new(err.kind(), format!("Could not open playlist manifest, push is run in the playlist directory: {err}"));
+            return Err(with_exit_code(e, EXIT_CONFIG_ERROR));
+        }
+    };
+
+    let playlist = parse_manifest(manifest).map_err(|e| with_exit_code(e, EXIT_CONFIG_ERROR))?;
+    let files = files_to_push(&playlist, &include, &exclude).map_err(|e| with_exit_code(e, EXIT_CONFIG_ERROR))?;
+
+    pl_update_vprintln!("Files to push: {:?}", files);
+
+    if files.is_empty() {
+        let e = Error::new(ErrorKind::NotFound, format!("Playlist \"{}\" has no files to push.", playlist.title));
+        return Err(with_exit_code(e, EXIT_CONFIG_ERROR));
     }
 
+
     let ret = DeviceManager::new("adb");
 
-    
-    
+
     match ret {
         Err(e) => {
-            pl_update_fatal_error!(e.kind(), "Adb tool could not be launched, check that it is installed and is accessible (ie. in the system path or working directory)\nReason:{e}");
+            let e = Error::new(e.kind(), format!("Adb tool could not be launched, check that it is installed and is accessible (ie. in the system path or working directory)\nReason:{e}"));
+            return Err(with_exit_code(e, EXIT_TOOL_MISSING));
         },
         Ok(t) => device_manager = t,
     }
     
     pl_update_vprintln!("Found Android Debug Bridge version {}", device_manager.get_version());
-    
+    run_summary().tools.insert("adb".to_string(), device_manager.get_version());
 
 
-    devices = device_manager.get_devices()?;
+    let devices = device_manager.get_devices()?;
 
     if devices.is_empty() {
         pl_update_fatal_error!(ErrorKind::NotFound, "No devices were available.");
 
     }
 
-    if device_id.is_some() {
+    if let Some(device_id) = &device_id {
         let mut found_device = None;
 
         for device in devices {
-            if device.identifier == device_id.clone().unwrap() {
-                if found_device.is_none() {
-                    found_device = Some(device);
+            if device.identifier == *device_id {
+                if let Some(found) = &found_device {
+                    pl_update_fatal_error!(ErrorKind::AlreadyExists, "Devices {} and {} have duplicate ids.", found, device);
                 } else {
-                    pl_update_fatal_error!(ErrorKind::AlreadyExists, "Devices {} and {} have duplicate ids.", found_device.unwrap(), device);
+                    found_device = Some(device);
                 }
             }
         }
-        
 
-       
-        target_device = found_device.unwrap();
-        
+
+        target_device = match found_device {
+            Some(val) => val,
+            None => {
+                pl_update_fatal_error!(ErrorKind::NotFound, "No device with id {} was found.", device_id);
+            }
+        };
 
 
     } else {
 
         if devices.len() == 1 {
-            target_device = devices.get(0).expect("device at index 0 should exist").clone();
+            target_device = devices.first().expect("device at index 0 should exist").clone();
         } else {
 
 
@@ -110,12 +150,9 @@ pub(crate) fn pl_push(options: Args, device_id: Option<String>) -> std::io::Resu
 
             pl_update_println!("\nMore than one device was detected, please select from the following list:");
             pl_update_println!("No\t\t\tIdentifier\t\t\tModel");
-            let mut i: u8 = 1;
-            for device in &devices {
-                pl_update_println!("{}\t\t\t{}\t\t\t{}", i, device.identifier, device.model);
-                i += 1;
+            for (i, device) in devices.iter().enumerate() {
+                pl_update_println!("{}\t\t\t{}\t\t\t{}", i + 1, device.identifier, device.model);
             }
-            
 
 
             loop {
@@ -149,7 +186,6 @@ pub(crate) fn pl_push(options: Args, device_id: Option<String>) -> std::io::Resu
     pl_update_println!("Device {} selected for use.", target_device);
 
 
-
     let status = device_manager.get_device_status(target_device.clone())?;
 
     match status {
@@ -171,9 +207,11 @@ pub(crate) fn pl_push(options: Args, device_id: Option<String>) -> std::io::Resu
     }
 
     let current_dir= env::current_dir()?;
-    let current_dir_str = current_dir.to_str().unwrap();
+    let dir_name = current_dir.file_name().map_or(playlist.title.clone(), |f| f.to_string_lossy().to_string());
+
+    pl_update_println!("Pushing {} files of \"{}\"...", files.len(), playlist.title);
 
-    let ret = device_manager.push_dir(current_dir_str, "/storage/E23F-11FD/music", target_device.clone())?;
+    let ret = device_manager.push_files(&files, "/storage/E23F-11FD/music", &dir_name, target_device.clone())?;
     println!("[adb] {}", ret);
 
 
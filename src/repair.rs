@@ -1,15 +1,23 @@
 
 use crate::parse_manifest;
+use crate::history::record_playlist;
+use crate::parse_song_filename;
+use crate::recorded_extractor;
+use crate::UNKNOWN_EXTRACTOR;
+use crate::apply_tags;
+use crate::sanitize_title;
+use crate::split_track_number;
+use crate::tags::read_tags;
+use crate::snapshots::{snapshot_manifest, snapshot_path};
+use crate::update_manifest;
 
 use crate::Args;
-use crate::SEP_CHAR;
-use colored::Colorize;
+use crate::Manifest;
+use crate::Song;
 //mod main;
 
-use crate::pl_update_warn;
 use crate::pl_update_fatal_error;
 
-use chrono::Local;
 use std::env::set_current_dir;
 use std::fs;
 use std::fs::read_dir;
@@ -17,47 +25,10 @@ use std::fs::File;
 use std::io;
 use std::io::Error;
 use std::io::ErrorKind;
-use std::io::Write;
-use std::time::SystemTime;
 
 
+pub(crate) fn pl_repair(options: Args, playlist_name: Option<String>, offline: bool) -> std::io::Result<()> {
 
-pub(crate) fn pl_repair(options: Args, playlist_name: Option<String>) -> std::io::Result<()> {
-
-
-    const FILE_EXT: &str = ".mp3";
-
-  
-
-
-    // macro_rules! pl_update_println {
-    //     ($($x:expr),*) => {
-    //         if !options.quiet {
-    //             println!("[pl-update] {}",
-    //             format! (
-    //                     $(
-    //                         $x,
-    //                     )*
-    //                 )
-    //             )
-    //         }
-    //     };
-    // }
-
-    macro_rules! pl_update_vprintln {
-        ($($x:expr),*) => {
-            if options.verbose {
-                println!("{} [pl-update] {}", "DEBUG:".blue(),
-                format! (
-                    $(
-                        $x,
-                    )*
-                )
-
-                )
-            }
-        };
-    }
 
     if playlist_name.is_some() {
         match set_current_dir(playlist_name.clone().unwrap()) {
@@ -69,93 +40,209 @@ pub(crate) fn pl_repair(options: Args, playlist_name: Option<String>) -> std::io
         }
     } 
 
-    const YOUTUBE_ID_LEN: usize = 11; //The length of a youtube ID, these get placed at the end of every file name so...
-                                    //they need to be removed before being set to YTDL.
+    let old_playlist_filename = match snapshot_manifest() {
+        Ok(val) => val,
+        Err(e) => {
+            if e.kind() == ErrorKind::NotFound {
+                pl_update_fatal_error!(ErrorKind::NotFound, "The directory does not have an existing manifest, either run pl-update with the INIT command, or rename an old manifest to 'playlist.manifest'");
+
+            } else {
+                pl_update_fatal_error!(e.kind(), "Could not rename playlist.manifest: {}", e);
+            }
+        }
+    };
+
+    let mut playlist = parse_manifest(File::open(snapshot_path(&old_playlist_filename))?)?;
+    record_playlist(&playlist);
+    let old_songs = std::mem::take(&mut playlist.songs);
+
 
     //This list contains all files in the target directory.
     let directory_entry = read_dir(".")?.collect::<Result<Vec<_>, io::Error>>().unwrap();
 
-    let mut song_ids: Vec<String> = Vec::new();
-    let mut song_names = Vec::new();
+    let mut song_files: Vec<(String, String, String)> = Vec::new(); //File name, song name, song id
 
-    //let mut songs_in_dir = 0;
 
-    
-   
     for file_entry in directory_entry {
 
         let file_name = file_entry.file_name().into_string().expect("File name was not string!");
-        let song_name: &str;
-        let remainder: &str;
-
 
 
-        if !file_name.ends_with(FILE_EXT){
+        if !file_name.ends_with(&format!(".{}", playlist.file_ext())) {
             pl_update_warn!("Loose file \"{}\" in directory.",  file_name);
             continue;
         }
 
 
-        if file_name.contains('[') {// If it has an opening bracket
-            
-            
-            (song_name, remainder) = file_name.split_at(file_name.rfind('[').unwrap());
-            
-            
-
-            if remainder.len() != FILE_EXT.len() + YOUTUBE_ID_LEN + 2 {
+        match parse_song_filename(&file_name, playlist.file_ext()) {
+            Some((song_name, song_id)) => {
+                song_files.push((file_name, song_name, song_id));
+            },
+            None => {
                 pl_update_warn!("Non yt-dl file \"{}\" in directory.", file_name);
-                continue;
             }
+        }
 
-            
-        } else {
-            pl_update_warn!("Non yt-dl file \"{}\" in directory.", file_name);
-            continue;
+    }
 
-        } 
+    
+    pl_update_vprintln!("Song files: {:?}", song_files);
 
-        //songs_in_dir += 1;
 
-       
+    let remote_songs = if offline || playlist.sources.is_empty() {
+        Vec::new()
+    } else {
+        pl_update_println!("Fetching remote playlist to recover titles and positions...");
+        fetch_remote_songs(&playlist, &options)
+    };
 
-        song_ids.push((&remainder[1..remainder.len() - FILE_EXT.len() - 1]).to_string());
-        song_names.push((&song_name[0..song_name.len()-1]).to_string());
+    let mut original_filenames = Vec::new();
+    let mut tagged_count = 0;
+    let mut remote_count = 0;
 
-    }
+    for (file_name, song_name, song_id) in song_files {
+        let mut song = Song::new(song_name, song_id, None, 0, Some(UNKNOWN_EXTRACTOR.to_string()));
 
-    
-    pl_update_vprintln!("Song names: {:?}", song_names);
-    pl_update_vprintln!("Song Ids: {:?}", song_ids);
+        //Track numbered files are named "<position> - <title> [<id>].<ext>"
+        if playlist.track_numbers {
+            if let (Some(number), rest) = split_track_number(&song.title) {
+                song.position = number;
+                song.title = rest.to_string();
+            }
+        }
 
+        match read_tags(&file_name) {
+            Ok(Some(tags)) => {
+                if apply_tags(&mut song, &tags) {
+                    tagged_count += 1;
+                }
+            },
+            Ok(None) => {},
+            Err(e) => pl_update_warn!("Could not read tags of \"{}\": {}", file_name, e),
+        }
 
-    let time: chrono::DateTime<Local> =  SystemTime::now().into();
-    let old_playlist_filename = format!("playlist-{}.manifest", time.format("%Y-%m-%dT%H%M%S%.f"));
+        if let Some(remote_song) = remote_songs.iter().find(|f| **f == song) {
+            if sanitize_title(&remote_song.title) == sanitize_title(&song.title) {
+                song.title = remote_song.title.clone();
+            }
+            song.position = remote_song.position;
+            song.url = remote_song.url.clone().or(song.url);
+            song.extractor = remote_song.extractor.clone();
+            song.artist = remote_song.artist.clone().or(song.artist);
+            song.duration = remote_song.duration.or(song.duration);
+            song.source = remote_song.source.clone();
+            remote_count += 1;
+        }
 
-    match fs::rename("playlist.manifest", old_playlist_filename.clone()) {
-        Ok(()) => (),
-        Err(e) => {
-            if e.kind() == ErrorKind::NotFound {
-                pl_update_fatal_error!(ErrorKind::NotFound, "The directory does not have an existing manifest, either run pl-update with the INIT command, or rename an old manifest to 'playlist.manifest'");
+        //Recorded even when unknown, as a song with no extractor is taken to be from an old youtube only manifest.
+        if song.extractor.as_deref().is_none_or(|f| f == UNKNOWN_EXTRACTOR) {
+            song.extractor = Some(recorded_extractor(&song.id, song.url.as_deref()));
+        }
 
-            } else {
-                pl_update_fatal_error!(e.kind(), "Could not rename playlist.manifest: {}", e);
-            }
+        //The file is unchanged, so its loudness does not need to be measured again.
+        //Pins are kept too, or the next update would delete songs added by hand.
+        if let Some(old_song) = old_songs.iter().find(|f| **f == song) {
+            song.loudness = old_song.loudness;
+            song.peak = old_song.peak;
+            song.source = song.source.or(old_song.source.clone());
+            song.pinned = old_song.pinned;
+            song.failed = old_song.failed.clone();
         }
+
+        song.url = song.url(); //Fall back to the canonical url so the manifest has as few empty urls as possible
+
+        if song.url.is_none() {
+            pl_update_warn!("No url could be found for \"{}\" [{}], it will not be possible to download it again.", song.title, song.id);
+        }
+
+        original_filenames.push((file_name, song));
     }
 
-    let (_, playlist_title, playlist_url) = parse_manifest(File::open(old_playlist_filename)?)?;
+    //Songs added by hand and failed downloads have no file to be rebuilt from, but are kept at their old positions.
+    //Otherwise the next update would delete the songs added by hand, and try the failed ones as if they were new.
+    let kept_songs: Vec<Song> = old_songs.iter()
+        .filter(|f| (f.pinned || f.failed.is_some()) && !original_filenames.iter().any(|(_, song)| song == *f))
+        .cloned().collect();
 
-    let mut manifest = File::create_new("playlist.manifest")?;
+    //Songs with no known position go to the end of the playlist.
+    let mut next_position = original_filenames.iter().map(|(_, f)| f.position).chain(kept_songs.iter().map(|f| f.position)).max().unwrap_or(0);
 
-    manifest.write(format!("playlist_title={}{SEP_CHAR}url={}\n", playlist_title, playlist_url).as_bytes())?;
+    for (_, song) in original_filenames.iter_mut().filter(|(_, f)| f.position == 0) {
+        next_position += 1;
+        song.position = next_position;
+    }
 
-    for i in 0..song_names.len() {
-        manifest.write(format!("title={}{SEP_CHAR}id={}{SEP_CHAR}url=\n", song_names.get(i).unwrap(), song_ids.get(i).unwrap()).as_bytes())?;
+    playlist.songs = original_filenames.iter().map(|(_, f)| f.clone()).chain(kept_songs.iter().cloned()).collect();
+    playlist.songs.sort_by_key(|f| f.position);
+
+
+    //Positions may have been recovered from the remote playlist, so track numbered files are renamed to match.
+    for (file_name, song) in &original_filenames {
+        let new_filename = playlist.song_filename(song);
+        if *file_name != new_filename {
+            pl_update_vprintln!("Renaming \"{}\" to \"{}\"", file_name, new_filename);
+            fs::rename(file_name, new_filename)?;
+        }
     }
 
 
+    let manifest = File::create_new("playlist.manifest")?;
+
+    playlist.write(manifest)?;
+
+    pl_update_println!("Rebuilt manifest with {} songs. {} titles recovered from tags, {} songs found in the remote playlist, {} songs without a file kept.",
+        playlist.songs.len(), tagged_count, remote_count, kept_songs.len());
 
 
     Ok(())
-}
\ No newline at end of file
+}
+
+
+/// Queries the remote playlist, returning an empty list if it could not be fetched.
+fn fetch_remote_songs(playlist: &Manifest, options: &Args) -> Vec<Song> {
+    const REMOTE_MANIFEST: &str = "playlist-remote.manifest";
+
+    let remote_songs = File::create(REMOTE_MANIFEST)
+        .and_then(|manifest| update_manifest(manifest, playlist, options))
+        .and_then(|_| parse_manifest(File::open(REMOTE_MANIFEST)?));
+
+    let _ = fs::remove_file(REMOTE_MANIFEST);
+
+    match remote_songs {
+        Ok(remote) => remote.songs,
+        Err(e) => {
+            pl_update_warn!("Could not fetch the remote playlist, titles and positions will be taken from the files only: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::DownloadFailure;
+    use crate::tests::{in_temp_dir, options_without_ffmpeg};
+
+    #[test]
+    fn keeps_pinned_and_failed_songs_without_files() {
+        in_temp_dir("repair", || {
+            let mut playlist = Manifest::new("Mix".to_string(), String::new());
+            playlist.songs = ["a", "b", "c", "d"].iter().enumerate()
+                .map(|(i, id)| Song::new(format!("Song {}", id), id.to_string(), None, i + 1, None)).collect();
+            playlist.songs[0].pinned = true;
+            playlist.songs[1].pinned = true;
+            playlist.songs[2].failed = Some(DownloadFailure { permanent: true, reason: "Video unavailable".to_string() });
+            playlist.write(File::create("playlist.manifest").unwrap()).unwrap();
+            fs::write(playlist.song_filename(&playlist.songs[0]), "audio").unwrap();
+
+            pl_repair(options_without_ffmpeg(), None, true).unwrap();
+
+            let repaired = parse_manifest(File::open("playlist.manifest").unwrap()).unwrap();
+            //The file of a has no track number, so it goes to the end.
+            assert_eq!(repaired.songs.iter().map(|f| (f.id.as_str(), f.position, f.pinned, f.failed.is_some())).collect::<Vec<_>>(),
+                [("b", 2, true, false), ("c", 3, false, true), ("a", 4, true, false)]);
+        });
+    }
+}
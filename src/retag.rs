@@ -0,0 +1,200 @@
+use regex::Regex;
+use std::{env::set_current_dir, fs::{self, File}, io::{Error, ErrorKind}};
+
+use crate::{find_ffmpeg, interrupted, parse_manifest, pl_update_fatal_error, rewrite_tags, save_manifest, unique_songs, Args, Manifest, Song, TagRules};
+use crate::history::record_playlist;
+use crate::tags::read_tags;
+
+
+fn compile_rules(rules: &TagRules) -> Result<(Option<Regex>, Vec<Regex>), Error> {
+    let split = match &rules.split {
+        Some(pattern) => match Regex::new(pattern) {
+            Ok(val) => {
+                let names: Vec<_> = val.capture_names().flatten().collect();
+                if !names.contains(&"artist") || !names.contains(&"title") {
+                    pl_update_fatal_error!(ErrorKind::InvalidInput, "Split rule \"{}\" must have groups named \"artist\" and \"title\".", pattern);
+                }
+                Some(val)
+            },
+            Err(e) => {
+                pl_update_fatal_error!(ErrorKind::InvalidInput, "Split rule \"{}\" is not a valid regex: {}", pattern, e);
+            }
+        },
+        None => None,
+    };
+
+    let mut strip = Vec::new();
+
+    for pattern in &rules.strip {
+        match Regex::new(pattern) {
+            Ok(val) => strip.push(val),
+            Err(e) => {
+                pl_update_fatal_error!(ErrorKind::InvalidInput, "Strip rule \"{}\" is not a valid regex: {}", pattern, e);
+            }
+        }
+    }
+
+    Ok((split, strip))
+}
+
+
+/// The tags the playlist's rules give a song.
+fn normalized_tags(playlist: &Manifest, song: &Song, split: Option<&Regex>, strip: &[Regex]) -> Vec<(&'static str, String)> {
+    let mut title = song.title.clone();
+    let mut artist = song.artist.clone();
+
+    for pattern in strip {
+        title = pattern.replace_all(&title, "").to_string();
+    }
+
+    if let Some(captures) = split.and_then(|f| f.captures(&title)) {
+        if let (Some(split_artist), Some(split_title)) = (captures.name("artist"), captures.name("title")) {
+            artist = Some(split_artist.as_str().trim().to_string());
+            title = split_title.as_str().to_string();
+        }
+    }
+
+    let mut tags = vec![("title", title.trim().to_string())];
+
+    if let Some(artist) = artist {
+        tags.push(("artist", artist));
+    }
+
+    if playlist.tag_rules.album {
+        tags.push(("album", playlist.title.clone()));
+    }
+
+    if playlist.tag_rules.track_number {
+        tags.push(("track", song.position.to_string()));
+    }
+
+    tags
+}
+
+
+/// Rewrites the tags of every song in the playlist directory that does not match the playlist's tag rules.
+/// Returns the number of files that were changed.
+pub(crate) fn apply_tag_rules(playlist: &Manifest, options: &Args) -> Result<usize, Error> {
+    if playlist.tag_rules.is_empty() {
+        return Ok(0);
+    }
+
+    let (split, strip) = compile_rules(&playlist.tag_rules)?;
+
+    let ffmpeg_command = options.ffmpeg_location.clone().unwrap_or("ffmpeg".to_string());
+    find_ffmpeg(&ffmpeg_command)?;
+
+    let mut changed = 0;
+
+    //Songs that appear more than once are tagged with their first position.
+    for song in unique_songs(&playlist.songs) {
+        if interrupted() {
+            break;
+        }
+
+        let filename = playlist.song_filename(&song);
+
+        if fs::metadata(&filename).is_err() {
+            continue;
+        }
+
+        let wanted = normalized_tags(playlist, &song, split.as_ref(), &strip);
+
+        let current = match read_tags(&filename) {
+            Ok(val) => val.unwrap_or_default(),
+            Err(e) => {
+                pl_update_warn!("Could not read tags of \"{}\": {}", filename, e);
+                continue;
+            }
+        };
+
+        let up_to_date = wanted.iter().all(|(key, value)| {
+            let current_value = match *key {
+                "title" => current.title.clone(),
+                "artist" => current.artist.clone(),
+                "album" => current.album.clone(),
+                "track" => current.track.clone().map(|f| f.split('/').next().unwrap_or_default().to_string()), //Can be "<track>/<total>"
+                _ => None,
+            };
+            current_value.as_ref() == Some(value)
+        });
+
+        if up_to_date {
+            continue;
+        }
+
+        pl_update_vprintln!("Retagging \"{}\" with {:?}", filename, wanted);
+
+        rewrite_tags(&ffmpeg_command, &filename, &filename, &wanted)?;
+        changed += 1;
+    }
+
+    Ok(changed)
+}
+
+
+pub(crate) fn pl_retag(options: Args, playlist_name: Option<String>, split: Option<String>, strip: Vec<String>, album: bool, track_number: bool, clear: bool) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
+        }
+    };
+
+    let mut playlist = parse_manifest(manifest)?;
+    record_playlist(&playlist);
+    let old_rules = playlist.tag_rules.header_fields();
+
+
+    if clear {
+        playlist.tag_rules = TagRules::default();
+    }
+
+    if let Some(split) = split {
+        playlist.tag_rules.split = Some(if split == "default" { TagRules::DEFAULT_SPLIT.to_string() } else { split });
+    }
+
+    for pattern in strip {
+        let pattern = if pattern == "default" { TagRules::DEFAULT_STRIP.to_string() } else { pattern };
+        if !playlist.tag_rules.strip.contains(&pattern) {
+            playlist.tag_rules.strip.push(pattern);
+        }
+    }
+
+    playlist.tag_rules.album |= album;
+    playlist.tag_rules.track_number |= track_number;
+
+
+    if playlist.tag_rules.header_fields() != old_rules {
+        compile_rules(&playlist.tag_rules)?; //Check the new rules before they are saved
+
+        save_manifest(&playlist)?;
+        pl_update_println!("Saved tag rules for \"{}\".", playlist.title);
+    }
+
+
+    if playlist.tag_rules.is_empty() {
+        pl_update_println!("Playlist \"{}\" has no tag rules.", playlist.title);
+        return Ok(());
+    }
+
+    pl_update_println!("Applying tag rules...");
+    let changed = apply_tag_rules(&playlist, &options)?;
+    pl_update_println!("Retagged {} files.", changed);
+
+
+    Ok(())
+}
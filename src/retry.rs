@@ -0,0 +1,97 @@
+use std::time::{Duration, SystemTime};
+
+use crate::SEP_CHAR;
+
+
+/// Why a song could not be downloaded.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DownloadFailure {
+    /// The song is gone or cannot be reached, so there is no point retrying it.
+    pub permanent: bool,
+    /// The error yt-dlp gave.
+    pub reason: String,
+}
+
+impl DownloadFailure {
+    /// Errors that will not go away by trying again. Anything else, such as timeouts, 5xx and 429 responses, is retried.
+    const PERMANENT_ERRORS: [&str; 14] = ["video unavailable", "private video", "has been removed", "is not available", "has been terminated",
+        "copyright", "unsupported url", "members-only", "join this channel", "sign in to confirm your age", "http error 404", "http error 410",
+        "does not exist", "premieres in"];
+
+    /// Classifies an "ERROR:" line printed by yt-dlp.
+    pub fn from_error(error: &str) -> Self {
+        let message = error.trim().strip_prefix("ERROR:").unwrap_or(error).trim();
+
+        //Drop the "[extractor] id: " prefix
+        let reason = match message.strip_prefix('[').and_then(|f| f.split_once("]")).and_then(|f| f.1.split_once(": ")) {
+            Some((_, reason)) => reason.trim(),
+            None => message,
+        };
+
+        let lowercase = reason.to_lowercase();
+
+        //YouTube reports rate limited sessions as unavailable videos.
+        let permanent = !lowercase.contains("try again later") && Self::PERMANENT_ERRORS.iter().any(|f| lowercase.contains(f));
+
+        DownloadFailure { permanent, reason: reason.replace(SEP_CHAR, " ") }
+    }
+}
+
+
+/// The wait before retry number attempt, doubling each time with up to half the base delay of jitter
+/// so that threads rate limited together do not all retry at once.
+pub(crate) fn retry_delay(base_delay: u64, attempt: u32) -> Duration {
+    let base_millis = base_delay.saturating_mul(1000);
+    let delay = base_millis.saturating_mul(1 << (attempt - 1).min(16));
+
+    let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |f| f.subsec_nanos() as u64);
+    let jitter = if base_millis > 1 { nanos % (base_millis / 2) } else { 0 };
+
+    Duration::from_millis(delay.saturating_add(jitter))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_permanent_errors() {
+        let failure = DownloadFailure::from_error("ERROR: [youtube] dQw4w9WgXcQ: Video unavailable. This video has been removed by the uploader");
+
+        assert_eq!(failure, DownloadFailure { permanent: true, reason: "Video unavailable. This video has been removed by the uploader".to_string() });
+        assert!(DownloadFailure::from_error("ERROR: [youtube] abc: Private video. Sign in if you've been granted access").permanent);
+        assert!(DownloadFailure::from_error("ERROR: Unsupported URL: https://example.com").permanent);
+    }
+
+    #[test]
+    fn classifies_transient_errors() {
+        let failure = DownloadFailure::from_error("ERROR: [youtube] dQw4w9WgXcQ: Unable to download webpage: HTTP Error 429: Too Many Requests");
+
+        assert_eq!(failure, DownloadFailure { permanent: false, reason: "Unable to download webpage: HTTP Error 429: Too Many Requests".to_string() });
+        assert!(!DownloadFailure::from_error("ERROR: [youtube] abc: Read timed out.").permanent);
+        //Rate limited sessions are reported as unavailable videos.
+        assert!(!DownloadFailure::from_error("ERROR: [youtube] abc: Video unavailable. This content isn't available, try again later.").permanent);
+    }
+
+    #[test]
+    fn failure_reason_has_no_separator() {
+        let failure = DownloadFailure::from_error(&format!("ERROR: [youtube] abc: Bad{SEP_CHAR}reason"));
+
+        assert_eq!(failure.reason, "Bad reason");
+    }
+
+    #[test]
+    fn retry_delay_doubles_with_bounded_jitter() {
+        for attempt in 1..=4 {
+            let delay = retry_delay(2, attempt).as_millis() as u64;
+            let base = 2000 << (attempt - 1);
+
+            assert!((base..base + 1000).contains(&delay), "attempt {}: {}ms", attempt, delay);
+        }
+
+        assert_eq!(retry_delay(0, 3), Duration::ZERO);
+        assert!(retry_delay(u64::MAX, 40) >= Duration::from_millis(u64::MAX / 2));
+    }
+}
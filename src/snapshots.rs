@@ -0,0 +1,233 @@
+use chrono::{Local, NaiveDateTime};
+use std::{env::set_current_dir, fs::{self, read_dir, remove_file, File}, io::{Error, ErrorKind}, path::{Path, PathBuf}, time::SystemTime};
+
+use crate::history::{read_history, record_playlist};
+use crate::{download, parse_manifest, playlist_filename, pl_update_fatal_error, rename_changed_files, run_summary, save_downloads, save_manifest, unique_songs, write_playlist_file, Args, Song, METADATA_DIR};
+
+
+const SNAPSHOT_TIME_FORMAT: &str = "%Y-%m-%dT%H%M%S%.f";
+
+
+
+/// The time in the name of a snapshot, or None if filename is not a snapshot.
+fn snapshot_time(filename: &str) -> Option<NaiveDateTime> {
+    let time = filename.strip_prefix("playlist-")?.strip_suffix(".manifest")?;
+    NaiveDateTime::parse_from_str(time, SNAPSHOT_TIME_FORMAT).ok()
+}
+
+
+pub(crate) fn snapshot_path(filename: &str) -> PathBuf {
+    Path::new(METADATA_DIR).join(filename)
+}
+
+
+/// Moves snapshots left in the playlist directory by older versions into the metadata directory.
+fn migrate_snapshots() -> Result<(), Error> {
+    for file_entry in read_dir(".")? {
+        let file_name = file_entry?.file_name().to_string_lossy().to_string();
+
+        if snapshot_time(&file_name).is_some() {
+            fs::rename(&file_name, snapshot_path(&file_name))?;
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Moves playlist.manifest aside to a timestamped snapshot so a new one can be written, returning the snapshot's filename.
+/// The first snapshot of a run is the playlist as it was before the run, it is recorded in the run's history.
+pub(crate) fn snapshot_manifest() -> Result<String, Error> {
+    let time: chrono::DateTime<Local> =  SystemTime::now().into();
+    let snapshot_filename = format!("playlist-{}.manifest", time.format(SNAPSHOT_TIME_FORMAT));
+
+    fs::create_dir_all(METADATA_DIR)?;
+    migrate_snapshots()?;
+
+    fs::rename("playlist.manifest", snapshot_path(&snapshot_filename))?;
+    run_summary().snapshot.get_or_insert(snapshot_filename.clone());
+
+    Ok(snapshot_filename)
+}
+
+
+/// The snapshots kept in the metadata directory of playlist_dir, oldest first.
+fn list_snapshots(playlist_dir: &Path) -> Result<Vec<(String, NaiveDateTime)>, Error> {
+    let mut snapshots = Vec::new();
+
+    let entries = match read_dir(playlist_dir.join(METADATA_DIR)) {
+        Ok(val) => val,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(snapshots),
+        Err(e) => return Err(e),
+    };
+
+    for file_entry in entries {
+        let file_name = file_entry?.file_name().to_string_lossy().to_string();
+
+        if let Some(time) = snapshot_time(&file_name) {
+            snapshots.push((file_name, time));
+        }
+    }
+
+    snapshots.sort_by_key(|(_, time)| *time);
+
+    Ok(snapshots)
+}
+
+
+/// Deletes the snapshots of the playlist in playlist_dir beyond the newest keep, and those older than max_age days.
+/// A keep of 0 keeps any number of snapshots. Returns the number deleted.
+pub(crate) fn prune_snapshots(playlist_dir: &Path, keep: usize, max_age: Option<u64>) -> Result<usize, Error> {
+    let snapshots = list_snapshots(playlist_dir)?;
+    let now = Local::now().naive_local();
+
+    let excess = if keep == 0 { 0 } else { snapshots.len().saturating_sub(keep) };
+    let mut deleted = 0;
+
+    for (index, (file_name, time)) in snapshots.iter().enumerate() {
+        let expired = max_age.is_some_and(|f| (now - *time).num_days() >= f as i64);
+
+        if index < excess || expired {
+            remove_file(playlist_dir.join(METADATA_DIR).join(file_name))?;
+            deleted += 1;
+        }
+    }
+
+    Ok(deleted)
+}
+
+
+/// The filename of the snapshot named by a rollback argument: its number in the snapshots listing, or its filename.
+fn find_snapshot(snapshots: &[(String, NaiveDateTime)], name: &str) -> Result<String, Error> {
+    if let Ok(number) = name.parse::<usize>() {
+        match number.checked_sub(1).and_then(|f| snapshots.iter().rev().nth(f)) {
+            Some((file_name, _)) => return Ok(file_name.clone()),
+            None => {
+                pl_update_fatal_error!(ErrorKind::InvalidInput, "There is no snapshot {}, the playlist has {} snapshots.", number, snapshots.len());
+            }
+        }
+    }
+
+    let file_name = Path::new(name).file_name().map_or(name.to_string(), |f| f.to_string_lossy().to_string());
+
+    if !snapshots.iter().any(|(f, _)| *f == file_name) {
+        pl_update_fatal_error!(ErrorKind::NotFound, "There is no snapshot \"{}\", list them with the snapshots command.", name);
+    }
+
+    Ok(file_name)
+}
+
+
+pub(crate) fn pl_snapshots(_options: Args, playlist_name: Option<String>) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let snapshots = list_snapshots(Path::new("."))?;
+    let runs = read_history()?;
+
+    //The snapshots are the output of this command, so they are printed even when quiet.
+    if snapshots.is_empty() {
+        println!("The playlist has no snapshots.");
+    }
+
+    for (number, (file_name, time)) in snapshots.iter().rev().enumerate() {
+        let songs = File::open(snapshot_path(file_name)).and_then(parse_manifest).map_or(String::from("unreadable"), |f| format!("{} songs", unique_songs(&f.songs).len()));
+
+        let run = runs.iter().position(|f| f.snapshot.as_ref() == Some(file_name))
+            .map_or(String::new(), |f| format!(", before run {} ({})", f + 1, runs[f].command));
+
+        println!("{:>3}  {}  {}  {}{}", number + 1, time.format("%Y-%m-%d %H:%M:%S"), file_name, songs, run);
+    }
+
+
+    Ok(())
+}
+
+
+pub(crate) fn pl_rollback(options: Args, playlist_name: Option<String>, snapshot: String, download_missing: bool) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
+        }
+    };
+
+    let current_playlist = parse_manifest(manifest)?;
+    record_playlist(&current_playlist);
+
+    let snapshot_filename = find_snapshot(&list_snapshots(Path::new("."))?, &snapshot)?;
+    let mut playlist = parse_manifest(File::open(snapshot_path(&snapshot_filename))?)?;
+
+
+    let current_songs = unique_songs(&current_playlist.songs);
+    let songs = unique_songs(&playlist.songs);
+
+    let restored_songs: Vec<Song> = songs.iter().filter(|f| !current_songs.contains(f)).cloned().collect();
+    let dropped_songs: Vec<Song> = current_songs.iter().filter(|f| !songs.contains(f)).cloned().collect();
+
+    pl_update_println!("Rolling back \"{}\" to {}", current_playlist.title, snapshot_filename);
+
+    //Track numbers and titles may differ between the two.
+    rename_changed_files(&current_playlist, &playlist)?;
+
+    //The current manifest becomes a snapshot itself, so the rollback can be undone.
+    save_manifest(&playlist)?;
+
+    if playlist.title != current_playlist.title {
+        let _ = remove_file(playlist_filename(&current_playlist.title));
+    }
+
+
+    let missing_songs: Vec<Song> = songs.into_iter().filter(|f| fs::metadata(playlist.song_filename(f)).is_err()).collect();
+
+    if download_missing && missing_songs.iter().any(|f| f.url().is_some()) {
+        pl_update_println!("Downloading {} songs deleted since the snapshot...", missing_songs.len());
+        let failures = download(&missing_songs, &playlist.audio_format, &options)?;
+        save_downloads(&mut playlist, &missing_songs, &failures, &options)?;
+    } else {
+        if !missing_songs.is_empty() {
+            pl_update_warn!("{} songs of the snapshot have no file, run rollback with --download or verify --missing download to download them.", missing_songs.len());
+        }
+
+        write_playlist_file(&playlist)?;
+    }
+
+
+    if !dropped_songs.is_empty() {
+        pl_update_println!("{} songs added since the snapshot are no longer in the manifest, their files were kept. Run verify --orphans delete to delete them.", dropped_songs.len());
+    }
+
+    {
+        let mut summary = run_summary();
+        summary.added = restored_songs.iter().map(Into::into).collect();
+        summary.removed = dropped_songs.iter().map(Into::into).collect();
+    }
+
+    pl_update_println!("Rolled back to {}: {} songs restored, {} removed.", snapshot_filename, restored_songs.len(), dropped_songs.len());
+
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::{env::set_current_dir, fs::File, io::{Error, ErrorKind}};
+
+use crate::{parse_manifest, pl_update_fatal_error, save_manifest, unique_songs, Args};
+use crate::history::record_playlist;
+
+
+pub(crate) fn pl_sources(_options: Args, playlist_name: Option<String>, add: Vec<String>, remove: Vec<String>) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
+        }
+    };
+
+    let mut playlist = parse_manifest(manifest)?;
+    let old_sources = playlist.sources.clone();
+
+
+    //Songs with no source belong to the first, which may be about to change.
+    for song in playlist.songs.iter_mut() {
+        if song.source.is_none() {
+            song.source = old_sources.first().cloned();
+        }
+    }
+
+    for source in remove {
+        match playlist.sources.iter().position(|f| *f == source) {
+            Some(index) => {
+                playlist.sources.remove(index);
+                pl_update_println!("Removed source \"{}\", its songs will be deleted on the next update.", source);
+            },
+            None => pl_update_warn!("\"{}\" is not a source of the playlist.", source),
+        }
+    }
+
+    for source in add {
+        if playlist.sources.contains(&source) {
+            pl_update_warn!("\"{}\" is already a source of the playlist.", source);
+        } else {
+            pl_update_println!("Added source \"{}\", its songs will be downloaded on the next update.", source);
+            playlist.sources.push(source);
+        }
+    }
+
+    if playlist.sources.is_empty() {
+        pl_update_fatal_error!(ErrorKind::InvalidInput, "A playlist needs at least one source.");
+    }
+
+
+    if playlist.sources != old_sources {
+        //A playlist with one source does not record it for each song.
+        if playlist.sources.len() == 1 {
+            for song in playlist.songs.iter_mut() {
+                song.source = None;
+            }
+        }
+
+        record_playlist(&playlist);
+        save_manifest(&playlist)?;
+    }
+
+
+    //The list of sources is the output of this command, so it is printed even when quiet.
+    let songs = unique_songs(&playlist.songs);
+
+    for source in &playlist.sources {
+        println!("{} ({} songs)", source, songs.iter().filter(|f| playlist.song_source(f) == source).count());
+    }
+
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use std::{env::set_current_dir, fs::{self, File}, io::Error};
+
+use crate::{duplicate_songs, parse_manifest, pl_update_fatal_error, unique_songs, Args, LoudnessMode};
+use crate::cover::FOLDER_COVER;
+use crate::filters::{describe_excludes, describe_filters};
+use crate::tags::read_tags;
+
+
+fn format_duration(seconds: u64) -> String {
+    format!("{}h {}m {}s", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+
+pub(crate) fn pl_status(_options: Args, playlist_name: Option<String>, check_tags: bool) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
+        }
+    };
+
+    let playlist = parse_manifest(manifest)?;
+    let songs = unique_songs(&playlist.songs);
+
+
+    //Status is the output of this command, so it is printed even when quiet.
+    println!("Playlist:      {}", playlist.title);
+    if playlist.sources.is_empty() {
+        println!("Url:           none, songs are only added by hand");
+    } else if playlist.sources.len() == 1 {
+        println!("Url:           {}", playlist.sources[0]);
+    } else {
+        for source in &playlist.sources {
+            println!("Source:        {} ({} songs)", source, songs.iter().filter(|f| playlist.song_source(f) == source).count());
+        }
+    }
+
+    if let Some(remote_title) = playlist.remote_title.as_ref().filter(|f| **f != playlist.title) {
+        println!("Remote title:  {}", remote_title);
+    }
+
+    for (date, title) in &playlist.title_history {
+        println!("Renamed:       {} from \"{}\"", date, title);
+    }
+
+    let filters = describe_filters(&playlist.filters);
+    if !filters.is_empty() {
+        println!("Filters:       {}", filters.join(", "));
+    }
+
+    let excludes = describe_excludes(&playlist.excludes);
+    if !excludes.is_empty() {
+        println!("Excluding:     {}", excludes.join(", "));
+    }
+
+    for id in &playlist.excludes.ids {
+        println!("Skipped:       [{}]", id);
+    }
+
+    println!("Audio format:  {}", playlist.audio_format);
+    println!("Track numbers: {}", if playlist.track_numbers { "on" } else { "off" });
+    println!("Songs:         {} ({} unique)", playlist.songs.len(), songs.len());
+
+    let pinned = songs.iter().filter(|f| f.pinned).count();
+    if pinned > 0 {
+        println!("Pinned:        {} songs added by hand", pinned);
+    }
+
+    if !playlist.cover_rules.is_empty() {
+        let rules = &playlist.cover_rules;
+        let mut cover = Vec::new();
+
+        if rules.square {
+            cover.push("square".to_string());
+        }
+        if let Some(size) = rules.size {
+            cover.push(format!("at most {}px", size));
+        }
+        if let Some(image) = &rules.image {
+            cover.push(format!("\"{}\"{}", image, if rules.fallback { " for songs without art" } else { "" }));
+        }
+        if rules.folder {
+            cover.push(FOLDER_COVER.to_string());
+        }
+
+        println!("Cover art:     {}", cover.join(", "));
+    }
+
+    if playlist.loudness_mode != LoudnessMode::Off {
+        println!("Loudness:      {} ({} of {} songs analysed)", playlist.loudness_mode.as_str(), songs.iter().filter(|f| f.loudness.is_some()).count(), songs.len());
+    }
+
+    let known_durations: Vec<u64> = songs.iter().filter_map(|f| f.duration).collect();
+    if !known_durations.is_empty() {
+        println!("Duration:      {}{}", format_duration(known_durations.iter().sum()),
+            if known_durations.len() < songs.len() { format!(" ({} songs of unknown length)", songs.len() - known_durations.len()) } else { String::new() });
+    }
+
+    for (song, count) in duplicate_songs(&playlist.songs) {
+        println!("Duplicate:     \"{}\" [{}] appears {} times", song.title, song.id, count);
+    }
+
+
+    let mut present_filenames = Vec::new();
+
+    for song in &songs {
+        let filename = playlist.song_filename(song);
+
+        if fs::metadata(&filename).is_ok() {
+            present_filenames.push(filename);
+        } else {
+            match &song.failed {
+                Some(failed) if failed.permanent => println!("Missing:       \"{}\" [{}], unavailable: {}", song.title, song.id, failed.reason),
+                Some(failed) => println!("Missing:       \"{}\" [{}], retried on the next update: {}", song.title, song.id, failed.reason),
+                None => println!("Missing:       \"{}\" [{}]", song.title, song.id),
+            }
+        }
+    }
+
+    println!("Files:         {} present, {} missing", present_filenames.len(), songs.len() - present_filenames.len());
+
+
+    if check_tags {
+        let mut untagged = 0;
+        let mut without_cover = 0;
+
+        for filename in &present_filenames {
+            pl_update_vprintln!("Reading tags of \"{}\"", filename);
+
+            let tags = match read_tags(filename) {
+                Ok(Some(val)) => val,
+                Ok(None) => {
+                    println!("Unreadable:    \"{}\" is not in a format tags can be read from", filename);
+                    continue;
+                },
+                Err(e) => {
+                    pl_update_warn!("Could not read tags of \"{}\": {}", filename, e);
+                    continue;
+                }
+            };
+
+            if tags.title.is_none() || tags.artist.is_none() {
+                println!("No tags:       \"{}\"", filename);
+                untagged += 1;
+            }
+
+            if !tags.has_cover {
+                println!("No cover art:  \"{}\"", filename);
+                without_cover += 1;
+            }
+        }
+
+        println!("Tags:          {} files missing a title or artist, {} files without cover art", untagged, without_cover);
+    }
+
+
+    Ok(())
+}
@@ -0,0 +1,506 @@
+use clap::ValueEnum;
+use std::{collections::{BTreeMap, BTreeSet}, env::current_dir, fs::{self, File, OpenOptions}, io::{BufRead, BufReader, Error, ErrorKind, Write}, path::{Path, PathBuf}, thread::sleep, time::{Duration, Instant}};
+
+use crate::{guess_extractor, parse_manifest, pl_update_fatal_error, with_exit_code, Args, Song, EXIT_CONFIG_ERROR};
+
+
+/// Which playlists use each stored file, one "<extractor> <id> <ext>\t<playlist directory>" line per use.
+const REFS_FILE: &str = "refs";
+
+/// Held while the store is changed, so runs for different playlists do not lose each other's references.
+/// It holds the id of the process holding it, so a lock left by a run that crashed can be broken.
+const LOCK_FILE: &str = ".lock";
+
+const LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+
+/// How a playlist directory refers to a file in the store. In every mode, retagging, cover art and normalizing write a new
+/// file in place of the playlist's, so the store and other playlists keep the file as it was downloaded.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub(crate) enum LinkMode {
+    /// Share the file's data so it takes no extra space, the store must be on the same filesystem as the playlists.
+    /// A song the playlist changes stops sharing, and takes space of its own
+    #[default]
+    Hardlink,
+    /// Point to the file in the store, which must stay where it is. A song the playlist changes becomes a regular file
+    Symlink,
+    /// Copy the file, which only saves downloading it again
+    Copy,
+}
+
+
+/// A directory shared by every playlist, where each song is downloaded once.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub(crate) struct StoreOptions {
+    /// Download songs once into this directory, shared by every playlist, and link playlist directories to it.
+    /// Songs are kept by extractor and id, and recorded in a yt-dlp --download-archive file for each audio format.
+    /// A playlist that retags, changes the cover of or normalizes a song gets its own copy of it, whatever --store-link is
+    #[arg(long)]
+    pub(crate) store: Option<PathBuf>,
+
+    /// How playlist directories refer to the files in the store
+    #[arg(long, value_enum, default_value_t)]
+    pub(crate) store_link: LinkMode,
+}
+
+
+/// The archive yt-dlp records downloads in for songs of audio_format, if there is a store.
+pub(crate) fn archive_path(options: &StoreOptions, file_ext: &str) -> Option<PathBuf> {
+    options.store.as_ref().map(|f| f.join(format!("archive.{}.txt", file_ext)))
+}
+
+
+/// The extractor and id the store keeps song under, the extractor in lowercase as yt-dlp's archive has it.
+fn song_key(song: &Song) -> Option<(String, String)> {
+    let extractor = song.extractor_name().or_else(|| guess_extractor(&song.id, song.url.as_deref()).map(|f| f.to_lowercase()))?;
+    Some((extractor, song.id.clone()))
+}
+
+
+fn ref_key(extractor: &str, id: &str, file_ext: &str) -> String {
+    format!("{} {} {}", extractor, id, file_ext)
+}
+
+
+fn copy_file(source: &Path, dest: &Path) -> Result<(), Error> {
+    fs::copy(source, dest).map(|_| ())
+}
+
+
+/// Whether the process that wrote lock_path is still running. A lock that cannot be read is taken to be held,
+/// as it may be being written.
+fn lock_holder_running(lock_path: &Path) -> bool {
+    let Some(pid) = fs::read_to_string(lock_path).ok().and_then(|f| f.trim().parse::<u32>().ok()) else {
+        return true;
+    };
+
+    if pid == std::process::id() {
+        return true;
+    }
+
+    #[cfg(unix)]
+    {
+        //Signal 0 only checks the process exists. EPERM means it exists but belongs to another user.
+        unsafe { libc::kill(pid as libc::pid_t, 0) == 0 || Error::last_os_error().raw_os_error() == Some(libc::EPERM) }
+    }
+
+    #[cfg(not(unix))]
+    {
+        true
+    }
+}
+
+
+/// Removes the lock when the store is closed.
+struct StoreLock(PathBuf);
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+
+pub(crate) struct Store {
+    dir: PathBuf,
+    link: LinkMode,
+    /// The playlist directories using each stored file.
+    refs: BTreeMap<String, BTreeSet<PathBuf>>,
+    _lock: StoreLock,
+}
+
+
+impl Store {
+    /// Opens the store, waiting for any other run using it. Returns None if no store is set.
+    pub(crate) fn open(options: &StoreOptions) -> Result<Option<Store>, Error> {
+        let Some(dir) = &options.store else {
+            return Ok(None);
+        };
+
+        fs::create_dir_all(dir)?;
+
+        let lock_path = dir.join(LOCK_FILE);
+        let start = Instant::now();
+
+        let lock = loop {
+            match OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+                Ok(mut file) => {
+                    let lock = StoreLock(lock_path);
+                    file.write_all(std::process::id().to_string().as_bytes())?;
+                    break lock;
+                },
+                Err(e) if e.kind() == ErrorKind::AlreadyExists && !lock_holder_running(&lock_path) => {
+                    pl_update_warn!("Breaking the lock on the store left by a run that is no longer running.");
+                    let _ = fs::remove_file(&lock_path);
+                },
+                Err(e) if e.kind() == ErrorKind::AlreadyExists && start.elapsed() < LOCK_TIMEOUT => sleep(Duration::from_millis(100)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    pl_update_fatal_error!(ErrorKind::WouldBlock, "The store is in use by another run. If no other run is using it, delete \"{}\".", lock_path.display());
+                },
+                Err(e) => {
+                    pl_update_fatal_error!(e.kind(), "Could not lock the store \"{}\": {}", dir.display(), e);
+                }
+            }
+        };
+
+        let mut refs: BTreeMap<String, BTreeSet<PathBuf>> = BTreeMap::new();
+
+        match File::open(dir.join(REFS_FILE)) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+
+                    if let Some((key, playlist_dir)) = line.split_once('\t') {
+                        refs.entry(key.to_string()).or_default().insert(PathBuf::from(playlist_dir));
+                    }
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+
+        Ok(Some(Store { dir: dir.clone(), link: options.store_link, refs, _lock: lock }))
+    }
+
+
+    fn file_path(&self, extractor: &str, id: &str, file_ext: &str) -> PathBuf {
+        self.dir.join(extractor).join(format!("{}.{}", id, file_ext))
+    }
+
+
+    fn save_refs(&self) -> Result<(), Error> {
+        let tmp_path = self.dir.join(format!("{}.new", REFS_FILE));
+        let mut file = File::create(&tmp_path)?;
+
+        for (key, playlist_dirs) in &self.refs {
+            for playlist_dir in playlist_dirs {
+                file.write_all(format!("{}\t{}\n", key, playlist_dir.display()).as_bytes())?;
+            }
+        }
+
+        fs::rename(tmp_path, self.dir.join(REFS_FILE))
+    }
+
+
+    /// Rewrites the archive for file_ext with only the songs in keep. Songs yt-dlp recorded but whose file is not in the store
+    /// or that no playlist refers to are dropped, as yt-dlp would skip them.
+    fn write_archive(&self, file_ext: &str, keep: impl Fn(&str, &str) -> bool) -> Result<(), Error> {
+        let path = self.dir.join(format!("archive.{}.txt", file_ext));
+
+        let lines = match fs::read_to_string(&path) {
+            Ok(val) => val,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let kept: Vec<&str> = lines.lines().filter(|line| line.split_once(' ').is_some_and(|(extractor, id)| keep(extractor, id))).collect();
+
+        if kept.len() != lines.lines().count() {
+            fs::write(&path, kept.iter().map(|f| format!("{}\n", f)).collect::<String>())?;
+        }
+
+        Ok(())
+    }
+
+
+    fn add_to_archive(&self, extractor: &str, id: &str, file_ext: &str) -> Result<(), Error> {
+        let path = self.dir.join(format!("archive.{}.txt", file_ext));
+        let entry = format!("{} {}", extractor, id);
+
+        if fs::read_to_string(&path).is_ok_and(|f| f.lines().any(|line| line == entry)) {
+            return Ok(());
+        }
+
+        OpenOptions::new().create(true).append(true).open(path)?.write_all(format!("{}\n", entry).as_bytes())
+    }
+
+
+    /// Puts a link to source at dest, as the store is configured. Falls back to copying when a link cannot be made.
+    fn link_file(&self, source: &Path, dest: &Path) -> Result<(), Error> {
+        let linked = match self.link {
+            LinkMode::Hardlink => fs::hard_link(source, dest),
+            #[cfg(unix)]
+            LinkMode::Symlink => std::os::unix::fs::symlink(source, dest),
+            #[cfg(windows)]
+            LinkMode::Symlink => std::os::windows::fs::symlink_file(source, dest),
+            LinkMode::Copy => return copy_file(source, dest),
+        };
+
+        if let Err(e) = linked {
+            pl_update_warn!("Could not link \"{}\" to the store, it was copied instead: {}", dest.display(), e);
+            copy_file(source, dest)?;
+        }
+
+        Ok(())
+    }
+
+
+    /// Links the songs that are already in the store into the current directory, under the names yt-dlp would give them.
+    /// Returns the songs that still have to be downloaded.
+    pub(crate) fn link_stored(&mut self, songs: &[Song], file_ext: &str) -> Result<Vec<Song>, Error> {
+        let playlist_dir = current_dir()?;
+        let mut pending = Vec::new();
+        let mut linked = 0;
+
+        for song in songs {
+            let Some((extractor, id)) = song_key(song) else {
+                pending.push(song.clone());
+                continue;
+            };
+
+            let stored = self.file_path(&extractor, &id, file_ext);
+
+            if !stored.exists() {
+                pending.push(song.clone());
+                continue;
+            }
+
+            let filename = song.to_filename(file_ext.to_owned());
+
+            if fs::symlink_metadata(&filename).is_err() {
+                self.link_file(&stored, Path::new(&filename))?;
+                linked += 1;
+            }
+
+            self.refs.entry(ref_key(&extractor, &id, file_ext)).or_default().insert(playlist_dir.clone());
+        }
+
+        self.save_refs()?;
+
+        //yt-dlp skips every song in the archive, so a song no playlist refers to would never get a file in this one.
+        self.write_archive(file_ext, |extractor, id| self.file_path(extractor, id, file_ext).exists()
+            && self.refs.get(&ref_key(extractor, id, file_ext)).is_some_and(|f| !f.is_empty()))?;
+
+        if linked > 0 {
+            pl_update_println!("Linked {} songs from the store.", linked);
+        }
+
+        Ok(pending)
+    }
+
+
+    /// Moves freshly downloaded songs from the current directory into the store, leaving links in their place.
+    pub(crate) fn adopt_downloads(&mut self, songs: &[Song], file_ext: &str) -> Result<(), Error> {
+        let playlist_dir = current_dir()?;
+
+        for song in songs {
+            let filename = song.to_filename(file_ext.to_owned());
+
+            let Some((extractor, id)) = song_key(song) else {
+                continue;
+            };
+
+            let stored = self.file_path(&extractor, &id, file_ext);
+            let downloaded = Path::new(&filename).is_file();
+
+            if downloaded && !stored.exists() {
+                fs::create_dir_all(stored.parent().unwrap_or(&self.dir))?;
+
+                //Renaming fails across filesystems, where a copy is made instead.
+                if fs::rename(&filename, &stored).is_err() {
+                    copy_file(Path::new(&filename), &stored)?;
+                    fs::remove_file(&filename)?;
+                }
+
+                self.link_file(&stored, Path::new(&filename))?;
+            } else if !downloaded && stored.exists() {
+                //Another run stored the song while this one was downloading, so yt-dlp skipped it as archived.
+                self.link_file(&stored, Path::new(&filename))?;
+            } else if !downloaded {
+                continue;
+            }
+
+            self.add_to_archive(&extractor, &id, file_ext)?;
+            self.refs.entry(ref_key(&extractor, &id, file_ext)).or_default().insert(playlist_dir.clone());
+        }
+
+        self.save_refs()
+    }
+
+
+    /// Records that the playlist in the current directory no longer uses songs. Songs no playlist uses are deleted from the store.
+    pub(crate) fn release(&mut self, songs: &[Song], file_ext: &str) -> Result<(), Error> {
+        let playlist_dir = current_dir()?;
+
+        for (extractor, id) in songs.iter().filter_map(song_key) {
+            let key = ref_key(&extractor, &id, file_ext);
+
+            if let Some(playlist_dirs) = self.refs.get_mut(&key) {
+                playlist_dirs.remove(&playlist_dir);
+            }
+        }
+
+        self.delete_unused(file_ext)?;
+        self.save_refs()
+    }
+
+
+    /// Moves the references of the playlist in old_dir to new_dir, for when the playlist directory is renamed.
+    pub(crate) fn move_playlist(&mut self, old_dir: &Path, new_dir: &Path) -> Result<(), Error> {
+        for playlist_dirs in self.refs.values_mut() {
+            if playlist_dirs.remove(old_dir) {
+                playlist_dirs.insert(new_dir.to_path_buf());
+            }
+        }
+
+        self.save_refs()
+    }
+
+
+    /// Every file in the store, with the reference key for it.
+    fn stored_files(&self) -> Result<Vec<(String, PathBuf)>, Error> {
+        let mut files = Vec::new();
+
+        for extractor_entry in fs::read_dir(&self.dir)? {
+            let extractor_entry = extractor_entry?;
+
+            if !extractor_entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let extractor = extractor_entry.file_name().to_string_lossy().to_string();
+
+            for file_entry in fs::read_dir(extractor_entry.path())? {
+                let path = file_entry?.path();
+
+                if let (Some(id), Some(file_ext)) = (path.file_stem(), path.extension()) {
+                    files.push((ref_key(&extractor, &id.to_string_lossy(), &file_ext.to_string_lossy()), path));
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+
+    /// Deletes the stored files of file_ext that no playlist uses, or of every format if file_ext is empty. Returns the number deleted.
+    fn delete_unused(&mut self, file_ext: &str) -> Result<usize, Error> {
+        self.refs.retain(|_, playlist_dirs| !playlist_dirs.is_empty());
+
+        let mut deleted = 0;
+        let mut formats = BTreeSet::new();
+
+        for (key, path) in self.stored_files()? {
+            let in_format = file_ext.is_empty() || key.ends_with(&format!(" {}", file_ext));
+
+            if in_format && !self.refs.contains_key(&key) {
+                match fs::remove_file(&path) {
+                    Ok(()) => deleted += 1,
+                    Err(e) if e.kind() == ErrorKind::NotFound => {},
+                    Err(e) => return Err(e),
+                }
+
+                formats.insert(key.rsplit_once(' ').map_or(String::new(), |f| f.1.to_string()));
+            }
+        }
+
+        for format in formats {
+            self.write_archive(&format, |extractor, id| self.file_path(extractor, id, &format).exists())?;
+        }
+
+        Ok(deleted)
+    }
+
+
+    /// Drops references from playlists that no longer exist or no longer have the song, then deletes the files no playlist uses.
+    /// Returns the number of references dropped and files deleted.
+    fn collect_garbage(&mut self) -> Result<(usize, usize), Error> {
+        let mut playlists = BTreeMap::new();
+        let mut dropped = 0;
+
+        for (key, playlist_dirs) in self.refs.iter_mut() {
+            let mut parts = key.splitn(3, ' ');
+            let (extractor, id, file_ext) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+
+            playlist_dirs.retain(|playlist_dir| {
+                let playlist = playlists.entry(playlist_dir.clone())
+                    .or_insert_with(|| File::open(playlist_dir.join("playlist.manifest")).and_then(parse_manifest).ok());
+
+                let used = playlist.as_ref().is_some_and(|playlist| playlist.file_ext() == file_ext && playlist.songs.iter()
+                    .any(|song| song.id == id && song_key(song).is_some_and(|(f, _)| f == extractor)));
+
+                dropped += usize::from(!used);
+                used
+            });
+        }
+
+        let deleted = self.delete_unused("")?;
+        self.save_refs()?;
+
+        Ok((dropped, deleted))
+    }
+}
+
+
+/// Releases songs the playlist in the current directory no longer has from the store, if there is one.
+pub(crate) fn release_songs(options: &Args, songs: &[Song], file_ext: &str) -> Result<(), Error> {
+    match Store::open(&options.store_options)? {
+        Some(mut store) => store.release(songs, file_ext),
+        None => Ok(()),
+    }
+}
+
+
+pub(crate) fn pl_store(options: Args, gc: bool) -> Result<(), Error> {
+    let Some(mut store) = Store::open(&options.store_options)? else {
+        let e = Error::new(ErrorKind::InvalidInput, "No store is set, give its directory with --store.");
+        return Err(with_exit_code(e, EXIT_CONFIG_ERROR));
+    };
+
+
+    if gc {
+        let (dropped, deleted) = store.collect_garbage()?;
+        pl_update_println!("Dropped {} references from playlists that no longer have the song, deleted {} unused files.", dropped, deleted);
+    }
+
+
+    let files = store.stored_files()?;
+    let size: u64 = files.iter().filter_map(|(_, path)| fs::metadata(path).ok()).map(|f| f.len()).sum();
+    let unused = files.iter().filter(|(key, _)| !store.refs.contains_key(key)).count();
+    let playlists: BTreeSet<&PathBuf> = store.refs.values().flatten().collect();
+    let shared = store.refs.values().filter(|f| f.len() > 1).count();
+
+    //The store's contents are the output of this command, so they are printed even when quiet.
+    println!("Store:      {}", store.dir.display());
+    println!("Link mode:  {:?}", store.link);
+    println!("Songs:      {} ({:.1} MB)", files.len(), size as f64 / 1_000_000.0);
+    println!("Shared:     {} songs are used by more than one playlist", shared);
+    println!("Unused:     {} songs, delete them with --gc", unused);
+
+    for playlist_dir in playlists {
+        let count = store.refs.values().filter(|f| f.contains(playlist_dir)).count();
+        println!("Playlist:   {} ({} songs)", playlist_dir.display(), count);
+    }
+
+
+    Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::in_temp_dir;
+
+    #[test]
+    fn leaves_songs_without_refs_out_of_the_archive() {
+        in_temp_dir("store", || {
+            let options = StoreOptions { store: Some(current_dir().unwrap().join("store")), ..Default::default() };
+            fs::create_dir_all("store/youtube").unwrap();
+            fs::write("store/youtube/abc.mp3", "audio").unwrap();
+            fs::write("store/youtube/def.mp3", "audio").unwrap();
+            fs::write("store/youtube/xyz.mp3", "audio").unwrap();
+            fs::write("store/archive.mp3.txt", "youtube abc\nyoutube def\nyoutube ghi\nyoutube xyz\n").unwrap();
+            fs::write("store/refs", "youtube def mp3\t/playlists/other\n").unwrap();
+
+            let songs = [Song::new("Song".to_string(), "abc".to_string(), None, 1, Some("Youtube".to_string()))];
+            let mut store = Store::open(&options).unwrap().unwrap();
+            store.link_stored(&songs, "mp3").unwrap();
+
+            //abc is now used by this playlist, ghi has no file and no playlist uses xyz.
+            assert_eq!(fs::read_to_string("store/archive.mp3.txt").unwrap(), "youtube abc\nyoutube def\n");
+        });
+    }
+}
@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs::File, io::{BufWriter, Error, Write}, path::{Path, PathBuf}, sync::{LazyLock, Mutex, MutexGuard}, time::Instant};
+
+use crate::{DownloadFailure, Song};
+
+
+/// What a run did, collected as it goes and written by --summary-json and to the playlist's history.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct RunSummary {
+    pub(crate) command: String,
+    pub(crate) playlist: Option<String>,
+    pub(crate) started: String,
+    pub(crate) duration_seconds: f64,
+    pub(crate) exit_code: i32,
+    pub(crate) error: Option<String>,
+    /// How long each step of the run took, in the order they ran.
+    pub(crate) phases: Vec<Phase>,
+    pub(crate) added: Vec<SongSummary>,
+    pub(crate) removed: Vec<SongSummary>,
+    pub(crate) renamed: Vec<SongSummary>,
+    pub(crate) moved: Vec<SongSummary>,
+    pub(crate) downloaded: usize,
+    pub(crate) failed: Vec<FailedSong>,
+    /// Sources of the playlist that could not be listed.
+    pub(crate) failed_sources: Vec<String>,
+    /// The version of each external tool that was used.
+    pub(crate) tools: BTreeMap<String, String>,
+    /// The manifest as it was before the run, if the run changed it.
+    pub(crate) snapshot: Option<String>,
+    /// The directory of the playlist the run worked on, its history is kept there.
+    #[serde(skip)]
+    pub(crate) playlist_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Phase {
+    pub(crate) name: String,
+    pub(crate) seconds: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SongSummary {
+    pub(crate) id: String,
+    pub(crate) title: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct FailedSong {
+    pub(crate) id: String,
+    pub(crate) title: String,
+    pub(crate) permanent: bool,
+    pub(crate) reason: String,
+}
+
+impl From<&Song> for SongSummary {
+    fn from(song: &Song) -> Self {
+        SongSummary { id: song.id.clone(), title: song.title.clone() }
+    }
+}
+
+impl From<&(Song, DownloadFailure)> for FailedSong {
+    fn from((song, failure): &(Song, DownloadFailure)) -> Self {
+        FailedSong { id: song.id.clone(), title: song.title.clone(), permanent: failure.permanent, reason: failure.reason.clone() }
+    }
+}
+
+impl RunSummary {
+    /// Records that the step called name has finished, having started at start.
+    pub(crate) fn phase(&mut self, name: &str, start: Instant) {
+        self.phases.push(Phase { name: name.to_string(), seconds: start.elapsed().as_secs_f64() });
+    }
+
+    /// Whether anything the run set out to do was not done.
+    pub(crate) fn is_partial(&self) -> bool {
+        !self.failed.is_empty() || !self.failed_sources.is_empty()
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.write_all(b"\n")?;
+        writer.flush()
+    }
+}
+
+
+static SUMMARY: LazyLock<Mutex<RunSummary>> = LazyLock::new(Default::default);
+
+/// The summary of the current run. Recording into it never fails, a panic elsewhere does not lose it.
+pub(crate) fn run_summary() -> MutexGuard<'static, RunSummary> {
+    SUMMARY.lock().unwrap_or_else(|e| e.into_inner())
+}
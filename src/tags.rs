@@ -0,0 +1,851 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+
+/// Metadata embedded in an audio file.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<String>,
+    pub comment: Option<String>,
+    /// The source url, yt-dlp writes this as the "purl" tag.
+    pub purl: Option<String>,
+    /// The length of the audio in seconds.
+    pub duration: Option<f64>,
+    pub has_cover: bool,
+    /// The width and height of the cover art, if it is a JPEG or PNG image.
+    pub cover_size: Option<(u32, u32)>,
+}
+
+impl Tags {
+    /// The url the file was downloaded from, if it was recorded.
+    pub fn source_url(&self) -> Option<String> {
+        self.purl.clone().or_else(|| self.comment.clone().filter(|f| f.starts_with("http")))
+    }
+
+    /// Sets a tag from a Vorbis comment style key, used by ogg, opus and flac files.
+    fn set_vorbis_comment(&mut self, key: &str, value: String) {
+        match key.to_ascii_lowercase().as_str() {
+            "title" => self.title = Some(value),
+            "artist" => self.artist = Some(value),
+            "album" => self.album = Some(value),
+            "tracknumber" | "track" => self.track = Some(value),
+            "comment" | "description" if self.comment.is_none() => self.comment = Some(value),
+            "purl" => self.purl = Some(value),
+            "metadata_block_picture" | "coverart" => self.has_cover = true,
+            _ => {}
+        }
+    }
+}
+
+
+/// Reads the tags of the file at path. Supports ID3v2 tagged mp3 files, Vorbis comments in
+/// ogg, opus and flac files, and mp4/m4a metadata.
+/// Returns Ok(None) if the file is not in a format that can be read.
+pub(crate) fn read_tags(path: &str) -> io::Result<Option<Tags>> {
+    let mut file = File::open(path)?;
+
+    let mut magic = [0u8; 12];
+    let magic_len = read_up_to(&mut file, &mut magic)?;
+    let magic = &magic[..magic_len];
+
+    file.seek(SeekFrom::Start(0))?;
+
+    if magic.starts_with(b"ID3") || (magic.len() >= 2 && magic[0] == 0xff && magic[1] & 0xe0 == 0xe0) {
+        read_mp3(&mut file).map(Some)
+    } else if magic.starts_with(b"fLaC") {
+        read_flac(&mut file).map(Some)
+    } else if magic.starts_with(b"OggS") {
+        read_ogg(&mut file).map(Some)
+    } else if magic.len() >= 8 && &magic[4..8] == b"ftyp" {
+        read_mp4(&mut file).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+
+/// Like read_exact, but stops at the end of the file. Returns the number of bytes read.
+fn read_up_to(file: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+
+    while total < buf.len() {
+        let read = file.read(&mut buf[total..])?;
+        if read == 0 {
+            break;
+        }
+        total += read;
+    }
+
+    Ok(total)
+}
+
+/// Reads up to len bytes. Sizes come from the file itself, so nothing is allocated beyond what the file holds.
+fn read_vec(file: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    file.take(len as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn stream_len(file: &mut impl Seek) -> io::Result<u64> {
+    let pos = file.stream_position()?;
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(pos))?;
+    Ok(len)
+}
+
+
+fn synchsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, b| (acc << 7) | (*b as u32 & 0x7f))
+}
+
+fn big_endian(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u32)
+}
+
+fn little_endian(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0, |acc, b| (acc << 8) | *b as u32)
+}
+
+/// The width and height of a JPEG or PNG image, read from its header.
+fn image_size(data: &[u8]) -> Option<(u32, u32)> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let ihdr = data.get(16..24)?;
+        return Some((big_endian(&ihdr[..4]), big_endian(&ihdr[4..])));
+    }
+
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    //Segments are a marker followed by a length that counts itself, the size is in the start of frame segment.
+    let mut pos = 2;
+
+    while let Some(segment) = data.get(pos..pos + 4) {
+        let marker = segment[1];
+
+        if segment[0] != 0xff || marker == 0xda {
+            return None; //Corrupt, or the image data started before any frame
+        }
+
+        //0xc4, 0xc8 and 0xcc share the range but are tables.
+        if (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            let frame = data.get(pos + 5..pos + 9)?;
+            return Some((big_endian(&frame[2..]), big_endian(&frame[..2])));
+        }
+
+        pos += 2 + big_endian(&segment[2..]) as usize;
+    }
+
+    None
+}
+
+
+
+// MP3 / ID3v2
+
+
+fn read_mp3(file: &mut (impl Read + Seek)) -> io::Result<Tags> {
+    let file_len = stream_len(file)?;
+
+    let mut tags = Tags::default();
+    let mut audio_start = 0;
+
+    let mut header = [0u8; 10];
+    if read_up_to(file, &mut header)? == header.len() && &header[..3] == b"ID3" {
+        let major_version = header[3];
+        let flags = header[5];
+        let size = synchsafe(&header[6..10]) as usize;
+
+        let mut data = read_vec(file, size)?;
+
+        if flags & 0x80 != 0 && major_version < 4 {
+            data = remove_unsynchronisation(&data);
+        }
+
+        tags = parse_id3v2_frames(&data, major_version, flags);
+
+        audio_start = 10 + size as u64 + if flags & 0x10 != 0 { 10 } else { 0 }; //Footer
+    }
+
+    file.seek(SeekFrom::Start(audio_start))?;
+    let audio = read_vec(file, 64 * 1024)?;
+
+    if let Some(duration) = mpeg_duration(&audio, file_len.saturating_sub(audio_start)) {
+        tags.duration = Some(duration);
+    }
+
+    Ok(tags)
+}
+
+/// Works out the length of mpeg layer III audio from the first frame, which holds the frame count
+/// for VBR files. CBR files are measured from their size and bitrate.
+fn mpeg_duration(audio: &[u8], audio_len: u64) -> Option<f64> {
+    const MPEG1_BITRATES: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+    const MPEG2_BITRATES: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+    let start = audio.windows(2).position(|f| f[0] == 0xff && f[1] & 0xe0 == 0xe0 && f[1] & 0x06 == 0x02)?;
+    let frame = audio.get(start..start + 4)?;
+
+    let version = (frame[1] >> 3) & 0x03; //3 is MPEG1, 2 is MPEG2, 0 is MPEG2.5
+    let bitrate_index = (frame[2] >> 4) as usize;
+    let sample_rate_index = ((frame[2] >> 2) & 0x03) as usize;
+    let mono = frame[3] >> 6 == 3;
+
+    if version == 1 || bitrate_index == 0 || bitrate_index >= 15 || sample_rate_index >= 3 {
+        return None;
+    }
+
+    let (bitrate, sample_rate, samples_per_frame, side_info_len) = match version {
+        3 => (MPEG1_BITRATES[bitrate_index], SAMPLE_RATES[sample_rate_index], 1152, if mono { 17 } else { 32 }),
+        2 => (MPEG2_BITRATES[bitrate_index], SAMPLE_RATES[sample_rate_index] / 2, 576, if mono { 9 } else { 17 }),
+        _ => (MPEG2_BITRATES[bitrate_index], SAMPLE_RATES[sample_rate_index] / 4, 576, if mono { 9 } else { 17 }),
+    };
+
+    let xing_start = start + 4 + side_info_len;
+
+    if let Some(xing) = audio.get(xing_start..xing_start + 12) {
+        if (&xing[..4] == b"Xing" || &xing[..4] == b"Info") && big_endian(&xing[4..8]) & 0x01 != 0 {
+            let frames = big_endian(&xing[8..12]);
+            return Some(frames as f64 * samples_per_frame as f64 / sample_rate as f64);
+        }
+    }
+
+    Some(audio_len.saturating_sub(start as u64) as f64 * 8.0 / (bitrate as f64 * 1000.0))
+}
+
+fn remove_unsynchronisation(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        out.push(data[i]);
+        if data[i] == 0xff && data.get(i + 1) == Some(&0x00) {
+            i += 1;
+        }
+        i += 1;
+    }
+
+    out
+}
+
+
+fn parse_id3v2_frames(data: &[u8], major_version: u8, flags: u8) -> Tags {
+    let mut tags = Tags::default();
+
+    let (id_len, header_len) = if major_version == 2 { (3, 6) } else { (4, 10) };
+
+    let mut pos = 0;
+
+    if flags & 0x40 != 0 && major_version > 2 && data.len() >= 4 {
+        //Skip the extended header, v2.3 does not count the size bytes in its size.
+        pos = if major_version == 3 { big_endian(&data[..4]) as usize + 4 } else { synchsafe(&data[..4]) as usize };
+    }
+
+    while pos + header_len <= data.len() {
+        let frame_id = &data[pos..pos + id_len];
+
+        if frame_id[0] == 0 {
+            break; //Padding
+        }
+
+        let frame_size = match major_version {
+            2 => big_endian(&data[pos + 3..pos + 6]) as usize,
+            3 => big_endian(&data[pos + 4..pos + 8]) as usize,
+            _ => synchsafe(&data[pos + 4..pos + 8]) as usize,
+        };
+
+        let body_start = pos + header_len;
+        let body_end = body_start + frame_size;
+
+        if body_end > data.len() {
+            break;
+        }
+
+        let body = &data[body_start..body_end];
+
+        match frame_id {
+            b"TIT2" | b"TT2" => tags.title = decode_text(body),
+            b"TPE1" | b"TP1" => tags.artist = decode_text(body),
+            b"TALB" | b"TAL" => tags.album = decode_text(body),
+            b"TRCK" | b"TRK" => tags.track = decode_text(body),
+            b"COMM" | b"COM" => tags.comment = decode_comment(body),
+            b"APIC" | b"PIC" => {
+                tags.has_cover = true;
+                tags.cover_size = tags.cover_size.or_else(|| id3_picture(body, frame_id == b"PIC").and_then(image_size));
+            },
+            b"TLEN" | b"TLE" if tags.duration.is_none() => {
+                tags.duration = decode_text(body).and_then(|f| f.trim().parse::<f64>().ok()).map(|f| f / 1000.0);
+            },
+            b"TXXX" | b"TXX" => {
+                if let Some((description, value)) = decode_user_text(body) {
+                    if description.eq_ignore_ascii_case("purl") {
+                        tags.purl = Some(value);
+                    }
+                }
+            },
+            _ => {}
+        }
+
+        pos = body_end;
+    }
+
+    tags
+}
+
+/// The image data of an APIC frame, or of a PIC frame in ID3v2.2 which has a 3 letter format in place of the mime type.
+fn id3_picture(body: &[u8], is_v2_2: bool) -> Option<&[u8]> {
+    let encoding = *body.first()?;
+
+    let format_end = if is_v2_2 { 4 } else { 2 + body.get(1..)?.iter().position(|f| *f == 0)? };
+    let description = body.get(format_end + 1..)?; //After the picture type
+
+    //The description ends with a null, which is two bytes in UTF-16.
+    let description_len = if matches!(encoding, 1 | 2) {
+        2 + 2 * description.chunks_exact(2).position(|f| f == [0, 0])?
+    } else {
+        1 + description.iter().position(|f| *f == 0)?
+    };
+
+    description.get(description_len..)
+}
+
+
+/// Decodes a string in one of the ID3v2 text encodings, returning it and the number of bytes used
+/// including the terminator.
+fn decode_string(encoding: u8, data: &[u8]) -> (String, usize) {
+    if encoding == 1 || encoding == 2 {
+        let mut end = 0;
+        while end + 1 < data.len() && !(data[end] == 0 && data[end + 1] == 0) {
+            end += 2;
+        }
+
+        let mut bytes = &data[..end.min(data.len())];
+        let mut little_endian = false;
+
+        if encoding == 1 && bytes.len() >= 2 {
+            little_endian = bytes[..2] == [0xff, 0xfe];
+            if bytes[..2] == [0xff, 0xfe] || bytes[..2] == [0xfe, 0xff] {
+                bytes = &bytes[2..];
+            }
+        }
+
+        let units: Vec<u16> = bytes.chunks_exact(2).map(|f| if little_endian {
+            u16::from_le_bytes([f[0], f[1]])
+        } else {
+            u16::from_be_bytes([f[0], f[1]])
+        }).collect();
+
+        (String::from_utf16_lossy(&units), (end + 2).min(data.len()))
+
+    } else {
+        let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+        let bytes = &data[..end];
+
+        let text = if encoding == 3 {
+            String::from_utf8_lossy(bytes).to_string()
+        } else {
+            bytes.iter().map(|b| *b as char).collect() //ISO-8859-1 maps directly onto unicode
+        };
+
+        (text, (end + 1).min(data.len()))
+    }
+}
+
+fn decode_text(body: &[u8]) -> Option<String> {
+    let (&encoding, text) = body.split_first()?;
+    let (text, _) = decode_string(encoding, text);
+
+    Some(text).filter(|f| !f.is_empty())
+}
+
+fn decode_user_text(body: &[u8]) -> Option<(String, String)> {
+    let (&encoding, data) = body.split_first()?;
+    let (description, used) = decode_string(encoding, data);
+    let (value, _) = decode_string(encoding, &data[used..]);
+
+    Some((description, value))
+}
+
+fn decode_comment(body: &[u8]) -> Option<String> {
+    if body.len() < 4 {
+        return None;
+    }
+
+    let encoding = body[0];
+    let (_, used) = decode_string(encoding, &body[4..]); //Skip the language code and description
+    let (text, _) = decode_string(encoding, &body[4 + used..]);
+
+    Some(text).filter(|f| !f.is_empty())
+}
+
+
+
+// Vorbis comments, FLAC and Ogg
+
+
+fn parse_vorbis_comment(data: &[u8], tags: &mut Tags) {
+    let read_u32 = |pos: usize| data.get(pos..pos + 4).map(little_endian);
+
+    let Some(vendor_len) = read_u32(0) else { return };
+    let mut pos = 4 + vendor_len as usize;
+
+    let Some(count) = read_u32(pos) else { return };
+    pos += 4;
+
+    for _ in 0..count {
+        let Some(len) = read_u32(pos) else { return };
+        pos += 4;
+
+        let Some(comment) = data.get(pos..pos.saturating_add(len as usize)) else { return };
+        pos += len as usize;
+
+        let comment = String::from_utf8_lossy(comment);
+        if let Some((key, value)) = comment.split_once('=') {
+            tags.set_vorbis_comment(key, value.to_string());
+        }
+    }
+}
+
+
+/// The width and height recorded in a FLAC picture block, after its picture type, mime type and description.
+fn flac_picture_size(block: &[u8]) -> Option<(u32, u32)> {
+    let mime_end = 8 + big_endian(block.get(4..8)?) as usize;
+    let description_end = mime_end + 4 + big_endian(block.get(mime_end..mime_end + 4)?) as usize;
+    let size = block.get(description_end..description_end + 8)?;
+
+    Some((big_endian(&size[..4]), big_endian(&size[4..])))
+}
+
+fn read_flac(file: &mut (impl Read + Seek)) -> io::Result<Tags> {
+    let mut tags = Tags::default();
+
+    file.seek(SeekFrom::Start(4))?;
+
+    loop {
+        let mut header = [0u8; 4];
+        if read_up_to(file, &mut header)? < header.len() {
+            break;
+        }
+
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let len = big_endian(&header[1..4]) as usize;
+
+        match block_type {
+            0 => { //STREAMINFO
+                let block = read_vec(file, len)?;
+                if block.len() >= 18 {
+                    let sample_rate = (block[10] as u64) << 12 | (block[11] as u64) << 4 | (block[12] as u64) >> 4;
+                    let total_samples = ((block[13] & 0x0f) as u64) << 32 | big_endian(&block[14..18]) as u64;
+                    if sample_rate > 0 && total_samples > 0 {
+                        tags.duration = Some(total_samples as f64 / sample_rate as f64);
+                    }
+                }
+            },
+            4 => parse_vorbis_comment(&read_vec(file, len)?, &mut tags),
+            6 => {
+                tags.has_cover = true;
+                let block = read_vec(file, len)?;
+                tags.cover_size = tags.cover_size.or_else(|| flac_picture_size(&block));
+            },
+            _ => {
+                file.seek(SeekFrom::Current(len as i64))?;
+            }
+        }
+
+        if is_last {
+            break;
+        }
+    }
+
+    Ok(tags)
+}
+
+
+fn read_ogg(file: &mut (impl Read + Seek)) -> io::Result<Tags> {
+    let mut tags = Tags::default();
+
+    //The first packet identifies the codec, the second holds the comments.
+    //The comments can span many pages when cover art is embedded.
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    let mut packet = Vec::new();
+
+    while packets.len() < 2 {
+        let mut header = [0u8; 27];
+        if read_up_to(file, &mut header)? < header.len() || &header[..4] != b"OggS" {
+            break;
+        }
+
+        let segment_table = read_vec(file, header[26] as usize)?;
+
+        for segment_len in segment_table {
+            packet.extend(read_vec(file, segment_len as usize)?);
+
+            if segment_len < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+    }
+
+    let (sample_rate, pre_skip) = match packets.first() {
+        Some(id) if id.starts_with(b"OpusHead") && id.len() >= 12 => (48000, u16::from_le_bytes([id[10], id[11]]) as u64),
+        Some(id) if id.starts_with(b"\x01vorbis") && id.len() >= 16 => (little_endian(&id[12..16]) as u64, 0),
+        _ => return Ok(tags),
+    };
+
+    if let Some(comments) = packets.get(1) {
+        if let Some(data) = comments.strip_prefix(b"OpusTags").or_else(|| comments.strip_prefix(b"\x03vorbis")) {
+            parse_vorbis_comment(data, &mut tags);
+        }
+    }
+
+    //The granule position of the last page is the number of samples in the stream.
+    let file_len = stream_len(file)?;
+    let tail_start = file_len.saturating_sub(64 * 1024);
+    file.seek(SeekFrom::Start(tail_start))?;
+    let tail = read_vec(file, (file_len - tail_start) as usize)?;
+
+    if let Some(last_page) = tail.windows(4).rposition(|f| f == b"OggS") {
+        if let Some(granule) = tail.get(last_page + 6..last_page + 14) {
+            let granule = u64::from_le_bytes(granule.try_into().unwrap());
+            if sample_rate > 0 && granule > pre_skip {
+                tags.duration = Some((granule - pre_skip) as f64 / sample_rate as f64);
+            }
+        }
+    }
+
+    Ok(tags)
+}
+
+
+
+// MP4
+
+
+/// Splits data into the atoms it contains, as (type, body) pairs.
+fn mp4_atoms(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut atoms = Vec::new();
+    let mut pos = 0;
+
+    while pos + 8 <= data.len() {
+        let mut size = big_endian(&data[pos..pos + 4]) as usize;
+        let atom_type = &data[pos + 4..pos + 8];
+        let mut header_len = 8;
+
+        if size == 1 && pos + 16 <= data.len() {
+            size = u64::from_be_bytes(data[pos + 8..pos + 16].try_into().unwrap()) as usize;
+            header_len = 16;
+        } else if size == 0 {
+            size = data.len() - pos;
+        }
+
+        if size < header_len || size > data.len() - pos {
+            break;
+        }
+
+        atoms.push((atom_type, &data[pos + header_len..pos + size]));
+        pos += size;
+    }
+
+    atoms
+}
+
+fn read_mp4(file: &mut (impl Read + Seek)) -> io::Result<Tags> {
+    let mut tags = Tags::default();
+    let file_len = stream_len(file)?;
+
+    //Find the moov atom without reading the media data, which can come before it.
+    let mut pos = 0;
+    let mut moov = None;
+
+    while pos + 8 <= file_len {
+        file.seek(SeekFrom::Start(pos))?;
+
+        let mut header = [0u8; 16];
+        let header_read = read_up_to(file, &mut header)?;
+        if header_read < 8 {
+            break;
+        }
+
+        let mut size = big_endian(&header[..4]) as u64;
+        let mut header_len = 8;
+
+        if size == 1 && header_read == 16 {
+            size = u64::from_be_bytes(header[8..16].try_into().unwrap());
+            header_len = 16;
+        } else if size == 0 {
+            size = file_len - pos;
+        }
+
+        if size < header_len || size > file_len - pos {
+            break;
+        }
+
+        if &header[4..8] == b"moov" {
+            file.seek(SeekFrom::Start(pos + header_len))?;
+            moov = Some(read_vec(file, (size - header_len) as usize)?);
+            break;
+        }
+
+        pos += size;
+    }
+
+    let Some(moov) = moov else { return Ok(tags) };
+
+    for (atom_type, body) in mp4_atoms(&moov) {
+        match atom_type {
+            b"mvhd" if !body.is_empty() => {
+                let (timescale, duration) = if body[0] == 1 && body.len() >= 32 {
+                    (big_endian(&body[20..24]) as u64, u64::from_be_bytes(body[24..32].try_into().unwrap()))
+                } else if body.len() >= 20 {
+                    (big_endian(&body[12..16]) as u64, big_endian(&body[16..20]) as u64)
+                } else {
+                    continue;
+                };
+
+                if timescale > 0 {
+                    tags.duration = Some(duration as f64 / timescale as f64);
+                }
+            },
+            b"udta" => {
+                for (_, meta) in mp4_atoms(body).into_iter().filter(|f| f.0 == b"meta" && f.1.len() > 4) {
+                    for (_, ilst) in mp4_atoms(&meta[4..]).into_iter().filter(|f| f.0 == b"ilst") { //meta has 4 bytes of version and flags
+                        parse_mp4_items(ilst, &mut tags);
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
+    Ok(tags)
+}
+
+fn parse_mp4_items(ilst: &[u8], tags: &mut Tags) {
+    for (item_type, item) in mp4_atoms(ilst) {
+        let children = mp4_atoms(item);
+
+        //The value is held in a data atom, after 4 bytes of type and 4 bytes of locale.
+        let value = children.iter().find(|f| f.0 == b"data" && f.1.len() >= 8).map(|f| String::from_utf8_lossy(&f.1[8..]).to_string());
+
+        match item_type {
+            b"\xa9nam" => tags.title = value,
+            b"\xa9ART" => tags.artist = value,
+            b"\xa9alb" => tags.album = value,
+            b"trkn" => {
+                //Binary, two reserved bytes then the track number and track count.
+                tags.track = children.iter().find(|f| f.0 == b"data" && f.1.len() >= 12).map(|f| u16::from_be_bytes([f.1[10], f.1[11]]).to_string());
+            },
+            b"\xa9cmt" => tags.comment = value,
+            b"covr" => {
+                tags.has_cover = true;
+                tags.cover_size = children.iter().find(|f| f.0 == b"data" && f.1.len() >= 8).and_then(|f| image_size(&f.1[8..]));
+            },
+            b"----" => {
+                let name = children.iter().find(|f| f.0 == b"name" && f.1.len() >= 4).map(|f| String::from_utf8_lossy(&f.1[4..]).to_string());
+                if let (Some(name), Some(value)) = (name, value) {
+                    tags.set_vorbis_comment(&name, value);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn id3_frame(id: &[u8], body: &[u8]) -> Vec<u8> {
+        [id, &(body.len() as u32).to_be_bytes(), &[0, 0], body].concat()
+    }
+
+    /// An ID3v2.3 tag holding frames, with the size in its header set to size.
+    fn id3_tag(frames: &[u8], size: u32) -> Vec<u8> {
+        let synchsafe_size = [(size >> 21) as u8 & 0x7f, (size >> 14) as u8 & 0x7f, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f];
+        [b"ID3\x03\x00\x00".as_slice(), &synchsafe_size, frames].concat()
+    }
+
+    fn atom(atom_type: &[u8], body: &[u8]) -> Vec<u8> {
+        [&(body.len() as u32 + 8).to_be_bytes(), atom_type, body].concat()
+    }
+
+    fn vorbis_comment(comments: &[&str]) -> Vec<u8> {
+        let mut data = [4u32.to_le_bytes().as_slice(), b"test", &(comments.len() as u32).to_le_bytes()].concat();
+
+        for comment in comments {
+            data.extend((comment.len() as u32).to_le_bytes());
+            data.extend(comment.as_bytes());
+        }
+
+        data
+    }
+
+    fn mp4_file(ilst: &[u8]) -> Vec<u8> {
+        //Version 0 mvhd: version and flags, creation and modification times, then the timescale and duration.
+        let mvhd = [[0u8; 12].as_slice(), &1000u32.to_be_bytes(), &90_500u32.to_be_bytes()].concat();
+        let meta = [[0u8; 4].as_slice(), &atom(b"ilst", ilst)].concat();
+        let moov = atom(b"moov", &[atom(b"mvhd", &mvhd), atom(b"udta", &atom(b"meta", &meta))].concat());
+
+        [atom(b"ftyp", b"M4A \x00\x00\x00\x00"), atom(b"mdat", &[0u8; 16]), moov].concat()
+    }
+
+    fn mp4_item(item_type: &[u8], value: &str) -> Vec<u8> {
+        atom(item_type, &atom(b"data", &[&[0, 0, 0, 1, 0, 0, 0, 0], value.as_bytes()].concat()))
+    }
+
+
+    #[test]
+    fn reads_id3v2_frames() {
+        let frames = [
+            id3_frame(b"TIT2", b"\x03Song title"),
+            id3_frame(b"TPE1", b"\x00Artist"),
+            id3_frame(b"TRCK", b"\x033"),
+            id3_frame(b"TXXX", b"\x03purl\x00https://www.youtube.com/watch?v=aaaaaaaaaaa"),
+            id3_frame(b"APIC", b"\x00image/jpeg\x00\x03\x00"),
+        ].concat();
+
+        let tags = read_mp3(&mut Cursor::new(id3_tag(&frames, frames.len() as u32))).unwrap();
+
+        assert_eq!(tags.title.as_deref(), Some("Song title"));
+        assert_eq!(tags.artist.as_deref(), Some("Artist"));
+        assert_eq!(tags.track.as_deref(), Some("3"));
+        assert_eq!(tags.source_url().as_deref(), Some("https://www.youtube.com/watch?v=aaaaaaaaaaa"));
+        assert!(tags.has_cover);
+    }
+
+    #[test]
+    fn reads_utf16_id3v2_text() {
+        let text: Vec<u8> = [0xff, 0xfe].into_iter().chain("Ünïcode".encode_utf16().flat_map(u16::to_le_bytes)).collect();
+        let frames = id3_frame(b"TIT2", &[&[1], text.as_slice(), &[0, 0]].concat());
+
+        let tags = read_mp3(&mut Cursor::new(id3_tag(&frames, frames.len() as u32))).unwrap();
+
+        assert_eq!(tags.title.as_deref(), Some("Ünïcode"));
+    }
+
+    #[test]
+    fn truncated_id3v2_tag_is_read_as_far_as_it_goes() {
+        let frames = [id3_frame(b"TIT2", b"\x03Song title"), id3_frame(b"TPE1", b"\x03Art")].concat();
+
+        //The header claims far more than the file holds, and the last frame is cut off.
+        let mut file = id3_tag(&frames, 0x0fff_ffff);
+        file.truncate(file.len() - 2);
+
+        let tags = read_mp3(&mut Cursor::new(file)).unwrap();
+
+        assert_eq!(tags.title.as_deref(), Some("Song title"));
+        assert_eq!(tags.artist, None);
+        assert_eq!(tags.duration, None);
+    }
+
+    #[test]
+    fn truncated_id3v2_header_is_not_a_tag() {
+        assert!(read_mp3(&mut Cursor::new(b"ID3\x03".to_vec())).is_ok());
+    }
+
+    #[test]
+    fn reads_vorbis_comments() {
+        let mut tags = Tags::default();
+        parse_vorbis_comment(&vorbis_comment(&["TITLE=Song", "artist=Artist", "TRACKNUMBER=7", "PURL=https://x/y", "no separator"]), &mut tags);
+
+        assert_eq!(tags.title.as_deref(), Some("Song"));
+        assert_eq!(tags.artist.as_deref(), Some("Artist"));
+        assert_eq!(tags.track.as_deref(), Some("7"));
+        assert_eq!(tags.purl.as_deref(), Some("https://x/y"));
+    }
+
+    #[test]
+    fn truncated_vorbis_comments_keep_complete_ones() {
+        let mut data = vorbis_comment(&["TITLE=Song", "ARTIST=Artist"]);
+        data.truncate(data.len() - 3);
+
+        let mut tags = Tags::default();
+        parse_vorbis_comment(&data, &mut tags);
+
+        assert_eq!(tags.title.as_deref(), Some("Song"));
+        assert_eq!(tags.artist, None);
+
+        //A length running past the end of the data.
+        let mut tags = Tags::default();
+        parse_vorbis_comment(&[4u32.to_le_bytes().as_slice(), b"test", &1u32.to_le_bytes(), &u32::MAX.to_le_bytes()].concat(), &mut tags);
+        assert_eq!(tags.title, None);
+    }
+
+    #[test]
+    fn reads_flac_blocks() {
+        //44.1kHz with 441000 samples, 10 seconds.
+        let mut streaminfo = [0u8; 34];
+        streaminfo[10..14].copy_from_slice(&[0x0a, 0xc4, 0x40, 0x00]);
+        streaminfo[14..18].copy_from_slice(&441_000u32.to_be_bytes());
+
+        let comment = vorbis_comment(&["TITLE=Flac song"]);
+        let file = [b"fLaC".as_slice(), &[0, 0, 0, 34], &streaminfo, &[0x84, 0, 0, comment.len() as u8], &comment].concat();
+
+        let tags = read_flac(&mut Cursor::new(file)).unwrap();
+
+        assert_eq!(tags.title.as_deref(), Some("Flac song"));
+        assert_eq!(tags.duration, Some(10.0));
+    }
+
+    #[test]
+    fn reads_mp4_metadata() {
+        let ilst = [mp4_item(b"\xa9nam", "Mp4 song"), mp4_item(b"\xa9ART", "Artist"), atom(b"covr", &[])].concat();
+
+        let tags = read_mp4(&mut Cursor::new(mp4_file(&ilst))).unwrap();
+
+        assert_eq!(tags.title.as_deref(), Some("Mp4 song"));
+        assert_eq!(tags.artist.as_deref(), Some("Artist"));
+        assert_eq!(tags.duration, Some(90.5));
+        assert!(tags.has_cover);
+    }
+
+    #[test]
+    fn reads_cover_art_size() {
+        //An APP0 segment, then a baseline frame of 720x1280.
+        let jpeg = [[0xff, 0xd8, 0xff, 0xe0, 0, 4, 0, 0].as_slice(), &[0xff, 0xc0, 0, 17, 8, 0x02, 0xd0, 0x05, 0x00]].concat();
+        let png = [b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".as_slice(), &500u32.to_be_bytes(), &400u32.to_be_bytes()].concat();
+
+        let frames = id3_frame(b"APIC", &[b"\x00image/jpeg\x00\x03Cover\x00".as_slice(), &jpeg].concat());
+        let tags = read_mp3(&mut Cursor::new(id3_tag(&frames, frames.len() as u32))).unwrap();
+        assert_eq!(tags.cover_size, Some((1280, 720)));
+
+        let picture = [[0u8, 0, 0, 3, 0, 0, 0, 9].as_slice(), b"image/png", &[0, 0, 0, 0], &500u32.to_be_bytes(), &400u32.to_be_bytes(), &[0u8; 16]].concat();
+        let file = [b"fLaC".as_slice(), &[0x86, 0, 0, picture.len() as u8], &picture].concat();
+        assert_eq!(read_flac(&mut Cursor::new(file)).unwrap().cover_size, Some((500, 400)));
+
+        let covr = atom(b"covr", &atom(b"data", &[&[0, 0, 0, 14, 0, 0, 0, 0], png.as_slice()].concat()));
+        assert_eq!(read_mp4(&mut Cursor::new(mp4_file(&covr))).unwrap().cover_size, Some((500, 400)));
+
+        assert_eq!(image_size(&[0xff, 0xd8, 0xff, 0xda, 0, 2]), None);
+    }
+
+    #[test]
+    fn truncated_mp4_is_not_read() {
+        let mut file = mp4_file(&mp4_item(b"\xa9nam", "Mp4 song"));
+        file.truncate(file.len() - 4);
+
+        let tags = read_mp4(&mut Cursor::new(file)).unwrap();
+
+        assert_eq!(tags.title, None);
+    }
+
+    #[test]
+    fn corrupt_mp4_sizes_are_bounded() {
+        //A 64 bit size far larger than the file, which must not be allocated or added to the position.
+        let huge = [1u32.to_be_bytes().as_slice(), b"moov", &u64::MAX.to_be_bytes()].concat();
+        let file = [atom(b"ftyp", b"M4A \x00\x00\x00\x00"), huge].concat();
+
+        assert!(read_mp4(&mut Cursor::new(file)).unwrap().title.is_none());
+
+        assert!(mp4_atoms(&[1u32.to_be_bytes().as_slice(), b"ilst", &u64::MAX.to_be_bytes()].concat()).is_empty());
+        assert!(mp4_atoms(&[0xffu8, 0xff, 0xff, 0xff, b'd', b'a', b't', b'a']).is_empty());
+    }
+}
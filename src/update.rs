@@ -1,46 +1,18 @@
 use chrono::Local;
-use colored::Colorize;
-use std::{env::set_current_dir, fs::{self, remove_file, File, OpenOptions}, io::{Error, ErrorKind}, time::SystemTime};
+use std::{env::{current_dir, set_current_dir}, fs::{self, remove_file, File, OpenOptions}, io::{Error, ErrorKind}, time::{Duration, SystemTime}};
 
-use crate::{download, parse_manifest, pl_update_fatal_error, pl_update_warn, update_manifest, Args};
+use crate::history::record_playlist;
+use crate::filters::apply_excludes;
+use crate::snapshots::snapshot_manifest;
+use crate::store::{release_songs, Store};
+use crate::{apply_file_rules, download, fetch_title, run_summary, sleep_unless_interrupted, find_ffmpeg, moved_songs, parse_manifest, playlist_filename, sanitize_dir_name, unused_dir_name, pl_update_fatal_error, report_duplicates, rewrite_tags, unique_songs, update_manifest, write_playlist_file, Args, Song};
 
 
+pub(crate) fn pl_update(options: Args, playlist_name: Option<String>, retag: bool, follow_title: bool, track_numbers: Option<bool>) -> Result<(), Error>{
 
 
-pub(crate) fn pl_update(options: Args, playlist_name: Option<String>) -> Result<(), Error>{
-
-    macro_rules! pl_update_vprintln {
-        ($($x:expr),*) => {
-            if options.verbose {
-                println!("{} [pl-update] {}", "DEBUG:".blue(),
-                format! (
-                    $(
-                        $x,
-                    )*
-                )
-
-                )
-            }
-        };
-    }
-
-    macro_rules! pl_update_println {
-        ($($x:expr),*) => {
-            if !options.quiet {
-                println!("[pl-update] {}",
-                format! (
-                        $(
-                            $x,
-                        )*
-                    )
-                )
-            }
-        };
-    }
-    
-  
-    if playlist_name.is_some() {
-        match set_current_dir(playlist_name.unwrap().clone()) {
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
             Ok(()) => (),
             Err(err) => {
                 pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
@@ -52,17 +24,18 @@ pub(crate) fn pl_update(options: Args, playlist_name: Option<String>) -> Result<
     let time: chrono::DateTime<Local> =  SystemTime::now().into();
     
 
-    let old_manifest = match OpenOptions::new().read(true).write(true).create(true).open("playlist.manifest") {
+    let old_manifest = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open("playlist.manifest") {
         Ok(val) => val,
         Err(err) => {
             pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
         }
     }; 
      
-    let (old_songs, playlist_name, playlist_url) = parse_manifest(old_manifest)?;
+    let old_playlist = parse_manifest(old_manifest)?;
 
 
-    pl_update_println!("Found playlist: \"{}\"", playlist_name);
+    pl_update_println!("Found playlist: \"{}\"", old_playlist.title);
+    record_playlist(&old_playlist);
 
     pl_update_println!("Updating manifest...");
 
@@ -71,16 +44,104 @@ pub(crate) fn pl_update(options: Args, playlist_name: Option<String>) -> Result<
         Err(e) => {
             if e.kind() == ErrorKind::AlreadyExists {
                 pl_update_warn!("playlist-new.manifest already exists. This likely indicates a download in progress failed. This file will be overrwritten.");
-                OpenOptions::new().read(true).write(true).open("playlist-new.manifest")?
+                OpenOptions::new().read(true).write(true).truncate(true).open("playlist-new.manifest")?
             } else {
                 pl_update_fatal_error!(e.kind(), "Could not create playlist-new.manifest: {}", e);
             }
         }
     };
 
-    update_manifest(new_manifest, playlist_name, &playlist_url, &options).unwrap();
+    let mut new_playlist = old_playlist.clone();
+    new_playlist.track_numbers = track_numbers.unwrap_or(old_playlist.track_numbers);
+
+    let failed_sources = update_manifest(new_manifest, &new_playlist, &options)?;
+
+    let mut new_playlist = parse_manifest(File::open("playlist-new.manifest")?)?;
+
+    //The title is only fetched from the first source, the others are merged into its playlist.
+    let mut renamed_remotely = false;
+
+    if let Some(source) = new_playlist.sources.first() {
+        sleep_unless_interrupted(options.rate_limits.pause(Duration::ZERO));
+
+        match fetch_title(source, &options) {
+            Ok(Some(remote_title)) => {
+                let known_title = new_playlist.remote_title.clone().unwrap_or(new_playlist.title.clone());
+
+                if remote_title != known_title {
+                    pl_update_println!("The playlist was renamed from \"{}\" to \"{}\".", known_title, remote_title);
+                    new_playlist.title_history.push((time.format("%Y-%m-%d").to_string(), known_title));
+                    new_playlist.remote_title = Some(remote_title);
+                    renamed_remotely = true;
+                }
+            },
+            Ok(None) => {},
+            Err(e) => pl_update_warn!("{}", e),
+        }
+    }
+
+    if let Some(remote_title) = new_playlist.remote_title.clone().filter(|f| *f != new_playlist.title) {
+        if follow_title {
+            pl_update_println!("Renaming playlist \"{}\" to \"{}\".", new_playlist.title, remote_title);
+            new_playlist.title = remote_title;
+            new_playlist.remote_title = None;
+        } else if renamed_remotely {
+            pl_update_println!("Run update with --follow-title to rename the playlist \"{}\" to match.", new_playlist.title);
+        }
+    }
+
+    //Kept files keep their measurements, songs downloaded again are analysed again.
+    for song in new_playlist.songs.iter_mut() {
+        if let Some(old_song) = old_playlist.songs.iter().find(|f| *f == song) {
+            song.loudness = old_song.loudness;
+            song.peak = old_song.peak;
+            song.failed = old_song.failed.clone();
+        }
+    }
+
+    //It is not known whether the songs of a source that could not be fetched were removed, so they are kept.
+    if !failed_sources.is_empty() {
+        let mut songs = Vec::new();
 
-    let (new_songs, _, _) = parse_manifest(File::open("playlist-new.manifest")?)?;
+        for source in &new_playlist.sources {
+            if failed_sources.contains(source) {
+                let kept: Vec<Song> = old_playlist.songs.iter().filter(|f| old_playlist.song_source(f) == source && !new_playlist.songs.contains(f)).cloned().collect();
+                pl_update_warn!("Could not fetch \"{}\", its {} songs will be kept as they are.", source, kept.len());
+
+                songs.extend(kept.into_iter().map(|mut song| { song.source = Some(source.clone()); song }));
+            } else {
+                songs.extend(new_playlist.songs.iter().filter(|f| new_playlist.song_source(f) == source).cloned());
+            }
+        }
+
+        for (position, song) in songs.iter_mut().enumerate() {
+            song.position = position + 1;
+        }
+
+        new_playlist.songs = songs;
+    }
+
+    //Pinned songs stay until they are removed by hand.
+    for old_song in old_playlist.songs.iter().filter(|f| f.pinned) {
+        match new_playlist.songs.iter_mut().find(|f| *f == old_song) {
+            Some(song) => song.pinned = true,
+            None => {
+                let mut song = old_song.clone();
+                song.position = new_playlist.songs.len() + 1;
+                new_playlist.songs.push(song);
+            }
+        }
+    }
+
+    for (song, reason) in apply_excludes(&mut new_playlist)? {
+        pl_update_vprintln!("Excluding \"{}\" [{}]: {}", song.title, song.id, reason);
+    }
+
+    report_duplicates(&new_playlist.songs);
+
+    //A file is shared by every occurrence of its id, so the diff is done on unique ids only.
+    let old_songs = unique_songs(&old_playlist.songs);
+    let new_songs = unique_songs(&new_playlist.songs);
     
 
     let removed_songs: Vec<_> = 
@@ -90,52 +151,190 @@ pub(crate) fn pl_update(options: Args, playlist_name: Option<String>) -> Result<
 
     ).collect();
 
-    let added_songs: Vec<_> = new_songs.into_iter().filter(|new_song|
+    let renamed_songs: Vec<_> = old_songs.iter().filter_map(|old_song|
+
+        new_songs.iter().find(|new_song| old_song.is_renamed(new_song)).map(|new_song| (old_song.clone(), new_song.clone()))
+
+    ).collect();
+
+    let moved_songs = moved_songs(&old_songs, &new_songs);
+
+    let added_songs: Vec<_> = new_songs.clone().into_iter().filter(|new_song|
 
         !old_songs.contains(new_song)
     
     ).collect();
 
+    //Songs that failed for a reason that may have passed are tried again, unavailable songs are left to verify.
+    let retried_songs: Vec<_> = new_songs.iter().filter(|new_song|
+
+        old_songs.contains(new_song) && new_song.failed.as_ref().is_some_and(|f| !f.permanent) && fs::metadata(old_playlist.song_filename(new_song)).is_err()
+
+    ).cloned().collect();
+
+    let download_songs = [added_songs.clone(), retried_songs.clone()].concat();
+
     pl_update_vprintln!("Items to download: {:?}", added_songs);
+    pl_update_vprintln!("Items to retry: {:?}", retried_songs);
 
-    let removed_filenames: Vec<_> = removed_songs.into_iter().map(|f| f.into_filename("mp3".to_owned())).collect();
-    let added_urls: Vec<_> = added_songs.into_iter().map(|u| u.url().unwrap()).collect();
+    let removed_filenames: Vec<_> = removed_songs.iter().map(|f| old_playlist.song_filename(f)).collect();
 
     pl_update_vprintln!("Items to remove: {:?}", removed_filenames);
+    pl_update_vprintln!("Items to rename: {:?}", renamed_songs);
+    pl_update_vprintln!("Items moved: {:?}", moved_songs);
 
 
-    
-    if added_urls.len() > 0 {
+    if download_songs.iter().any(|f| f.url().is_some()) {
         pl_update_println!("Downloading new items...");
-        download(added_urls, &options)?;
+        let failures = download(&download_songs, &new_playlist.audio_format, &options)?;
+        new_playlist.record_downloads(&download_songs, &failures);
     } else {
         pl_update_println!("No items to download.");
     }
     
 
-    if removed_filenames.len() > 0 {
+    if !removed_filenames.is_empty() {
         pl_update_println!("Deleting removed items..."); 
-        for filename in removed_filenames {
-            remove_file(filename)?;
+        for filename in &removed_filenames {
+            //Songs that failed to download are in the manifest with no file.
+            match remove_file(filename) {
+                Ok(()) => {},
+                Err(e) if e.kind() == ErrorKind::NotFound => pl_update_vprintln!("\"{}\" has no file to delete.", filename),
+                Err(e) => return Err(e),
+            }
         }
+
+        release_songs(&options, &removed_songs, old_playlist.file_ext())?;
     }  else {
         pl_update_println!("No items to remove.")
     }
 
 
+    let ffmpeg_command = options.ffmpeg_location.clone().unwrap_or("ffmpeg".to_string());
 
-    let old_playlist_filename = format!("playlist-{}.manifest", time.format("%Y-%m-%dT%H%M%S%.f"));
-    match fs::rename("playlist.manifest", old_playlist_filename) {
-        Ok(()) => {},
+    if retag && !renamed_songs.is_empty() {
+        find_ffmpeg(&ffmpeg_command)?;
+    }
+
+    let mut renamed_files = Vec::new();
+
+    pl_update_vprintln!("Renaming files to match manifest...");
+    for new_song in &new_songs {
+        let old_song = old_songs.iter().find(|old_song| *old_song == new_song);
+
+        let current_filename = match old_song {
+            Some(old_song) if !retried_songs.contains(old_song) => old_playlist.song_filename(old_song),
+            _ => new_song.to_filename(new_playlist.file_ext().to_owned()), //Freshly downloaded
+        };
+        let new_filename = new_playlist.song_filename(new_song);
+
+        if current_filename == new_filename {
+            continue;
+        }
+
+        if fs::metadata(&current_filename).is_err() {
+            if old_song.is_some() && !retried_songs.contains(new_song) {
+                pl_update_warn!("Could not find \"{}\" to rename, it will not be re-downloaded.", current_filename);
+            }
+            continue;
+        }
+
+        let is_renamed = old_song.is_some_and(|old_song| old_song.is_renamed(new_song));
+
+        //Files have already been downloaded and deleted, so a file that cannot be changed must not stop the new manifest being saved.
+        if retag && is_renamed {
+            if let Err(e) = rewrite_tags(&ffmpeg_command, &current_filename, &new_filename, &[("title", new_song.title.clone())]) {
+                pl_update_warn!("Could not retag \"{}\", it is renamed only: {}", current_filename, e);
+            }
+        }
+
+        if fs::metadata(&current_filename).is_ok() {
+            if let Err(e) = fs::rename(&current_filename, &new_filename) {
+                pl_update_warn!("Could not rename \"{}\" to \"{}\": {}", current_filename, new_filename, e);
+                continue;
+            }
+        }
+
+        if is_renamed {
+            renamed_files.push((old_song.unwrap(), new_song));
+        }
+    }
+
+
+    //Songs that failed to download were already reported.
+    let missing_count = unique_songs(&new_playlist.songs).iter().filter(|f| f.failed.is_none() && fs::metadata(new_playlist.song_filename(f)).is_err()).count();
+
+    if missing_count > 0 {
+        pl_update_warn!("{} songs in the manifest have no file, run verify to check the playlist directory.", missing_count);
+    }
+
+    apply_file_rules(&mut new_playlist, &download_songs, &options);
+
+    if new_playlist.title != old_playlist.title {
+        let _ = remove_file(playlist_filename(&old_playlist.title));
+    }
+
+    write_playlist_file(&new_playlist)?;
+
+
+    pl_update_println!("Summary: {} added, {} removed, {} renamed, {} moved.", added_songs.len(), removed_songs.len(), renamed_files.len(), moved_songs.len());
+
+    {
+        let mut summary = run_summary();
+        summary.added = added_songs.iter().map(Into::into).collect();
+        summary.removed = removed_songs.iter().map(Into::into).collect();
+        summary.renamed = renamed_files.iter().map(|(_, new_song)| (*new_song).into()).collect();
+        summary.moved = moved_songs.iter().map(Into::into).collect();
+    }
+
+    for (old_song, new_song) in &renamed_files {
+        pl_update_println!("Renamed \"{}\" -> \"{}\" [{}]", old_song.title, new_song.title, new_song.id);
+    }
+
+    for song in &moved_songs {
+        let old_position = old_songs.iter().find(|old_song| *old_song == song).map_or(0, |old_song| old_song.position);
+        pl_update_println!("Moved \"{}\" [{}] from position {} to {}", song.title, song.id, old_position, song.position);
+    }
+
+
+    match snapshot_manifest() {
+        Ok(_) => {},
         Err(e) => {pl_update_fatal_error!(e.kind(), "Could not rename old playlist manifest: {}", e);}
     };
 
 
+    new_playlist.write(File::create("playlist-new.manifest")?)?;
+
     match fs::rename("playlist-new.manifest", "playlist.manifest") {
         Ok(()) => {},
         Err(e) => {pl_update_fatal_error!(e.kind(), "Could not rename new playlist manifest: {}", e);}
     };
 
 
+    //A directory that was not named after the title was chosen by hand, and is left as it is.
+    if new_playlist.title != old_playlist.title {
+        let dir = current_dir()?;
+
+        match dir.parent().filter(|_| dir.file_name().is_some_and(|f| *f == *sanitize_dir_name(&old_playlist.title))) {
+            Some(parent) => {
+                let dir_name = unused_dir_name(parent, &sanitize_dir_name(&new_playlist.title));
+
+                match fs::rename(&dir, parent.join(&dir_name)) {
+                    Ok(()) => {
+                        pl_update_println!("Renamed playlist directory to \"{}\".", dir_name);
+                        run_summary().playlist_dir = Some(parent.join(&dir_name));
+
+                        if let Some(mut store) = Store::open(&options.store_options)? {
+                            store.move_playlist(&dir, &parent.join(&dir_name))?;
+                        }
+                    },
+                    Err(e) => pl_update_warn!("Could not rename playlist directory to \"{}\": {}", dir_name, e),
+                }
+            },
+            None => pl_update_vprintln!("Playlist directory \"{}\" was not named after the playlist, it was not renamed.", dir.display()),
+        }
+    }
+
+
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,248 @@
+use std::{env::set_current_dir, fs::{self, read_dir, File}, io::{self, Error, ErrorKind}};
+
+use crate::cover::FOLDER_COVER;
+use crate::history::record_playlist;
+use crate::loudness::apply_loudness;
+use crate::{apply_tags, download, recorded_extractor, interrupted, is_partial_file, parse_manifest, parse_song_filename, playlist_filename, pl_update_fatal_error, prompt_choice, rename_changed_files, save_downloads, save_manifest, split_track_number, unique_songs, write_playlist_file, Args, LoudnessMode, MissingAction, OrphanAction, Song, UNKNOWN_EXTRACTOR};
+use crate::tags::read_tags;
+
+
+const QUARANTINE_DIR: &str = "quarantine";
+
+
+pub(crate) fn pl_verify(options: Args, playlist_name: Option<String>, missing_action: Option<MissingAction>, orphan_action: Option<OrphanAction>, delete_partial: bool) -> Result<(), Error> {
+
+
+    if let Some(playlist_name) = playlist_name {
+        match set_current_dir(playlist_name) {
+            Ok(()) => (),
+            Err(err) => {
+                pl_update_fatal_error!(err.kind(), "Could not find playlist directory: {}", err);
+            }
+
+        }
+    }
+
+
+    let manifest = match File::open("playlist.manifest") {
+        Ok(val) => val,
+        Err(err) => {
+            pl_update_fatal_error!(err.kind(), "Could not open playlist manifest: {}", err);
+        }
+    };
+
+    let mut playlist = parse_manifest(manifest)?;
+
+    pl_update_println!("Verifying playlist: \"{}\"", playlist.title);
+
+
+    let songs = unique_songs(&playlist.songs);
+    let expected_filenames: Vec<String> = songs.iter().map(|f| playlist.song_filename(f)).collect();
+    let generated_playlist = playlist_filename(&playlist.title);
+
+    let mut present_filenames = Vec::new();
+    let mut orphan_filenames = Vec::new();
+    let mut partial_filenames = Vec::new();
+
+
+    let mut manifest_changed = false;
+
+    let directory_entry = read_dir(".")?.collect::<Result<Vec<_>, io::Error>>()?;
+
+    for file_entry in directory_entry {
+
+        if file_entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let file_name = match file_entry.file_name().into_string() {
+            Ok(val) => val,
+            Err(name) => {
+                pl_update_warn!("File name {:?} is not valid unicode, it will be ignored.", name);
+                continue;
+            }
+        };
+
+        if file_name.ends_with(".manifest") || file_name == generated_playlist || file_name == FOLDER_COVER || playlist.cover_rules.image.as_ref() == Some(&file_name) {
+            continue;
+        }
+
+        if is_partial_file(&file_name) || file_entry.metadata()?.len() == 0 {
+            partial_filenames.push(file_name);
+        } else if expected_filenames.contains(&file_name) {
+            present_filenames.push(file_name);
+        } else {
+            orphan_filenames.push(file_name);
+        }
+
+    }
+
+
+    let missing_songs: Vec<Song> = songs.iter().zip(&expected_filenames).filter(|(_, filename)|
+        !present_filenames.contains(filename)
+    ).map(|(song, _)| song.clone()).collect();
+
+
+    pl_update_println!("{} of {} songs present, {} missing, {} files not in manifest, {} partial or empty files.",
+        present_filenames.len(), songs.len(), missing_songs.len(), orphan_filenames.len(), partial_filenames.len());
+
+    for song in &missing_songs {
+        pl_update_println!("Missing: \"{}\" [{}]", song.title, song.id);
+    }
+
+    for filename in &orphan_filenames {
+        pl_update_println!("Not in manifest: \"{}\"", filename);
+    }
+
+    for filename in &partial_filenames {
+        pl_update_println!("Partial or empty: \"{}\"", filename);
+    }
+
+
+    if !partial_filenames.is_empty() {
+        let delete = if delete_partial {
+            true
+        } else if options.quiet {
+            false
+        } else {
+            prompt_choice(&format!("Delete {} partial or empty files?", partial_filenames.len()), &["yes", "no"])? == 0
+        };
+
+        if delete {
+            for filename in &partial_filenames {
+                pl_update_vprintln!("Deleting \"{}\"", filename);
+                fs::remove_file(filename)?;
+            }
+            pl_update_println!("Deleted {} partial or empty files.", partial_filenames.len());
+        }
+    }
+
+
+    if !orphan_filenames.is_empty() {
+        let action = match orphan_action {
+            Some(val) => val,
+            None if options.quiet => OrphanAction::Ignore,
+            None => {
+                let choices = [OrphanAction::Ignore, OrphanAction::Adopt, OrphanAction::Quarantine, OrphanAction::Delete];
+                let names: Vec<&str> = choices.iter().map(|f| f.as_str()).collect();
+                choices[prompt_choice(&format!("What should be done with {} files not in the manifest?", orphan_filenames.len()), &names)?]
+            }
+        };
+
+        match action {
+            OrphanAction::Ignore => {},
+            OrphanAction::Adopt => {
+                let old_playlist = playlist.clone();
+                let mut adopted = Vec::new();
+
+                for filename in &orphan_filenames {
+                    match parse_song_filename(filename, playlist.file_ext()) {
+                        Some((title, id)) => {
+                            let title = if playlist.track_numbers { split_track_number(&title).1.to_string() } else { title };
+                            let mut song = Song::new(title, id, None, playlist.songs.len() + 1, Some(UNKNOWN_EXTRACTOR.to_string()));
+
+                            match read_tags(filename) {
+                                Ok(Some(tags)) => {
+                                    apply_tags(&mut song, &tags);
+                                },
+                                Ok(None) => {},
+                                Err(e) => pl_update_warn!("Could not read tags of \"{}\": {}", filename, e),
+                            }
+
+                            song.extractor = Some(recorded_extractor(&song.id, song.url.as_deref()));
+
+                            if playlist.songs.contains(&song) {
+                                pl_update_warn!("\"{}\" has the same id as a song already in the manifest, it will not be adopted.", filename);
+                                continue;
+                            }
+
+                            pl_update_vprintln!("Adopting \"{}\"", filename);
+                            playlist.songs.push(song.clone());
+                            adopted.push((filename, song));
+                        },
+                        None => pl_update_warn!("\"{}\" is not a yt-dl file and cannot be adopted.", filename),
+                    }
+                }
+
+                //Adopted songs now have a position, and the track number width may have grown.
+                rename_changed_files(&old_playlist, &playlist)?;
+
+                for (filename, song) in &adopted {
+                    let new_filename = playlist.song_filename(song);
+                    if **filename != new_filename {
+                        fs::rename(filename, new_filename)?;
+                    }
+                }
+
+                manifest_changed |= !adopted.is_empty();
+
+                pl_update_println!("Adopted {} files into the manifest.", adopted.len());
+            },
+            OrphanAction::Quarantine => {
+                match fs::create_dir(QUARANTINE_DIR) {
+                    Ok(()) => {},
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => {},
+                    Err(e) => {pl_update_fatal_error!(e.kind(), "Could not create quarantine directory: {}", e);}
+                }
+
+                for filename in &orphan_filenames {
+                    pl_update_vprintln!("Quarantining \"{}\"", filename);
+                    fs::rename(filename, format!("{}/{}", QUARANTINE_DIR, filename))?;
+                }
+                pl_update_println!("Moved {} files to \"{}\".", orphan_filenames.len(), QUARANTINE_DIR);
+            },
+            OrphanAction::Delete => {
+                for filename in &orphan_filenames {
+                    pl_update_vprintln!("Deleting \"{}\"", filename);
+                    fs::remove_file(filename)?;
+                }
+                pl_update_println!("Deleted {} files.", orphan_filenames.len());
+            }
+        }
+    }
+
+
+    let mut download_songs = Vec::new();
+
+    if !missing_songs.is_empty() {
+        let action = match missing_action {
+            Some(val) => val,
+            None if options.quiet => MissingAction::Ignore,
+            None => {
+                let choices = [MissingAction::Ignore, MissingAction::Download];
+                let names: Vec<&str> = choices.iter().map(|f| f.as_str()).collect();
+                choices[prompt_choice(&format!("What should be done with {} missing songs?", missing_songs.len()), &names)?]
+            }
+        };
+
+        if action == MissingAction::Download && missing_songs.iter().any(|f| f.url().is_some()) {
+            download_songs = missing_songs;
+        }
+    }
+
+
+    //Adopted files are measured here, downloaded ones once they are saved.
+    if manifest_changed && download_songs.is_empty() && playlist.loudness_mode != LoudnessMode::Off && !interrupted() {
+        match apply_loudness(&mut playlist, &options) {
+            Ok((analysed, changed)) => pl_update_println!("Analysed the loudness of {} songs, {} files changed.", analysed, changed),
+            Err(e) => pl_update_warn!("Could not apply the loudness mode: {}", e),
+        }
+    }
+
+    if manifest_changed || !download_songs.is_empty() {
+        record_playlist(&playlist);
+        save_manifest(&playlist)?;
+    }
+
+
+    if download_songs.is_empty() {
+        write_playlist_file(&playlist)?;
+    } else {
+        pl_update_println!("Downloading missing items...");
+        let failures = download(&download_songs, &playlist.audio_format, &options)?;
+        save_downloads(&mut playlist, &download_songs, &failures, &options)?;
+    }
+
+
+    Ok(())
+}